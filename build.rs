@@ -0,0 +1,547 @@
+// Generates an enum, or a flag set for bitfields, for each type of the profile in src/types.json,
+// included by src/fitenums.rs,
+// and a typed struct for each message in src/messages.json, included by src/fitmessages.rs.
+
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::fmt::Write as FmtWrite;
+use std::fs;
+use std::path::Path;
+
+use serde_json::Value;
+
+const RUST_KEYWORDS: &[&str] = &["as", "break", "const", "continue", "crate", "else", "enum", "extern",
+    "false", "fn", "for", "if", "impl", "in", "let", "loop", "match", "mod", "move", "mut", "pub", "ref",
+    "return", "static", "struct", "super", "trait", "true", "type", "unsafe", "use", "where", "while",
+    "async", "await", "dyn", "abstract", "become", "box", "do", "final", "macro", "override", "priv",
+    "typeof", "unsized", "virtual", "yield", "try"];
+
+// Types which are plain numbers with a few special values, these are not enums.
+const NUMERIC_TYPES: &[&str] = &["date_time", "local_date_time", "message_index", "device_index",
+    "backlight_timeout", "weight", "workout_hr", "workout_power", "user_local_id", "left_right_balance",
+    "left_right_balance_100", "time_into_day", "localtime_into_day"];
+
+/// How a field is represented in the generated struct.
+enum FieldKind {
+    Physical,                          // f64, after scale, offset and semicircle conversion.
+    Raw(&'static str),                 // The integer type holding the raw value.
+    Enum(String, &'static str),        // The generated enum or flag set and its raw integer type.
+    Bool,
+    Str,
+    Bytes,
+}
+
+struct FieldInfo {
+    field_defn_num: u64,
+    ident: String,
+    kind: FieldKind,
+    is_array: bool,
+    data_type: &'static str,           // FitDataType variant.
+    scale: Option<f64>,
+    offset: Option<f64>,
+    semicircles: bool,
+    units: Option<String>,
+}
+
+/// A subfield, which is the main field read another way when one of the reference fields has one
+/// of the given values, e.g. device_info.product is garmin_product if manufacturer is garmin.
+struct SubfieldInfo {
+    info: FieldInfo,
+    refs: Vec<(u64, Vec<u64>)>,        // Reference field numbers and raw values, any may match.
+    condition: String,                 // The same, for the doc comment.
+}
+
+/// The FitDataType variant and the Rust type of a base type name.
+fn base_type_info(name: &str) -> (&'static str, &'static str) {
+    match name {
+        "enum" | "bool" => ("FitEnum", "u8"),
+        "sint8" => ("FitSint8", "i8"),
+        "uint8" => ("FitUint8", "u8"),
+        "uint8z" => ("FitU8z", "u8"),
+        "sint16" => ("FitSint16", "i16"),
+        "uint16" => ("FitUint16", "u16"),
+        "uint16z" => ("FitU16z", "u16"),
+        "sint32" => ("FitSint32", "i32"),
+        "uint32" => ("FitUint32", "u32"),
+        "uint32z" => ("FitU32z", "u32"),
+        "float32" => ("FitF32", "f64"),
+        "float64" => ("FitF64", "f64"),
+        "string" => ("FitString", "String"),
+        "byte" => ("FitByte", "u8"),
+        "sint64" => ("FitSInt64", "i64"),
+        "uint64" => ("FitUint64", "u64"),
+        "uint64z" => ("FitUint64z", "u64"),
+        _ => panic!("Unknown base type {}", name),
+    }
+}
+
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|x| {
+            let mut chars = x.chars();
+            match chars.next() {
+                Some(c) => c.to_ascii_uppercase().to_string() + chars.as_str(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+fn field_ident(name: &str) -> String {
+    if RUST_KEYWORDS.contains(&name) {
+        format!("r#{}", name)
+    } else {
+        name.to_string()
+    }
+}
+
+/// Enum variant of a value name. Names starting with a digit are prefixed with N, e.g. N3WayCalfRaise.
+/// A value named "unknown" is UnknownValue, as Unknown holds values that are not in the profile.
+fn variant_ident(name: &str) -> String {
+    let ident = camel_case(name);
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("N{}", ident)
+    } else if ident == "Unknown" {
+        "UnknownValue".to_string()
+    } else {
+        ident
+    }
+}
+
+fn literal(x: Option<f64>) -> String {
+    match x {
+        Some(v) => format!("Some({:?})", v),
+        None => "None".to_string(),
+    }
+}
+
+fn field_info(field: &Value, base_types: &HashMap<String, String>, enum_types: &HashSet<String>) -> FieldInfo {
+    let field_type = field["field_type"].as_str().unwrap();
+    let base_type = base_types.get(field_type).map(|x| x.as_str()).unwrap_or(field_type);
+    let (data_type, rust_type) = base_type_info(base_type);
+    let is_array = matches!(&field["array"], Value::Bool(true)) || field["array"].as_str() == Some("true");
+    let scale = field["scale"].as_f64();
+    let offset = field["offset"].as_f64();
+    let units = field["units"].as_str().map(|x| x.to_string());
+    let semicircles = units.as_deref() == Some("semicircles");
+
+    let is_float = rust_type == "f64";
+    let is_scaled = scale.unwrap_or(1.0) != 1.0 || offset.unwrap_or(0.0) != 0.0;
+    let kind = if base_type == "string" {
+        FieldKind::Str
+    } else if base_type == "byte" && is_array {
+        FieldKind::Bytes
+    } else if is_float || is_scaled || semicircles {
+        FieldKind::Physical
+    } else if field_type == "bool" && !is_array {
+        FieldKind::Bool
+    } else if enum_types.contains(field_type) {
+        FieldKind::Enum(format!("fitenums::{}", camel_case(field_type)), rust_type)
+    } else {
+        FieldKind::Raw(rust_type)
+    };
+
+    FieldInfo {
+        field_defn_num: field["field_defn_num"].as_u64().unwrap(),
+        ident: field_ident(field["field_name"].as_str().unwrap()),
+        kind, is_array, data_type, scale, offset, semicircles, units,
+    }
+}
+
+fn subfield_infos(message: &Value, field: &Value, base_types: &HashMap<String, String>,
+                  enum_types: &HashSet<String>, type_values: &HashMap<String, HashMap<String, u64>>) -> Vec<SubfieldInfo> {
+    let mut subfields = Vec::new();
+    for subfield in field["subfields"].as_array().into_iter().flatten() {
+        // Subfields share the number and array-ness of the main field.
+        let mut value = subfield.clone();
+        value["field_defn_num"] = field["field_defn_num"].clone();
+        value["array"] = field["array"].clone();
+        let info = field_info(&value, base_types, enum_types);
+
+        let mut refs: Vec<(u64, Vec<u64>)> = Vec::new();
+        let mut conditions: Vec<(String, Vec<String>)> = Vec::new();
+        for ref_field in subfield["ref_fields"].as_array().unwrap() {
+            let ref_name = ref_field["ref_field_name"].as_str().unwrap();
+            let ref_value = ref_field["ref_field_value"].as_str().unwrap();
+            let ref_desc = match message["fields"].as_array().unwrap().iter().find(|x| x["field_name"] == ref_name) {
+                Some(x) => x,
+                None => continue,
+            };
+            let raw = match type_values.get(ref_desc["field_type"].as_str().unwrap()).and_then(|x| x.get(ref_value)) {
+                Some(x) => *x,
+                None => continue,
+            };
+            let ref_num = ref_desc["field_defn_num"].as_u64().unwrap();
+            match refs.iter_mut().find(|x| x.0 == ref_num) {
+                Some((_, values)) => values.push(raw),
+                None => refs.push((ref_num, vec![raw])),
+            }
+            match conditions.iter_mut().find(|x| x.0 == ref_name) {
+                Some((_, names)) => names.push(ref_value.to_string()),
+                None => conditions.push((ref_name.to_string(), vec![ref_value.to_string()])),
+            }
+        }
+        let condition = conditions.iter()
+            .map(|(name, values)| format!("{} is {}", name, values.join(" or ")))
+            .collect::<Vec<_>>()
+            .join(", or ");
+        subfields.push(SubfieldInfo{ info, refs, condition });
+    }
+    subfields
+}
+
+fn rust_type(info: &FieldInfo) -> String {
+    let element = match &info.kind {
+        FieldKind::Physical => "f64",
+        FieldKind::Raw(t) => t,
+        FieldKind::Enum(t, _) => t.as_str(),
+        FieldKind::Bool => "bool",
+        FieldKind::Str => return "Option<String>".to_string(),
+        FieldKind::Bytes => return "Option<Vec<u8>>".to_string(),
+    };
+    if info.is_array {
+        format!("Option<Vec<Option<{}>>>", element)
+    } else {
+        format!("Option<{}>", element)
+    }
+}
+
+fn conversion(info: &FieldInfo) -> String {
+    format!("FitConversion{{ scale: {}, offset: {}, semicircles: {} }}",
+            literal(info.scale), literal(info.offset), info.semicircles)
+}
+
+/// Expression reading the field from `mesg`.
+fn decode_expr(info: &FieldInfo) -> String {
+    let num = info.field_defn_num;
+    match (&info.kind, info.is_array) {
+        (FieldKind::Physical, false) => format!("physical(mesg, {}, &{})", num, conversion(info)),
+        (FieldKind::Physical, true) => format!("physical_array(mesg, {}, &{})", num, conversion(info)),
+        (FieldKind::Raw("u64"), false) => format!("raw(mesg, {})", num),
+        (FieldKind::Raw(t), false) => format!("raw(mesg, {}).map(|v| v as {})", num, t),
+        (FieldKind::Raw("u64"), true) => format!("raw_array(mesg, {})", num),
+        (FieldKind::Raw(t), true) =>
+            format!("raw_array(mesg, {}).map(|x| x.into_iter().map(|v| v.map(|v| v as {})).collect())", num, t),
+        (FieldKind::Enum(t, raw_type), false) => format!("raw(mesg, {}).map(|v| {}::from(v as {}))", num, t, raw_type),
+        (FieldKind::Enum(t, raw_type), true) => format!(
+            "raw_array(mesg, {}).map(|x| x.into_iter().map(|v| v.map(|v| {}::from(v as {}))).collect())",
+            num, t, raw_type),
+        (FieldKind::Bool, _) => format!("raw(mesg, {}).map(|v| v != 0)", num),
+        (FieldKind::Str, _) => format!("string(mesg, {})", num),
+        (FieldKind::Bytes, _) => format!("bytes(mesg, {})", num),
+    }
+}
+
+/// Expression building the FitDataField from `v`, a reference to the value.
+fn encode_expr(info: &FieldInfo) -> String {
+    let num = info.field_defn_num;
+    let data_type = format!("FitDataType::{}", info.data_type);
+    match (&info.kind, info.is_array) {
+        (FieldKind::Physical, false) =>
+            format!("from_physical({}, &{}, {}, &[Some(*v)])", num, conversion(info), data_type),
+        (FieldKind::Physical, true) =>
+            format!("from_physical({}, &{}, {}, v)", num, conversion(info), data_type),
+        (FieldKind::Raw("u64"), false) => format!("from_raw({}, {}, &[Some(*v)])", num, data_type),
+        (FieldKind::Raw(_), false) => format!("from_raw({}, {}, &[Some(*v as u64)])", num, data_type),
+        (FieldKind::Raw("u64"), true) => format!("from_raw({}, {}, v)", num, data_type),
+        (FieldKind::Raw(_), true) => format!(
+            "from_raw({}, {}, &v.iter().map(|x| x.map(|x| x as u64)).collect::<Vec<_>>())", num, data_type),
+        (FieldKind::Enum(_, raw_type), false) =>
+            format!("from_raw({}, {}, &[Some({}::from(*v) as u64)])", num, data_type, raw_type),
+        (FieldKind::Enum(_, raw_type), true) => format!(
+            "from_raw({}, {}, &v.iter().map(|x| x.map(|x| {}::from(x) as u64)).collect::<Vec<_>>())",
+            num, data_type, raw_type),
+        (FieldKind::Bool, _) => format!("from_raw({}, {}, &[Some(*v as u64)])", num, data_type),
+        (FieldKind::Str, _) => format!("from_string({}, v)", num),
+        (FieldKind::Bytes, _) => format!("from_bytes({}, v)", num),
+    }
+}
+
+fn generate_message(out: &mut String, message: &Value, base_types: &HashMap<String, String>,
+                    enum_types: &HashSet<String>, type_values: &HashMap<String, HashMap<String, u64>>) {
+    let mesg_num = message["mesg_num"].as_u64().unwrap();
+    let message_name = message["message_name"].as_str().unwrap();
+    let struct_name = camel_case(message_name);
+    let fields: Vec<FieldInfo> = message["fields"].as_array().unwrap().iter()
+        .map(|x| field_info(x, base_types, enum_types))
+        .collect();
+    // The subfields of each field, in the same order.
+    let subfields: Vec<Vec<SubfieldInfo>> = message["fields"].as_array().unwrap().iter()
+        .map(|x| subfield_infos(message, x, base_types, enum_types, type_values))
+        .collect();
+
+    writeln!(out, "/// The `{}` message, global message number {}.", message_name, mesg_num).unwrap();
+    writeln!(out, "#[derive(Clone, Debug, Default, PartialEq)]").unwrap();
+    writeln!(out, "pub struct {} {{", struct_name).unwrap();
+    for info in &fields {
+        match (&info.kind, &info.units) {
+            (FieldKind::Physical, _) if info.semicircles =>
+                writeln!(out, "    /// Field {}, degrees.", info.field_defn_num).unwrap(),
+            (_, Some(units)) => writeln!(out, "    /// Field {}, {}.", info.field_defn_num, units).unwrap(),
+            (_, None) => writeln!(out, "    /// Field {}.", info.field_defn_num).unwrap(),
+        }
+        writeln!(out, "    pub {}: {},", info.ident, rust_type(info)).unwrap();
+    }
+    for sub in subfields.iter().flatten() {
+        let units = sub.info.units.as_ref().map(|x| format!(", {}", x)).unwrap_or_default();
+        writeln!(out, "    /// Field {} when {}{}.", sub.info.field_defn_num, sub.condition, units).unwrap();
+        writeln!(out, "    pub {}: {},", sub.info.ident, rust_type(&sub.info)).unwrap();
+    }
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl {} {{", struct_name).unwrap();
+    writeln!(out, "    pub const MESG_NUM: u16 = {};", mesg_num).unwrap();
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl TryFrom<&FitDataMessage> for {} {{", struct_name).unwrap();
+    writeln!(out, "    type Error = &'static str;\n").unwrap();
+    writeln!(out, "    fn try_from(mesg: &FitDataMessage) -> Result<Self, Self::Error> {{").unwrap();
+    writeln!(out, "        if mesg.global_message_number != {} {{", mesg_num).unwrap();
+    writeln!(out, "            return Err(\"Not a {} message\");", message_name).unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "        Ok({} {{", struct_name).unwrap();
+    for info in &fields {
+        writeln!(out, "            {}: {},", info.ident, decode_expr(info)).unwrap();
+    }
+    for sub in subfields.iter().flatten() {
+        let condition = sub.refs.iter()
+            .map(|(num, values)| format!("refers_to(mesg, {}, &{:?})", num, values))
+            .collect::<Vec<_>>();
+        let condition = if condition.is_empty() { "false".to_string() } else { condition.join(" || ") };
+        writeln!(out, "            {}: if {} {{ {} }} else {{ None }},", sub.info.ident, condition, decode_expr(&sub.info)).unwrap();
+    }
+    writeln!(out, "        }})").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl From<&{}> for FitDataMessage {{", struct_name).unwrap();
+    if fields.is_empty() {
+        writeln!(out, "    fn from(_value: &{}) -> Self {{", struct_name).unwrap();
+        writeln!(out, "        FitDataMessage{{ global_message_number: {}, ..Default::default() }}", mesg_num).unwrap();
+        writeln!(out, "    }}").unwrap();
+        writeln!(out, "}}\n").unwrap();
+        return;
+    }
+    writeln!(out, "    fn from(value: &{}) -> Self {{", struct_name).unwrap();
+    writeln!(out, "        let mut fields = Vec::new();").unwrap();
+    // A subfield is written if the main field is not set.
+    for (info, subfields) in fields.iter().zip(&subfields) {
+        writeln!(out, "        if let Some(v) = &value.{} {{", info.ident).unwrap();
+        writeln!(out, "            fields.push({});", encode_expr(info)).unwrap();
+        for sub in subfields {
+            writeln!(out, "        }} else if let Some(v) = &value.{} {{", sub.info.ident).unwrap();
+            writeln!(out, "            fields.push({});", encode_expr(&sub.info)).unwrap();
+        }
+        writeln!(out, "        }}").unwrap();
+    }
+    writeln!(out, "        FitDataMessage{{ global_message_number: {}, fields, ..Default::default() }}", mesg_num).unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}\n").unwrap();
+}
+
+fn generate_enum(out: &mut String, a_type: &Value) {
+    let type_name = a_type["type_name"].as_str().unwrap();
+    let enum_name = camel_case(type_name);
+    let (_, raw_type) = base_type_info(a_type["base_type"].as_str().unwrap());
+    let mut values: Vec<(u64, String, String)> = a_type["values"].as_object().unwrap().iter()
+        .map(|(k, v)| (v.as_u64().unwrap(), k.clone(), variant_ident(k)))
+        .collect();
+    values.sort();
+    // Where several names have the same value, the first is used when decoding.
+    let mut seen = HashSet::new();
+    let unique: Vec<&(u64, String, String)> = values.iter().filter(|x| seen.insert(x.0)).collect();
+
+    writeln!(out, "/// The `{}` type.", type_name).unwrap();
+    writeln!(out, "#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]").unwrap();
+    writeln!(out, "pub enum {} {{", enum_name).unwrap();
+    for (value, _, ident) in &values {
+        writeln!(out, "    {}, // {}", ident, value).unwrap();
+    }
+    writeln!(out, "    Unknown({}),", raw_type).unwrap();
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl From<{}> for {} {{", raw_type, enum_name).unwrap();
+    writeln!(out, "    fn from(value: {}) -> Self {{", raw_type).unwrap();
+    writeln!(out, "        match value {{").unwrap();
+    for (value, _, ident) in &unique {
+        writeln!(out, "            {} => {}::{},", value, enum_name, ident).unwrap();
+    }
+    writeln!(out, "            _ => {}::Unknown(value),", enum_name).unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl From<{}> for {} {{", enum_name, raw_type).unwrap();
+    writeln!(out, "    fn from(value: {}) -> Self {{", enum_name).unwrap();
+    writeln!(out, "        match value {{").unwrap();
+    for (value, _, ident) in &values {
+        writeln!(out, "            {}::{} => {},", enum_name, ident, value).unwrap();
+    }
+    writeln!(out, "            {}::Unknown(x) => x,", enum_name).unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl {} {{", enum_name).unwrap();
+    writeln!(out, "    /// The name of the value in the profile, None if it is not in the profile.").unwrap();
+    writeln!(out, "    pub fn name(&self) -> Option<&'static str> {{").unwrap();
+    writeln!(out, "        match self {{").unwrap();
+    for (_, name, ident) in &values {
+        writeln!(out, "            {}::{} => Some(\"{}\"),", enum_name, ident, name).unwrap();
+    }
+    writeln!(out, "            {}::Unknown(_) => None,", enum_name).unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl fmt::Display for {} {{", enum_name).unwrap();
+    writeln!(out, "    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {{").unwrap();
+    writeln!(out, "        match self.name() {{").unwrap();
+    writeln!(out, "            Some(x) => write!(f, \"{{}}\", x),").unwrap();
+    writeln!(out, "            None => write!(f, \"{{}}\", {}::from(*self)),", raw_type).unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl FromStr for {} {{", enum_name).unwrap();
+    writeln!(out, "    type Err = &'static str;\n").unwrap();
+    writeln!(out, "    fn from_str(s: &str) -> Result<Self, Self::Err> {{").unwrap();
+    writeln!(out, "        match s {{").unwrap();
+    for (_, name, ident) in &values {
+        writeln!(out, "            \"{}\" => Ok({}::{}),", name, enum_name, ident).unwrap();
+    }
+    writeln!(out, "            _ => s.parse::<{}>().map({}::from).map_err(|_| \"Unknown {} value\"),",
+             raw_type, enum_name, type_name).unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}\n").unwrap();
+}
+
+/// Constant name of a flag, e.g. READ. Names starting with a digit are prefixed with N_.
+fn flag_ident(name: &str) -> String {
+    let ident = name.to_ascii_uppercase();
+    if ident.starts_with(|c: char| c.is_ascii_digit()) {
+        format!("N_{}", ident)
+    } else {
+        ident
+    }
+}
+
+fn generate_flags(out: &mut String, a_type: &Value) {
+    let type_name = a_type["type_name"].as_str().unwrap();
+    let struct_name = camel_case(type_name);
+    let (_, raw_type) = base_type_info(a_type["base_type"].as_str().unwrap());
+    let mut values: Vec<(u64, String, String)> = a_type["values"].as_object().unwrap().iter()
+        .map(|(k, v)| (v.as_u64().unwrap(), k.clone(), flag_ident(k)))
+        .collect();
+    values.sort();
+
+    writeln!(out, "/// The `{}` type, a set of flags.", type_name).unwrap();
+    writeln!(out, "#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]").unwrap();
+    writeln!(out, "pub struct {}(pub {});\n", struct_name, raw_type).unwrap();
+
+    writeln!(out, "impl {} {{", struct_name).unwrap();
+    for (value, _, ident) in &values {
+        writeln!(out, "    pub const {}: {} = {}({});", ident, struct_name, struct_name, value).unwrap();
+    }
+    writeln!(out, "    const FLAGS: &'static [({}, &'static str)] = &[", raw_type).unwrap();
+    for (value, name, _) in values.iter().filter(|x| x.0 != 0) {
+        writeln!(out, "        ({}, \"{}\"),", value, name).unwrap();
+    }
+    writeln!(out, "    ];\n").unwrap();
+    writeln!(out, "    pub fn bits(&self) -> {} {{ self.0 }}\n", raw_type).unwrap();
+    writeln!(out, "    pub fn is_empty(&self) -> bool {{ self.0 == 0 }}\n").unwrap();
+    writeln!(out, "    /// True if all the flags of `other` are set.").unwrap();
+    writeln!(out, "    pub fn contains(&self, other: {}) -> bool {{ self.0 & other.0 == other.0 }}\n", struct_name).unwrap();
+    writeln!(out, "    /// The names of the flags which are set, ordered by bit.").unwrap();
+    writeln!(out, "    pub fn names(&self) -> Vec<&'static str> {{").unwrap();
+    writeln!(out, "        Self::FLAGS.iter().filter(|(v, _)| self.0 & v == *v).map(|(_, k)| *k).collect()").unwrap();
+    writeln!(out, "    }}\n").unwrap();
+    writeln!(out, "    /// Bits which are set but are not flags of the profile.").unwrap();
+    writeln!(out, "    pub fn unknown_bits(&self) -> {} {{", raw_type).unwrap();
+    writeln!(out, "        Self::FLAGS.iter().fold(self.0, |acc, (v, _)| acc & !v)").unwrap();
+    writeln!(out, "    }}\n").unwrap();
+    writeln!(out, "    /// The flag with the given name.").unwrap();
+    writeln!(out, "    pub fn from_name(name: &str) -> Option<{}> {{", struct_name).unwrap();
+    writeln!(out, "        Self::FLAGS.iter().find(|(_, k)| *k == name).map(|(v, _)| {}(*v))", struct_name).unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl From<{}> for {} {{", raw_type, struct_name).unwrap();
+    writeln!(out, "    fn from(value: {}) -> Self {{ {}(value) }}", raw_type, struct_name).unwrap();
+    writeln!(out, "}}\n").unwrap();
+    writeln!(out, "impl From<{}> for {} {{", struct_name, raw_type).unwrap();
+    writeln!(out, "    fn from(value: {}) -> Self {{ value.0 }}", struct_name).unwrap();
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl ops::BitOr for {} {{", struct_name).unwrap();
+    writeln!(out, "    type Output = Self;").unwrap();
+    writeln!(out, "    fn bitor(self, rhs: Self) -> Self {{ {}(self.0 | rhs.0) }}", struct_name).unwrap();
+    writeln!(out, "}}\n").unwrap();
+    writeln!(out, "impl ops::BitOrAssign for {} {{", struct_name).unwrap();
+    writeln!(out, "    fn bitor_assign(&mut self, rhs: Self) {{ self.0 |= rhs.0 }}").unwrap();
+    writeln!(out, "}}\n").unwrap();
+    writeln!(out, "impl ops::BitAnd for {} {{", struct_name).unwrap();
+    writeln!(out, "    type Output = Self;").unwrap();
+    writeln!(out, "    fn bitand(self, rhs: Self) -> Self {{ {}(self.0 & rhs.0) }}", struct_name).unwrap();
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl fmt::Display for {} {{", struct_name).unwrap();
+    writeln!(out, "    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {{").unwrap();
+    writeln!(out, "        format_flags(f, &self.names(), self.unknown_bits() as u64)").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}\n").unwrap();
+
+    writeln!(out, "impl FromStr for {} {{", struct_name).unwrap();
+    writeln!(out, "    type Err = &'static str;\n").unwrap();
+    writeln!(out, "    fn from_str(s: &str) -> Result<Self, Self::Err> {{").unwrap();
+    writeln!(out, "        let mut flags = {}(0);", struct_name).unwrap();
+    writeln!(out, "        for name in s.split('|').map(|x| x.trim()).filter(|x| !x.is_empty()) {{").unwrap();
+    writeln!(out, "            match Self::from_name(name) {{").unwrap();
+    writeln!(out, "                Some(x) => flags |= x,").unwrap();
+    writeln!(out, "                None => flags |= {}(name.parse::<{}>().map_err(|_| \"Unknown {} flag\")?),",
+             struct_name, raw_type, type_name).unwrap();
+    writeln!(out, "            }}").unwrap();
+    writeln!(out, "        }}").unwrap();
+    writeln!(out, "        Ok(flags)").unwrap();
+    writeln!(out, "    }}").unwrap();
+    writeln!(out, "}}\n").unwrap();
+}
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-changed=src/messages.json");
+    println!("cargo:rerun-if-changed=src/types.json");
+
+    let messages: Value = serde_json::from_str(&fs::read_to_string("src/messages.json").unwrap()).unwrap();
+    let types: Value = serde_json::from_str(&fs::read_to_string("src/types.json").unwrap()).unwrap();
+
+    let mut base_types: HashMap<String, String> = HashMap::new();
+    let mut enum_types: HashSet<String> = HashSet::new();
+    let mut type_values: HashMap<String, HashMap<String, u64>> = HashMap::new();
+    let mut out = String::new();
+    for a_type in types.as_array().unwrap() {
+        let type_name = a_type["type_name"].as_str().unwrap();
+        base_types.insert(type_name.to_string(), a_type["base_type"].as_str().unwrap().to_string());
+        type_values.insert(type_name.to_string(), a_type["values"].as_object().unwrap().iter()
+            .map(|(k, v)| (k.clone(), v.as_u64().unwrap()))
+            .collect());
+        let has_values = !a_type["values"].as_object().unwrap().is_empty();
+        if has_values && !NUMERIC_TYPES.contains(&type_name) {
+            enum_types.insert(type_name.to_string());
+            if a_type["bitfield"].as_bool() == Some(true) {
+                generate_flags(&mut out, a_type);
+            } else {
+                generate_enum(&mut out, a_type);
+            }
+        }
+    }
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("types.rs"), out).unwrap();
+
+    let mut out = String::new();
+    for message in messages.as_array().unwrap() {
+        generate_message(&mut out, message, &base_types, &enum_types, &type_values);
+    }
+    fs::write(Path::new(&out_dir).join("messages.rs"), out).unwrap();
+}
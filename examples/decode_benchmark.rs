@@ -0,0 +1,119 @@
+// Time decoding of a long activity, e.g. `cargo run --release --example decode_benchmark -- 4`
+// for 4 hours of 1 s records, and of the same time of 25 Hz accelerometer data. The files are
+// made up, so that no sample file is needed.
+
+use std::io::Cursor;
+use std::time::Instant;
+
+use smallvec::smallvec;
+
+use fit_reader::fitcrc;
+use fit_reader::fiterror::FitResult;
+use fit_reader::fitfile::{FitReader, FitWriter};
+use fit_reader::fitheader::read_global_header;
+use fit_reader::fitindex::FitIndex;
+use fit_reader::fitlisten::FitDispatcher;
+use fit_reader::fitmessages::{Record, Session};
+use fit_reader::fitrecord::read_record;
+use fit_reader::fittypes::{FitDataField, FitDataMessage, FitFieldData, FitFileContext, FitFileHeader};
+
+fn activity(hours: u32) -> FitResult<Vec<u8>> {
+    let mut writer = FitWriter::new(Vec::new(), FitFileHeader{ header_size: 14, ..Default::default() });
+    for i in 0..hours * 3600 {
+        let t = i as f64;
+        let record = Record{
+            timestamp: Some(1_000_000_000 + i),
+            position_lat: Some(45.0 + t * 1e-5),
+            position_long: Some(-75.0 + t * 1e-5),
+            altitude: Some(100.0 + (t / 60.0).sin() * 10.0),
+            heart_rate: Some(120 + (i % 40) as u8),
+            cadence: Some(85),
+            distance: Some(t * 8.0),
+            speed: Some(8.0),
+            power: Some(200 + (i % 50) as u16),
+            temperature: Some(20),
+            ..Default::default()
+        };
+        writer.write_message(FitDataMessage::from(&record))?;
+    }
+    writer.finish()?;
+    Ok(writer.into_inner())
+}
+
+/// Accelerometer data, which is held in arrays of 25 values per second.
+fn accelerometer(hours: u32) -> FitResult<Vec<u8>> {
+    let mut writer = FitWriter::new(Vec::new(), FitFileHeader{ header_size: 14, ..Default::default() });
+    for i in 0..hours * 3600 {
+        let samples = |x: u16| FitFieldData::FitUint16((0..25).map(|j| x + j).collect());
+        let field = |field_defn_num, data| FitDataField{ field_defn_num, data };
+        let mesg = FitDataMessage{
+            global_message_number: 165,  // accelerometer_data
+            fields: vec![
+                field(253, FitFieldData::FitUint32(smallvec![1_000_000_000 + i])),
+                field(0, FitFieldData::FitUint16(smallvec![0])),
+                field(1, FitFieldData::FitUint16((0..25).map(|j| j * 40).collect())),
+                field(2, samples(2000)),
+                field(3, samples(2100)),
+                field(4, samples(2200)),
+            ],
+            ..Default::default()
+        };
+        writer.write_message(mesg)?;
+    }
+    writer.finish()?;
+    Ok(writer.into_inner())
+}
+
+/// Decode the records without converting to physical values.
+fn read_records(data: &[u8]) -> FitResult<usize> {
+    let mut context = FitFileContext::default();
+    let mut reader = Cursor::new(data);
+    let header = read_global_header(&mut context, &mut reader)?;
+    let mut count = 0;
+    while context.data_bytes_read < header.data_size {
+        read_record(&mut context, &mut reader)?;
+        count += 1;
+    }
+    Ok(count)
+}
+
+fn time<F: FnMut() -> FitResult<usize>>(name: &str, bytes: usize, mut f: F) -> FitResult<()> {
+    // The fastest of several runs, the others are slowed by whatever else is running.
+    let mut seconds = f64::MAX;
+    let mut records = 0;
+    for _ in 0..20 {
+        let start = Instant::now();
+        records = f()?;
+        seconds = seconds.min(start.elapsed().as_secs_f64());
+    }
+    println!("{:<24} {:>8} records {:>8.1} ms {:>8.1} MB/s",
+             name, records, seconds * 1e3, bytes as f64 / seconds / 1e6);
+    Ok(())
+}
+
+fn main() -> FitResult<()> {
+    let hours = std::env::args().nth(1).and_then(|x| x.parse().ok()).unwrap_or(4);
+    for (name, data) in [("activity", activity(hours)?), ("accelerometer", accelerometer(hours)?)].iter() {
+        println!("{}: {} hours, {} bytes", name, hours, data.len());
+        time("records", data.len(), || read_records(data))?;
+        time("FitReader", data.len(), || {
+            let mut count = 0;
+            for rec in FitReader::new(data.as_slice()) {
+                rec?;
+                count += 1;
+            }
+            Ok(count)
+        })?;
+        time("sessions only", data.len(), || {
+            let mut count = 0;
+            let mut dispatcher = FitDispatcher::new();
+            dispatcher.add_listener(Session::MESG_NUM, |_| count += 1);
+            dispatcher.read(&mut FitReader::new(data.as_slice()))?;
+            drop(dispatcher);
+            Ok(count)
+        })?;
+        time("FitIndex::build", data.len(), || Ok(FitIndex::build(data.as_slice())?.entries().len()))?;
+        time("verify_file", data.len(), || Ok(fitcrc::verify_file(data.as_slice())?.len()))?;
+    }
+    Ok(())
+}
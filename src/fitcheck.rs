@@ -5,6 +5,7 @@ use chrono::{Utc};
 use chrono::offset::TimeZone;
 
 
+use crate::fiterror::{FitErrorKind, FitResult};
 use crate::fittypes::{ FitFileContext,
                        FitFieldData,
                        FitRecord, FitDataMessage};
@@ -42,7 +43,7 @@ fn clamp_timestamp(v: i64) -> u32
 }
 
 pub fn check_rec(context: &FitFileContext, rec: &FitRecord)
-             -> Result< (), std::io::Error>
+             -> FitResult<()>
 {
     let now = Utc::now();
     let base_datetime = Utc.ymd(1989, 12, 31).and_hms(0, 0, 0);
@@ -66,10 +67,10 @@ pub fn check_rec(context: &FitFileContext, rec: &FitRecord)
                     let utc_dt = base_datetime + chrono::Duration::seconds(x as i64);
                     if x < offset_min || x > offset_max {
                         let errstr = format!("Timestamp error: Out of permitted range {}", utc_dt.to_rfc3339());
-                        return Err(std::io::Error::new(std::io::ErrorKind::Other, errstr));
+                        return Err(FitErrorKind::InvalidValue(errstr).into());
                     } else if x < context.timestamp {
                         let errstr = format!("Timestamp error: Timestamp is before previous one {}", utc_dt.to_rfc3339());
-                        return Err(std::io::Error::new(std::io::ErrorKind::Other, errstr));
+                        return Err(FitErrorKind::InvalidValue(errstr).into());
                     } else {
                         //println!("Timestamp: {}", utc_dt.to_rfc3339());
                     }
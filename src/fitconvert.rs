@@ -0,0 +1,191 @@
+// Conversion between the raw values stored in a FIT file and physical values.
+//
+// The FIT spec defines: physical = raw / scale - offset, so raw = (physical + offset) * scale.
+// Positions are stored in semicircles, where 2^31 semicircles = 180 degrees.
+
+use crate::fittypes::{FitDataType, FitFieldData, FitDevDataDescription};
+use crate::profile::{ProfileComponent, ProfileField};
+
+/// Degrees per semicircle, 180 / 2^31.
+pub const DEGREES_PER_SEMICIRCLE: f64 = 180.0 / 2147483648.0;
+
+/// How to convert the raw values of one field.
+#[derive(Copy, Clone, Default)]
+#[derive(Debug, PartialEq)]
+pub struct FitConversion {
+    pub scale: Option<f64>,
+    pub offset: Option<f64>,
+    pub semicircles: bool,  // Physical values are given in degrees.
+}
+
+fn is_semicircles(units: &Option<String>) -> bool {
+    units.as_deref() == Some("semicircles")
+}
+
+impl FitConversion {
+    pub fn from_field(field: &ProfileField) -> FitConversion {
+        FitConversion { scale: field.scale, offset: field.offset, semicircles: is_semicircles(&field.units) }
+    }
+
+    pub fn from_component(component: &ProfileComponent) -> FitConversion {
+        FitConversion { scale: component.scale, offset: component.offset,
+            semicircles: is_semicircles(&component.units) }
+    }
+
+    pub fn from_dev_field(desc: &FitDevDataDescription) -> FitConversion {
+        FitConversion { scale: desc.scale, offset: desc.offset, semicircles: is_semicircles(&desc.units) }
+    }
+
+    /// True if the raw value is the physical value.
+    pub fn is_identity(&self) -> bool {
+        !self.semicircles && self.scale.unwrap_or(1.0) == 1.0 && self.offset.unwrap_or(0.0) == 0.0
+    }
+
+    pub fn to_physical(&self, raw: f64) -> f64 {
+        let value = raw / self.scale.unwrap_or(1.0) - self.offset.unwrap_or(0.0);
+        if self.semicircles {
+            value * DEGREES_PER_SEMICIRCLE
+        } else {
+            value
+        }
+    }
+
+    /// The raw value, not rounded.
+    pub fn to_raw(&self, value: f64) -> f64 {
+        let value = if self.semicircles { value / DEGREES_PER_SEMICIRCLE } else { value };
+        (value + self.offset.unwrap_or(0.0)) * self.scale.unwrap_or(1.0)
+    }
+
+    /// Units of the physical value, given the units of the field.
+    pub fn units<'a>(&self, units: Option<&'a str>) -> Option<&'a str> {
+        if self.semicircles {
+            Some("degrees")
+        } else {
+            units
+        }
+    }
+}
+
+/// Physical value of a raw value of the given profile field.
+pub fn raw_to_physical(field: &ProfileField, raw: f64) -> f64 {
+    FitConversion::from_field(field).to_physical(raw)
+}
+
+/// Raw value for a physical value of the given profile field, rounded to the nearest integer.
+pub fn physical_to_raw(field: &ProfileField, value: f64) -> f64 {
+    FitConversion::from_field(field).to_raw(value).round()
+}
+
+/// The physical values of numeric field data, or None for strings.
+/// Invalid values are None, so that they are not mistaken for measurements.
+pub fn field_data_to_physical(conversion: &FitConversion, data: &FitFieldData) -> Option<Vec<Option<f64>>> {
+    let raw: Vec<f64> = match data {
+        FitFieldData::FitF32(x) => x.iter().map(|v| *v as f64).collect(),
+        FitFieldData::FitF64(x) => x.to_vec(),
+        _ => data.integer_values()?.into_iter().map(|v| signed_raw(data, v)).collect(),
+    };
+    Some(raw.iter().zip(data.valid_mask())
+        .map(|(v, valid)| if valid { Some(conversion.to_physical(*v)) } else { None })
+        .collect())
+}
+
+/// Field data of the given type holding the physical values. Integer types are rounded.
+/// Missing values are stored as the invalid value of the type.
+pub fn physical_to_field_data(conversion: &FitConversion, data_type: FitDataType, values: &[Option<f64>]) -> FitFieldData {
+    let is_float = matches!(data_type, FitDataType::FitF32 | FitDataType::FitF64);
+    if !is_float {
+        let raw: Vec<f64> = values.iter()
+            .map(|v| match v {
+                Some(v) => conversion.to_raw(*v).round(),
+                None => data_type.invalid_value() as f64,
+            })
+            .collect();
+        return FitFieldData::from_f64_values(data_type, &raw);
+    }
+    let raw: Vec<u64> = values.iter()
+        .map(|v| match (v, data_type) {
+            (Some(v), FitDataType::FitF32) => (conversion.to_raw(*v) as f32).to_bits() as u64,
+            (Some(v), _) => conversion.to_raw(*v).to_bits(),
+            (None, _) => data_type.invalid_value(),
+        })
+        .collect();
+    FitFieldData::from_integer_values(data_type, &raw)
+}
+
+/// Integer values are returned as u64, restore the sign of signed types.
+fn signed_raw(data: &FitFieldData, value: u64) -> f64 {
+    match data {
+        FitFieldData::FitSint8(_) => value as i8 as f64,
+        FitFieldData::FitSint16(_) => value as i16 as f64,
+        FitFieldData::FitSint32(_) => value as i32 as f64,
+        FitFieldData::FitSInt64(_) => value as i64 as f64,
+        _ => value as f64,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::profile::build_profile;
+
+    fn assert_close(expected: f64, actual: f64) {
+        assert!((expected - actual).abs() < 1e-9, "Expected {} got {}", expected, actual);
+    }
+
+    #[test]
+    fn test_scale_offset() {
+        let p = build_profile().unwrap();
+        let record = p.get_message(20).unwrap();
+
+        // altitude: scale 5, offset 500
+        let altitude = record.find_field(2).unwrap();
+        assert_close(278.2, raw_to_physical(altitude, 3891.0));
+        assert_eq!(3891.0, physical_to_raw(altitude, 278.2));
+        assert_eq!(-500.0, raw_to_physical(altitude, 0.0));
+
+        // heart_rate: no scale or offset
+        let heart_rate = record.find_field(3).unwrap();
+        assert!(FitConversion::from_field(heart_rate).is_identity());
+        assert_eq!(150.0, raw_to_physical(heart_rate, 150.0));
+    }
+
+    #[test]
+    fn test_semicircles() {
+        let p = build_profile().unwrap();
+        let position_lat = p.get_message(20).unwrap().find_field(0).unwrap();
+
+        assert_eq!(90.0, raw_to_physical(position_lat, 1073741824.0));
+        assert_eq!(-180.0, raw_to_physical(position_lat, -2147483648.0));
+        assert_eq!(1073741824.0, physical_to_raw(position_lat, 90.0));
+
+        let conversion = FitConversion::from_field(position_lat);
+        assert_eq!(Some("degrees"), conversion.units(position_lat.units.as_deref()));
+    }
+
+    #[test]
+    fn test_field_data() {
+        let conversion = FitConversion{ scale: Some(100.0), offset: None, semicircles: false };
+        let data = physical_to_field_data(&conversion, FitDataType::FitSint16, &[Some(-1.5), Some(2.254)]);
+        assert!(matches!(&data, FitFieldData::FitSint16(x) if x[..] == [-150, 225]));
+        let values = field_data_to_physical(&conversion, &data).unwrap();
+        assert_close(-1.5, values[0].unwrap());
+        assert_close(2.25, values[1].unwrap());
+        assert_eq!(None, field_data_to_physical(&conversion, &FitFieldData::FitString("a".to_string(), 2)));
+    }
+
+    #[test]
+    fn test_invalid_values() {
+        let conversion = FitConversion{ scale: Some(100.0), offset: None, semicircles: false };
+        let data = FitFieldData::FitUint16(smallvec![0xFFFF, 150]);
+        assert_eq!(Some(vec![None, Some(1.5)]), field_data_to_physical(&conversion, &data));
+
+        let data = FitFieldData::FitF32(smallvec![f32::from_bits(0xFFFFFFFF), 2.0]);
+        assert_eq!(Some(vec![None, Some(0.02)]), field_data_to_physical(&conversion, &data));
+
+        let data = physical_to_field_data(&conversion, FitDataType::FitUint16, &[None, Some(1.5)]);
+        assert!(matches!(&data, FitFieldData::FitUint16(x) if x[..] == [0xFFFF, 150]));
+        let data = physical_to_field_data(&conversion, FitDataType::FitF32, &[None]);
+        assert!(!data.is_valid());
+    }
+}
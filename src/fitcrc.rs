@@ -2,7 +2,11 @@
 extern crate base64;
 
 use byteorder::{LittleEndian, ReadBytesExt};
-use std::io::{Read, Seek};
+use std::io::{Read, Seek, Write};
+
+use crate::fiterror::{FitError, FitErrorKind, FitResult};
+use crate::fitheader::read_global_header;
+use crate::fittypes::{FitFileContext, FitFileHeader};
 
 #[derive(Copy, Clone, Default)]
 #[derive(Debug)]
@@ -24,7 +28,7 @@ impl FitCrc {
 
     pub fn reset(&mut self) { self.crc = 0; }
 
-    pub fn compute_crc<T: Read + Seek>(file: &mut T, from:u64, count:u64) -> Result<u16, std::io::Error>
+    pub fn compute_crc<T: Read + Seek>(file: &mut T, from:u64, count:u64) -> FitResult<u16>
     {
         file.seek(std::io::SeekFrom::Start(from))?;
 
@@ -32,10 +36,10 @@ impl FitCrc {
         let mut context = FitCrc::new();
         let mut remain = count;
         while remain != 0 {
-            let to_read = std::cmp::min(buff.len(), count as usize);
+            let to_read = std::cmp::min(buff.len() as u64, remain) as usize;
             let n = match file.read(&mut buff[0..to_read]) {
                 Ok(x) => {x},
-                Err(e) => { return Err(e);},
+                Err(e) => { return Err(e.into());},
             };
             if n == 0 {
                 break;
@@ -46,14 +50,14 @@ impl FitCrc {
         return Ok(context.digest());
     }
 
-    pub fn read_crc<T: Read + Seek>(file: &mut T, offset:u64) -> Result<u16, std::io::Error>
+    pub fn read_crc<T: Read + Seek>(file: &mut T, offset:u64) -> FitResult<u16>
     {
         file.seek(std::io::SeekFrom::Start(offset))?;
         let crc = file.read_u16::<LittleEndian>()?;
         return Ok(crc);
     }
 
-    pub fn check_crc<T: Read + Seek>(file: &mut T, from:u64, count:u64) -> Result<bool, std::io::Error>
+    pub fn check_crc<T: Read + Seek>(file: &mut T, from:u64, count:u64) -> FitResult<bool>
     {
         let crc_read = Self::read_crc(file, from + count - 2)?;
         let crc_computed = Self::compute_crc(file, from, count - 2)?;
@@ -66,7 +70,7 @@ pub fn compute(data: &[u8]) -> u16 {
     return context.digest();
 }
 
-pub fn crc_for_file<T: Read + Seek>(file: &mut T) -> Result< u16, std::io::Error>
+pub fn crc_for_file<T: Read + Seek>(file: &mut T) -> FitResult<u16>
 {
     file.seek(std::io::SeekFrom::Start(0))?;
 
@@ -75,7 +79,7 @@ pub fn crc_for_file<T: Read + Seek>(file: &mut T) -> Result< u16, std::io::Error
     loop {
         let n = match file.read(&mut buff) {
             Ok(x) => {x},
-            Err(e) => { return Err(e);},
+            Err(e) => { return Err(e.into());},
         };
         if n == 0 {
             break;
@@ -85,24 +89,147 @@ pub fn crc_for_file<T: Read + Seek>(file: &mut T) -> Result< u16, std::io::Error
     return Ok(context.digest());
 }
 
+/// Reads bytes and computes their CRC as they pass through.
+pub struct CrcReader<R: Read> {
+    inner: R,
+    crc: FitCrc,
+}
+
+impl<R: Read> CrcReader<R> {
+    pub fn new(inner: R) -> CrcReader<R> {
+        CrcReader::with_crc(inner, FitCrc::new())
+    }
+
+    /// Continue a CRC, e.g. one that includes the header.
+    pub fn with_crc(inner: R, crc: FitCrc) -> CrcReader<R> {
+        CrcReader{ inner, crc }
+    }
+
+    /// The CRC of the bytes read so far.
+    pub fn digest(&self) -> u16 { self.crc.digest() }
+
+    pub fn into_inner(self) -> R { self.inner }
+}
+
+impl<R: Read> Read for CrcReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.crc.consume(&buf[..n]);
+        Ok(n)
+    }
+}
+
+/// Writes bytes and computes their CRC as they pass through.
+pub struct CrcWriter<W: Write> {
+    inner: W,
+    crc: FitCrc,
+}
+
+impl<W: Write> CrcWriter<W> {
+    pub fn new(inner: W) -> CrcWriter<W> {
+        CrcWriter{ inner, crc: FitCrc::new() }
+    }
+
+    /// The CRC of the bytes written so far.
+    pub fn digest(&self) -> u16 { self.crc.digest() }
+
+    pub fn into_inner(self) -> W { self.inner }
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.crc.consume(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Check the header CRC and the file CRC of each of chained files, without decoding the records.
+/// Returns the headers. Give it a buffered source, the header is read a byte at a time.
+pub fn verify_file<R: Read>(mut source: R) -> FitResult<Vec<FitFileHeader>> {
+    let mut headers = Vec::new();
+    let mut file_start = 0;
+    let mut pending = None;
+    loop {
+        let mut context = FitFileContext{ file_start, ..Default::default() };
+        let header = match pending.take() {
+            Some(byte) => read_global_header(&mut context, &mut [byte].as_ref().chain(&mut source))?,
+            None => read_global_header(&mut context, &mut source)?,
+        };
+        let data_start = file_start + header.header_size as u64;
+        let data_size = header.data_size as u64;
+
+        let mut data = CrcReader::with_crc(source.by_ref().take(data_size), context.crc);
+        let copied = std::io::copy(&mut data, &mut std::io::sink())
+            .map_err(|e| FitError::from(e).at(data_start, 1))?;
+        let crc_offset = data_start + copied;
+        let at = |kind: FitErrorKind| FitError{ kind, offset: Some(crc_offset), record_index: None };
+        if copied < data_size {
+            return Err(at(FitErrorKind::Truncated));
+        }
+        let computed = data.digest();
+        let expected = source.read_u16::<LittleEndian>().map_err(|e| at(FitError::from(e).kind))?;
+        if expected != computed {
+            return Err(at(FitErrorKind::FileCrc{ expected, computed }));
+        }
+        headers.push(header);
+        file_start = crc_offset + 2;
+
+        // Another file may follow.
+        let mut buf = [0u8; 1];
+        if source.read(&mut buf).map_err(|e| FitError::from(e).at(file_start, 0))? == 0 {
+            return Ok(headers);
+        }
+        pending = Some(buf[0]);
+    }
+}
+
+/// Tables for the CRC of a byte followed by 0 to 7 zero bytes, so that 8 bytes are done at a time.
+const CRC_TABLES: [[u16; 256]; 8] = crc_tables();
+
+const fn crc_tables() -> [[u16; 256]; 8] {
+    let mut tables = [[0u16; 256]; 8];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u16;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xA001 } else { crc >> 1 };
+            bit += 1;
+        }
+        tables[0][i] = crc;
+        i += 1;
+    }
+    let mut k = 1;
+    while k < 8 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = tables[k - 1][i];
+            tables[k][i] = (prev >> 8) ^ tables[0][(prev & 0xFF) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    tables
+}
+
 fn fit_crc_16(mut crc: u16, byte_array: &[u8]) -> u16 {
-    let crc_table: [u16; 16] =  [
-        0x0000, 0xCC01, 0xD801, 0x1400, 0xF001, 0x3C00, 0x2800, 0xE401,
-        0xA001, 0x6C00, 0x7800, 0xB401, 0x5000, 0x9C01, 0x8801, 0x4400
-    ];
-
-    for byte in byte_array.iter() {
-        // compute checksum of lower four bits of byte
-        let mut tmp = crc_table[(crc & 0xF) as usize];
-        crc = (crc >> 4) & 0x0FFF;
-        crc = crc ^ tmp ^ crc_table[(byte & 0xF) as usize];
-
-        // now compute checksum of upper four bits of byte
-        tmp = crc_table[(crc & 0xF) as usize];
-        crc = (crc >> 4) & 0x0FFF;
-        crc = crc ^ tmp ^ crc_table[((byte >> 4) & 0xFu8) as usize];
-    }
-    return crc;
+    let t = &CRC_TABLES;
+    let mut chunks = byte_array.chunks_exact(8);
+    for b in &mut chunks {
+        let c = crc ^ u16::from_le_bytes([b[0], b[1]]);
+        crc = t[7][(c & 0xFF) as usize] ^ t[6][(c >> 8) as usize]
+            ^ t[5][b[2] as usize] ^ t[4][b[3] as usize] ^ t[3][b[4] as usize]
+            ^ t[2][b[5] as usize] ^ t[1][b[6] as usize] ^ t[0][b[7] as usize];
+    }
+    for byte in chunks.remainder() {
+        crc = (crc >> 8) ^ t[0][((crc ^ *byte as u16) & 0xFF) as usize];
+    }
+    crc
 }
 
 
@@ -113,7 +240,7 @@ mod tests {
     use std::io::Cursor;
 
     #[test]
-    fn test_crc() -> Result< (), std::io::Error>
+    fn test_crc() -> FitResult<()>
     {
         let ba : [u8; 0] = [];
         assert_eq!(1234_u16, fit_crc_16(1234, &ba));
@@ -144,4 +271,78 @@ mod tests {
         assert_eq!(settings_crc, compute(&settings_fit[..]));
         Ok(())
     }
+
+    // The FIT SDK's CRC, a nibble at a time.
+    fn nibble_crc_16(mut crc: u16, byte_array: &[u8]) -> u16 {
+        let crc_table: [u16; 16] =  [
+            0x0000, 0xCC01, 0xD801, 0x1400, 0xF001, 0x3C00, 0x2800, 0xE401,
+            0xA001, 0x6C00, 0x7800, 0xB401, 0x5000, 0x9C01, 0x8801, 0x4400
+        ];
+        for byte in byte_array.iter() {
+            let mut tmp = crc_table[(crc & 0xF) as usize];
+            crc = (crc >> 4) & 0x0FFF;
+            crc = crc ^ tmp ^ crc_table[(byte & 0xF) as usize];
+            tmp = crc_table[(crc & 0xF) as usize];
+            crc = (crc >> 4) & 0x0FFF;
+            crc = crc ^ tmp ^ crc_table[((byte >> 4) & 0xFu8) as usize];
+        }
+        crc
+    }
+
+    #[test]
+    fn test_same_as_nibbles() -> FitResult<()> {
+        let data: Vec<u8> = (0..3000u32).map(|x| (x * 7919 % 251) as u8).collect();
+        for len in 0..40 {
+            assert_eq!(nibble_crc_16(0x1234, &data[..len]), fit_crc_16(0x1234, &data[..len]));
+        }
+        assert_eq!(nibble_crc_16(0, &data), compute(&data));
+
+        // In pieces, and only the bytes asked for.
+        let mut crc = FitCrc::new();
+        data.chunks(13).for_each(|x| crc.consume(x));
+        assert_eq!(compute(&data), crc.digest());
+        assert_eq!(compute(&data[100..1600]), FitCrc::compute_crc(&mut Cursor::new(&data), 100, 1500)?);
+
+        let mut reader = CrcReader::new(&data[..]);
+        std::io::copy(&mut reader, &mut std::io::sink())?;
+        let mut writer = CrcWriter::new(Vec::new());
+        writer.write_all(&data)?;
+        assert_eq!(compute(&data), reader.digest());
+        assert_eq!(compute(&data), writer.digest());
+        assert_eq!(data, writer.into_inner());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_file() -> FitResult<()> {
+        let settings_fit = base64::decode(
+            "DBBHAEQAAAAuRklUQAABAAAEAQKEAgKEAwSMAAEAAAABA9wAAeJAA\
+                   kAAAQADBQQChAEBAAIBAgMBAgUBAAADhAEcvgBAAAEABAEBAosAAGQ5UA==").unwrap();
+        let headers = verify_file(settings_fit.as_slice())?;
+        assert_eq!(1, headers.len());
+        assert_eq!(settings_fit.len() as u32 - 14, headers[0].data_size);
+
+        let mut chained = settings_fit.clone();
+        chained.extend_from_slice(&settings_fit);
+        assert_eq!(2, verify_file(chained.as_slice())?.len());
+
+        let last = chained.len() - 1;
+        chained[last] ^= 0xFF;
+        let e = verify_file(chained.as_slice()).unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::FileCrc{ .. }));
+        assert_eq!(Some(last as u64 - 1), e.offset);
+
+        let e = verify_file(&settings_fit[..40]).unwrap_err();
+        assert!(e.is_truncated());
+        assert_eq!(Some(40), e.offset);
+
+        let mut writer = crate::fitfile::FitWriter::new(Vec::new(), FitFileHeader{ header_size: 14, ..Default::default() });
+        writer.finish()?;
+        let mut bad_header = writer.into_inner();
+        assert_eq!(1, verify_file(bad_header.as_slice())?.len());
+        bad_header[12] ^= 0xFF;
+        let e = verify_file(bad_header.as_slice()).unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::HeaderCrc{ .. }));
+        Ok(())
+    }
 }
@@ -2,22 +2,27 @@
 // std imports
 use std::io::{Read, Write};
 
-use crate::fittypes::{FitDataType, FitFieldData, FitDataMessage, FitDataField, FitFileContext, FitDevDataDescription, FitDevDataField, FitFileDeveloperId, FitDeveloperFieldDefinition};
+use crate::fittypes::{FitDataType, FitFieldData, FitDataMessage, FitDefinitionMessage, FitDataField, FitFileContext, FitDevDataDescription, FitDevDataField, FitFileDeveloperId, FitAccumulator, FitUuid};
+use crate::fiterror::{FitErrorKind, FitResult};
 use crate::fitwrite::{fit_write_u8};
 
-use crate::fitfield::{read_fit_field, write_fit_field};
+use crate::fitfield::{decode_fit_field, write_fit_field};
+use crate::fitread::fit_read_bytes;
+use crate::profile::{ProfileData, ProfileField, ProfileMessage};
+use crate::fitconvert::{FitConversion, field_data_to_physical, physical_to_field_data, physical_to_raw, raw_to_physical};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::sync::Arc;
 
 pub fn read_data_message( context: &mut FitFileContext, reader: &mut dyn Read,
-                      local_message_type: u8, timestamp: Option<u32>) -> Result< FitDataMessage, std::io::Error> {
+                      local_message_type: u8, timestamp: Option<u32>) -> FitResult<FitDataMessage> {
 
     debug!("Data message, local ID: {:} at byte {:}", local_message_type, context.data_bytes_read);
 
     let defn_mesg=
         match context.field_definitions.get(&local_message_type) {
             Some(v) => v,
-            None => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Field id not found")),
+            None => return Err(FitErrorKind::UndefinedLocalMessage(local_message_type).into()),
         }.clone();
 
     let mut mesg = FitDataMessage{
@@ -29,17 +34,22 @@ pub fn read_data_message( context: &mut FitFileContext, reader: &mut dyn Read,
 
     context.architecture = Some(defn_mesg.architecture);
 
-    for field in &defn_mesg.field_defns {
-        let data_size = field.data_type.unwrap().data_size();
-        let count:u8 = match data_size {
-            0 => field.size_in_bytes,
-            _ => field.size_in_bytes / data_size };
+    // Read the whole message at once, then decode the fields from it.
+    let data = fit_read_bytes(context, reader, defn_mesg.data_size())?;
+    let mut pos = 0;
+    let mut next_field = |size_in_bytes: u8| {
+        let bytes = &data[pos..pos + size_in_bytes as usize];
+        pos += size_in_bytes as usize;
+        bytes
+    };
 
-        let field_value_data = read_fit_field(context, reader,
-                                              field.data_type.unwrap(), count)?;
+    mesg.fields.reserve(defn_mesg.field_defns.len());
+    for field in &defn_mesg.field_defns {
+        let field_value_data = decode_fit_field(context.architecture, field.data_type.unwrap(),
+                                                next_field(field.size_in_bytes))?;
         // If this is a timestamp, then update the file timestamp, for any compressed messages.
         if field.field_defn_num == 253 {
-            match field_value_data.clone() {
+            match &field_value_data {
                 FitFieldData::FitUint32(value) => context.timestamp = value[0],
                 _ => warn!("Warning, bad timestamp type")
             }
@@ -64,16 +74,22 @@ pub fn read_data_message( context: &mut FitFileContext, reader: &mut dyn Read,
             };
         }
 
-        if let Some(desc) = desc2
-        {
-            let base_type = desc.base_type.unwrap();
-            let data_size = base_type.data_size();
-            let count:u8 = match data_size {
-                0 => field.size_in_bytes,
-                _ => field.size_in_bytes / data_size };
+        if let Some(desc) = desc2.as_ref().filter(|x| x.base_type.is_some_and(|t| !t.fits_size(field.size_in_bytes))) {
+            let kind = FitErrorKind::InvalidFieldDefinition("developer field size is not a multiple of the base type");
+            if context.checks.field_sizes {
+                return Err(kind.into());
+            }
+            warn!("Dev field {} is read as bytes: {}", desc.field_name, kind);
+            context.warnings.push(kind.into());
+            desc2 = None;
+        }
 
-            let field_value_data = read_fit_field(context, reader,
-                                                  base_type, count)?;
+        // A description without a usable base type was let through when it was read, and the
+        // field is read as bytes.
+        let bytes = next_field(field.size_in_bytes);
+        if let Some((data_type, desc)) = desc2.and_then(|x| Some((x.base_type?, x)))
+        {
+            let field_value_data = decode_fit_field(context.architecture, data_type, bytes)?;
 
             let field_value = FitDevDataField {
                 field_defn_num: field.field_defn_num,
@@ -84,14 +100,9 @@ pub fn read_data_message( context: &mut FitFileContext, reader: &mut dyn Read,
         } else {
             // Field description not found. Load as bytes.
             warn!("Unknown dev field index={} defn_num={}", field.dev_data_index, field.field_defn_num);
-            let base_type = FitDataType::FitByte;
-            let count = field.size_in_bytes;
-            let field_value_data = read_fit_field(context, reader,
-                                                  base_type, count)?;
-
             let field_value = FitDevDataField {
                 field_defn_num: field.field_defn_num,
-                data: field_value_data,
+                data: FitFieldData::FitByte(bytes.into()),
                 description: None,
             };
             mesg.dev_fields.push(field_value);
@@ -99,35 +110,345 @@ pub fn read_data_message( context: &mut FitFileContext, reader: &mut dyn Read,
     }
 
 
-    debug!("Data message: {:?}", mesg);
+    if let Some(profile) = context.profile.clone() {
+        set_accumulated_fields(&profile, &mut context.accumulators, &mesg);
+        expand_components(&profile, &mut context.accumulators, &mut mesg);
+        add_native_fields(&profile, &mut mesg);
+    }
 
-    const FIELD_DESCRIPTION: u16 = 206;
+    // The timestamp of a compressed message is the reference for the next one. It is also
+    // given as a timestamp field, so that the message reads the same as an uncompressed one.
+    if let Some(x) = timestamp {
+        context.timestamp = x;
+        if !mesg.fields.iter().any(|f| f.field_defn_num == 253) {
+            mesg.expanded_fields.push(FitDataField{ field_defn_num: 253, data: FitFieldData::FitUint32(smallvec![x]) });
+        }
+    }
+
+    debug!("Data message: {:?}", mesg);
 
+    if defn_mesg.global_message_number == DEVELOPER_DATA_ID {
+        add_developer_data_id( context, &mesg );
+    }
     if defn_mesg.global_message_number == FIELD_DESCRIPTION {
-        add_dev_field_description( context, &mesg );
+        read_dev_field_description( context, &mesg )?;
     }
 
     Ok(mesg)
 }
 
-fn add_dev_field_description( context: &mut FitFileContext, mesg: &FitDataMessage )
+/// Read past a data message without decoding it, returning its global message number. Only the
+/// timestamp is decoded, as the compressed timestamps of later messages are offsets from it.
+pub fn skip_data_message( context: &mut FitFileContext, reader: &mut dyn Read,
+                          local_message_type: u8, timestamp: Option<u32>) -> FitResult<u16> {
+    skip_accumulating_data_message(context, reader, local_message_type, timestamp, &[])
+}
+
+/// Read past a data message as skip_data_message does, but decode the fields at the given
+/// positions of the definition to update the accumulators, see `accumulated_fields`.
+pub(crate) fn skip_accumulating_data_message( context: &mut FitFileContext, reader: &mut dyn Read,
+                                              local_message_type: u8, timestamp: Option<u32>,
+                                              accumulated: &[usize]) -> FitResult<u16> {
+    let defn_mesg = match context.field_definitions.get(&local_message_type) {
+        Some(v) => v.clone(),
+        None => return Err(FitErrorKind::UndefinedLocalMessage(local_message_type).into()),
+    };
+    context.architecture = Some(defn_mesg.architecture);
+
+    let data = fit_read_bytes(context, reader, defn_mesg.data_size())?;
+    let mut mesg = FitDataMessage{ global_message_number: defn_mesg.global_message_number, ..Default::default() };
+    let mut pos = 0;
+    for (i, field) in defn_mesg.field_defns.iter().enumerate() {
+        let bytes = &data[pos..pos + field.size_in_bytes as usize];
+        pos += bytes.len();
+        if field.field_defn_num == 253 {
+            if let FitFieldData::FitUint32(value) = decode_fit_field(context.architecture, field.data_type.unwrap(), bytes)? {
+                context.timestamp = value[0];
+            }
+        }
+        if accumulated.contains(&i) {
+            let data = decode_fit_field(context.architecture, field.data_type.unwrap(), bytes)?;
+            mesg.fields.push(FitDataField{ field_defn_num: field.field_defn_num, data });
+        }
+    }
+    if let Some(x) = timestamp {
+        context.timestamp = x;
+    }
+
+    if let Some(profile) = context.profile.clone().filter(|_| !mesg.fields.is_empty()) {
+        set_accumulated_fields(&profile, &mut context.accumulators, &mesg);
+        expand_components(&profile, &mut context.accumulators, &mut mesg);
+    }
+    Ok(defn_mesg.global_message_number)
+}
+
+/// Messages that are needed to read later ones, so are never skipped.
+pub(crate) fn is_developer_data(global_message_number: u16) -> bool {
+    global_message_number == DEVELOPER_DATA_ID || global_message_number == FIELD_DESCRIPTION
+}
+
+/// The positions in the definition of the fields which change the accumulators when decoded, so
+/// that they have to be decoded for the accumulated fields of later messages to be right. If a
+/// subfield accumulates, all the fields are needed to choose it.
+pub(crate) fn accumulated_fields(profile: &ProfileData, defn_mesg: &FitDefinitionMessage) -> Vec<usize> {
+    let message = match profile.get_message(defn_mesg.global_message_number) {
+        Some(x) => x,
+        None => return Vec::new(),
+    };
+    let accumulates = |x: &ProfileField| x.components.iter().any(|c| c.accumulate);
+    let fields: Vec<Option<&ProfileField>> = defn_mesg.field_defns.iter()
+        .map(|x| message.find_field(x.field_defn_num))
+        .collect();
+    if fields.iter().flatten().any(|x| x.subfields.iter().any(accumulates)) {
+        return (0..fields.len()).collect();
+    }
+    fields.iter().enumerate()
+        .filter(|(_, x)| x.is_some_and(|x| x.is_accumulated || accumulates(x)))
+        .map(|(i, _)| i)
+        .collect()
+}
+
+/// Read `bits` bits starting at bit `start` of a little-endian byte array.
+fn extract_bits(bytes: &[u8], start: usize, bits: u8) -> u64
+{
+    let mut value = 0u64;
+    for i in 0..bits as usize {
+        let bit = start + i;
+        if (bytes[bit / 8] >> (bit % 8)) & 1 != 0 {
+            value |= 1u64 << i;
+        }
+    }
+    value
+}
+
+/// Unpack the components of one field into the destination fields they describe.
+fn expand_field( accumulators: &mut HashMap<(u16, u8), FitAccumulator>,
+                 message: &ProfileMessage, field_desc: &ProfileField, data: &FitFieldData,
+                 expanded: &mut Vec<FitDataField> )
+{
+    if !data.is_valid() {
+        return;
+    }
+    let bytes = match data.to_le_bytes() {
+        Some(x) => x,
+        None => {
+            warn!("Can not expand components of field {}", field_desc.field_name);
+            return;
+        },
+    };
+
+    // Components with the same destination (e.g. hr.event_timestamp) are collected into an array.
+    let mut values: Vec<(&ProfileField, Vec<f64>)> = Vec::new();
+    let mut bit_pos = 0usize;
+    for component in &field_desc.components {
+        if bit_pos + component.bits as usize > bytes.len() * 8 {
+            break;
+        }
+        let mut raw = extract_bits(&bytes, bit_pos, component.bits);
+        bit_pos += component.bits as usize;
+
+        let dest = match component.field_defn_num.and_then(|x| message.find_field(x)) {
+            Some(x) => x,
+            None => {
+                warn!("Component {} of {} is not in the profile", component.field_name, field_desc.field_name);
+                continue;
+            },
+        };
+
+        if component.accumulate {
+            raw = accumulators.entry((message.mesg_num, dest.field_defn_num))
+                .or_default()
+                .accumulate(raw, component.bits);
+        }
+
+        // Convert to the physical value using the component's scale, then back to the
+        // destination's raw representation.
+        let value = FitConversion::from_component(component).to_physical(raw as f64);
+        let dest_raw = physical_to_raw(dest, value);
+
+        match values.iter_mut().find(|(f, _)| f.field_defn_num == dest.field_defn_num) {
+            Some((_, v)) => v.push(dest_raw),
+            None => values.push((dest, vec![dest_raw])),
+        }
+    }
+
+    for (dest, dest_values) in values {
+        let data_type = dest.base_type.unwrap_or(FitDataType::FitUint32);
+        expanded.push(FitDataField {
+            field_defn_num: dest.field_defn_num,
+            data: FitFieldData::from_f64_values(data_type, &dest_values),
+        });
+    }
+}
+
+/// When a field that is normally accumulated from components is sent in full, restart the
+/// accumulation from its value. The accumulator counts in the component's units.
+fn set_accumulated_fields( profile: &ProfileData, accumulators: &mut HashMap<(u16, u8), FitAccumulator>,
+                           mesg: &FitDataMessage )
+{
+    let message = match profile.get_message(mesg.global_message_number) {
+        Some(x) => x,
+        None => return,
+    };
+    for field in &mesg.fields {
+        let field_desc = match message.find_field(field.field_defn_num) {
+            Some(x) if x.is_accumulated => x,
+            _ => continue,
+        };
+        if !field.data.is_valid() {
+            continue;
+        }
+        let component = match message.accumulating_component(&field_desc.field_name) {
+            Some(x) => x,
+            None => continue,
+        };
+        if let Some(raw) = field.data.integer_values().and_then(|x| x.last().copied()) {
+            let value = raw_to_physical(field_desc, raw as f64);
+            let component_raw = FitConversion::from_component(component).to_raw(value).round();
+            accumulators.entry((message.mesg_num, field.field_defn_num))
+                .or_default()
+                .set(component_raw as u64);
+        }
+    }
+}
+
+/// Expand bit-packed components into `expanded_fields`, as the profile describes.
+fn expand_components( profile: &ProfileData, accumulators: &mut HashMap<(u16, u8), FitAccumulator>,
+                      mesg: &mut FitDataMessage )
+{
+    let message = match profile.get_message(mesg.global_message_number) {
+        Some(x) => x,
+        None => return,
+    };
+
+    let mut expanded: Vec<FitDataField> = Vec::new();
+    for field in &mesg.fields {
+        if let Some(field_desc) = message.resolve_field(profile, field.field_defn_num, mesg) {
+            if !field_desc.components.is_empty() {
+                expand_field(accumulators, message, field_desc, &field.data, &mut expanded);
+            }
+        }
+    }
+
+    // Expanded fields may themselves have components, e.g. event.data16 -> data -> gear values.
+    let mut i = 0;
+    while i < expanded.len() {
+        if let Some(field_desc) = message.resolve_field(profile, expanded[i].field_defn_num, mesg) {
+            if !field_desc.components.is_empty() {
+                let data = expanded[i].data.clone();
+                expand_field(accumulators, message, field_desc, &data, &mut expanded);
+            }
+        }
+        i += 1;
+    }
+
+    mesg.expanded_fields = expanded;
+}
+
+pub(crate) const FIELD_DESCRIPTION: u16 = 206;
+pub(crate) const DEVELOPER_DATA_ID: u16 = 207;
+
+/// Record the application of a developer_data_id message. The fields of any application
+/// previously using the same developer data index are forgotten.
+pub(crate) fn add_developer_data_id( context: &mut FitFileContext, mesg: &FitDataMessage )
+{
+    const DEVELOPER_ID: u8 = 0;
+    const APPLICATION_ID: u8 = 1;
+    const MANUFACTURER_ID: u8 = 2;
+    const DEVELOPER_DATA_INDEX: u8 = 3;
+    const APPLICATION_VERSION: u8 = 4;
+
+    let mut developer_id: FitFileDeveloperId = Default::default();
+    for ifield in &mesg.fields {
+        let value = if ifield.data.is_valid() { ifield.data.first_integer() } else { None };
+        match ifield.field_defn_num {
+            DEVELOPER_ID => developer_id.developer_id = FitUuid::from_field_data(&ifield.data),
+            APPLICATION_ID => developer_id.application_id = FitUuid::from_field_data(&ifield.data),
+            MANUFACTURER_ID => developer_id.manufacturer_id = value.map(|x| x as u16),
+            DEVELOPER_DATA_INDEX => developer_id.developer_data_index = value.map(|x| x as u8),
+            APPLICATION_VERSION => developer_id.application_version = value.map(|x| x as u32),
+            _ => {
+                debug!("Not using developer data id:{}, {:?}", &ifield.field_defn_num, &ifield.data);
+            }
+        }
+    }
+    match developer_id.developer_data_index {
+        Some(index) => {
+            debug!("Inserting developer data id: {} = {:?}", index, &developer_id);
+            context.developer_ids.insert(index, developer_id);
+        },
+        None => warn!("Developer data id has no developer data index"),
+    }
+}
+
+/// A developer field may stand for a field of the profile, e.g. power from a power meter app
+/// for record.power. If the message has no valid value of its own for that field, the developer
+/// field is converted and added to `expanded_fields`.
+fn add_native_fields( profile: &ProfileData, mesg: &mut FitDataMessage )
+{
+    let message = match profile.get_message(mesg.global_message_number) {
+        Some(x) => x,
+        None => return,
+    };
+
+    let mut native: Vec<FitDataField> = Vec::new();
+    for dev_field in &mesg.dev_fields {
+        let desc = match &dev_field.description {
+            Some(x) if x.native_mesg_num == Some(mesg.global_message_number) => x,
+            _ => continue,
+        };
+        let field_defn_num = match desc.native_field_num {
+            Some(x) => x,
+            None => continue,
+        };
+        let has_value = mesg.fields.iter().chain(mesg.expanded_fields.iter()).chain(native.iter())
+            .any(|x| x.field_defn_num == field_defn_num && x.data.is_valid());
+        let field_desc = message.find_field(field_defn_num);
+        let data_type = field_desc.and_then(|x| x.base_type);
+        let values = field_data_to_physical(&FitConversion::from_dev_field(desc), &dev_field.data);
+        if let (false, Some(field_desc), Some(data_type), Some(values)) = (has_value, field_desc, data_type, values) {
+            debug!("Developer field {} is {}", desc.field_name, field_desc.field_name);
+            let data = physical_to_field_data(&FitConversion::from_field(field_desc), data_type, &values);
+            native.push(FitDataField{ field_defn_num, data });
+        }
+    }
+    mesg.expanded_fields.append(&mut native);
+}
+
+/// Add the description of a field that was read. One without a usable base type is an error if
+/// base types are checked, otherwise it is a warning and its fields are read as bytes.
+pub(crate) fn read_dev_field_description( context: &mut FitFileContext, mesg: &FitDataMessage ) -> FitResult<()>
+{
+    if let Err(e) = add_dev_field_description( context, mesg ) {
+        if context.checks.valid_base_types {
+            return Err(e);
+        }
+        warn!("Developer field is read as bytes: {}", e);
+        context.warnings.push(e);
+    }
+    Ok(())
+}
+
+/// The description is added even if its base type is missing or unknown, which is then the error.
+pub(crate) fn add_dev_field_description( context: &mut FitFileContext, mesg: &FitDataMessage ) -> FitResult<()>
 {
     const DEV_DATA_INDEX: u8 = 0;
     const FIELD_DEFN_NUM: u8 = 1;
     const BASE_TYPE_ID: u8 = 2;
     const NAME: u8 = 3;
-    // ARRAY = 4,
-    // 5	components
+    const ARRAY: u8 = 4;
+    const COMPONENTS: u8 = 5;
     const SCALE: u8 = 6;
     const OFFSET: u8 = 7;
     const UNITS: u8 = 8;
-    //9	bits
-    //10	accumulate
-    //13	fit_base_unit_id
-    //14	native_mesg_num
-    //15	native_field_num
+    const BITS: u8 = 9;
+    const ACCUMULATE: u8 = 10;
+    const FIT_BASE_UNIT_ID: u8 = 13;
+    const NATIVE_MESG_NUM: u8 = 14;
+    const NATIVE_FIELD_NUM: u8 = 15;
 
     let mut has_field_defn = false;
+    let mut base_type_error = None;
 
     let mut dev_data_desc: FitDevDataDescription = Default::default();
     for ifield in &mesg.fields {
@@ -151,7 +472,10 @@ fn add_dev_field_description( context: &mut FitFileContext, mesg: &FitDataMessag
                 if  let Ok(x) = u8::try_from(&ifield.data) {
                     // Upper bit contains endianness
                     let type_id = x & 0x7F;
-                    dev_data_desc.base_type = Some(FitDataType::from_type_id(type_id).unwrap());
+                    match FitDataType::from_type_id(type_id) {
+                        Ok(t) => dev_data_desc.base_type = Some(t),
+                        Err(_) => base_type_error = Some(FitErrorKind::InvalidBaseType(x)),
+                    }
                 } else {
                     warn!("Bad type for base_type");
                 }
@@ -184,53 +508,142 @@ fn add_dev_field_description( context: &mut FitFileContext, mesg: &FitDataMessag
                     warn!("Bad type for units");
                 }
             },
+            ARRAY => {
+                // Any value other than zero marks an array.
+                if ifield.data.is_valid() {
+                    dev_data_desc.is_array = ifield.data.first_integer().map(|x| x != 0);
+                }
+            },
+            COMPONENTS => dev_data_desc.components = String::try_from(&ifield.data).ok(),
+            BITS => dev_data_desc.bits = String::try_from(&ifield.data).ok(),
+            ACCUMULATE => dev_data_desc.accumulate = String::try_from(&ifield.data).ok(),
+            FIT_BASE_UNIT_ID => {
+                if ifield.data.is_valid() {
+                    dev_data_desc.fit_base_unit_id = ifield.data.first_integer().map(|x| x as u16);
+                }
+            },
+            NATIVE_MESG_NUM => {
+                if ifield.data.is_valid() {
+                    dev_data_desc.native_mesg_num = ifield.data.first_integer().map(|x| x as u16);
+                }
+            },
+            NATIVE_FIELD_NUM => {
+                if ifield.data.is_valid() {
+                    dev_data_desc.native_field_num = ifield.data.first_integer().map(|x| x as u8);
+                }
+            },
             _ => {
                 debug!("Not using developer desc:{}, {:?}", &ifield.field_defn_num, &ifield.data);
             }
         }
     }
+    if dev_data_desc.base_type.is_none() && base_type_error.is_none() {
+        base_type_error = Some(FitErrorKind::InvalidFieldDefinition("developer field description has no base type"));
+    }
     if has_field_defn {
         debug!("Inserting field defn: {} = {:?}", &dev_data_desc.field_defn_num, &dev_data_desc);
         if !context.developer_ids.contains_key(&dev_data_desc.dev_data_index) {
-            let mut developer_id: FitFileDeveloperId = Default::default();
+            warn!("Field description for unknown developer data index {}", dev_data_desc.dev_data_index);
+            let developer_id: FitFileDeveloperId = Default::default();
             context.developer_ids.insert(dev_data_desc.dev_data_index, developer_id);
         }
         let dev_id = context.developer_ids.get_mut(&dev_data_desc.dev_data_index).unwrap();
+        dev_data_desc.application_id = dev_id.application_id;
+        dev_data_desc.application_version = dev_id.application_version;
         dev_id.developer_field_definitions.insert(dev_data_desc.field_defn_num,  Arc::new(dev_data_desc));
     } else {
         warn!("Developer field description has no field definition number");
     }
+    match base_type_error {
+        Some(kind) => Err(kind.into()),
+        None => Ok(()),
+    }
 }
 
-pub fn write_data_message( context: &mut FitFileContext, writer: &mut dyn Write, mesg: &FitDataMessage)
-                       -> Result< (), std::io::Error>
-{
-    let is_compressed = mesg.timestamp.is_some();
-    let record_hdr = if is_compressed {
-        assert!(mesg.local_message_type <= 0x03);
+/// Check that the fields of the message are those of the definition, with the same sizes and types.
+pub fn check_definition(defn: &FitDefinitionMessage, mesg: &FitDataMessage) -> FitResult<()> {
+    let mismatch = |x: String| Err(FitErrorKind::DefinitionMismatch(x).into());
+    if defn.global_message_number != mesg.global_message_number {
+        return mismatch(format!("global message number {} is not {}",
+                                mesg.global_message_number, defn.global_message_number));
+    }
+    if defn.field_defns.len() != mesg.fields.len() || defn.dev_field_defns.len() != mesg.dev_fields.len() {
+        return mismatch("number of fields".to_string());
+    }
+    for (field_defn, field) in defn.field_defns.iter().zip(&mesg.fields) {
+        if field_defn.field_defn_num != field.field_defn_num
+            || field_defn.size_in_bytes as usize != field.data.size_in_bytes()
+            || !(field_defn.data_type == Some(field.data.data_type()) || is_string_bytes(field_defn.data_type, &field.data)) {
+            return mismatch(format!("field {}", field.field_defn_num));
+        }
+    }
+    for (field_defn, field) in defn.dev_field_defns.iter().zip(&mesg.dev_fields) {
+        if field_defn.field_defn_num != field.field_defn_num
+            || field_defn.size_in_bytes as usize != field.data.size_in_bytes() {
+            return mismatch(format!("developer field {}", field.field_defn_num));
+        }
+    }
+    Ok(())
+}
 
-        let prev_time_stamp = context.timestamp;
-        let new_timestamp = mesg.timestamp.unwrap();
-        assert!((prev_time_stamp & 0xFFFFFFE0) < new_timestamp);
+/// Strings that are not UTF-8 are read as bytes.
+fn is_string_bytes(data_type: Option<FitDataType>, data: &FitFieldData) -> bool {
+    data_type == Some(FitDataType::FitString) && matches!(data, FitFieldData::FitByte(_))
+}
 
-        if (new_timestamp - prev_time_stamp) > 0x1f {
-            warn!("Warning: compressed timestamp overflow");
+/// Check that developer fields have been described by a field description message, and that
+/// the values have the type of the description. Fields that could not be decoded when read are
+/// bytes without a description, and are written as they are.
+fn check_dev_fields(context: &FitFileContext, defn: &FitDefinitionMessage, mesg: &FitDataMessage) -> FitResult<()> {
+    for (field_defn, field) in defn.dev_field_defns.iter().zip(&mesg.dev_fields) {
+        let desc = context.developer_ids.get(&field_defn.dev_data_index)
+            .and_then(|x| x.developer_field_definitions.get(&field_defn.field_defn_num))
+            .filter(|x| x.base_type.is_some());
+        let is_valid = match desc {
+            Some(desc) => desc.base_type == Some(field.data.data_type()) || is_string_bytes(desc.base_type, &field.data),
+            None => field.description.is_none() && matches!(field.data, FitFieldData::FitByte(_)),
+        };
+        if !is_valid {
+            return Err(FitErrorKind::DefinitionMismatch(
+                format!("developer field {} of developer {}", field_defn.field_defn_num, field_defn.dev_data_index)).into());
         }
-        let time_offset = (new_timestamp & 0x1F) as u8;
+    }
+    Ok(())
+}
+
+pub fn write_data_message( context: &mut FitFileContext, writer: &mut dyn Write, mesg: &FitDataMessage)
+                       -> FitResult<()>
+{
+    let record_hdr = match mesg.timestamp {
+        Some(new_timestamp) => {
+            if mesg.local_message_type > 0x03 {
+                return Err(FitErrorKind::CompressedTimestamp("local message type is more than 3").into());
+            }
+            // The offset is from the previous timestamp, which must be at most 31 s earlier.
+            let prev_time_stamp = context.timestamp;
+            if new_timestamp < prev_time_stamp || new_timestamp - prev_time_stamp > 0x1f {
+                return Err(FitErrorKind::CompressedTimestamp("more than 31 s from the previous timestamp").into());
+            }
+            let time_offset = (new_timestamp & 0x1F) as u8;
+
+            0x80u8 | ((mesg.local_message_type & 0x3 ) << 5) | time_offset
+        },
+        None => {
+            assert!(mesg.local_message_type <= 0x0F);
+            mesg.local_message_type
+        },
+    };
 
-        0x80u8 | ((mesg.local_message_type & 0x3 ) << 5) | time_offset
-    }else {
-        assert!(mesg.local_message_type <= 0x0F);
-        mesg.local_message_type
+    let defn = match context.field_definitions.get(&mesg.local_message_type) {
+        Some(x) => x.clone(),
+        None => return Err(FitErrorKind::UndefinedLocalMessage(mesg.local_message_type).into()),
     };
+    check_definition(&defn, mesg)?;
+    check_dev_fields(context, &defn, mesg)?;
+    context.architecture = Some(defn.architecture);
 
     fit_write_u8(context, writer, record_hdr)?;  // Write header byte
 
-    let defn = context.field_definitions.get(&mesg.local_message_type);
-    match defn {
-        None => {debug!("Using defaults");},
-        Some(x) => {context.architecture = Some(x.architecture);},
-    }
     for field in &mesg.fields {
         write_fit_field(context, writer, &field.data)?;
 
@@ -246,6 +659,133 @@ pub fn write_data_message( context: &mut FitFileContext, writer: &mut dyn Write,
     for field in &mesg.dev_fields {
         write_fit_field(context, writer, &field.data)?;
     }
+    if let Some(x) = mesg.timestamp {
+        context.timestamp = x;
+    }
+
+    // Developer fields written later are checked against the description.
+    if mesg.global_message_number == DEVELOPER_DATA_ID {
+        add_developer_data_id( context, mesg );
+    }
+    if mesg.global_message_number == FIELD_DESCRIPTION {
+        // Fields of a description without a usable base type are written as the bytes they were read as.
+        let _ = add_dev_field_description( context, mesg );
+    }
     Ok( () )
 }
 
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::fittypes::{Endianness, FitDefinitionMessage, FitFieldDefinition, FitRecord};
+    use crate::fitdefnmesg::write_definition_message;
+    use crate::fitrecord::read_record;
+    use crate::profile::build_profile;
+
+    fn record_definition(local_message_type: u8, field_defn_num: u8, size_in_bytes: u8, data_type: FitDataType)
+        -> FitDefinitionMessage
+    {
+        FitDefinitionMessage {
+            architecture: Endianness::Little,
+            global_message_number: 20,
+            local_message_type,
+            field_defns: vec![
+                Arc::new(FitFieldDefinition{ field_defn_num, size_in_bytes, data_type: Some(data_type), raw_base_type: None }),
+            ],
+            dev_field_defns: vec![],
+            ..Default::default()
+        }
+    }
+
+    fn record_message(local_message_type: u8, field_defn_num: u8, data: FitFieldData) -> FitDataMessage
+    {
+        FitDataMessage {
+            global_message_number: 20,
+            local_message_type,
+            fields: vec![FitDataField{ field_defn_num, data }],
+            ..Default::default()
+        }
+    }
+
+    fn compressed_speed_distance(speed: u32, distance: u32) -> FitFieldData {
+        let packed: u32 = speed | (distance << 12);
+        FitFieldData::FitByte(packed.to_le_bytes()[0..3].into())
+    }
+
+    /// Write the definitions and messages, then decode them again with the profile.
+    fn write_and_read(defns: &[FitDefinitionMessage], mesgs: &[FitDataMessage])
+        -> FitResult<Vec<FitDataMessage>>
+    {
+        let mut context: FitFileContext = Default::default();
+        let mut buf: Vec<u8> = Vec::new();
+        for defn in defns {
+            write_definition_message(&mut context, &mut buf, defn)?;
+        }
+        for mesg in mesgs {
+            write_data_message(&mut context, &mut buf, mesg)?;
+        }
+
+        let mut read_context = FitFileContext {
+            profile: Some(Arc::new(build_profile().unwrap())),
+            ..Default::default()
+        };
+        let mut reader = buf.as_slice();
+        let mut result = Vec::new();
+        while !reader.is_empty() {
+            if let FitRecord::DataRecord(x) = read_record(&mut read_context, &mut reader)? {
+                result.push(x);
+            }
+        }
+        Ok(result)
+    }
+
+    #[test]
+    fn test_expand_compressed_speed_distance() -> FitResult<()> {
+        // speed = 5.0 m/s (12 bits, scale 100), distance = 100 m (12 bits, scale 16)
+        let defn = record_definition(0, 8, 3, FitDataType::FitByte);
+        let mesg = record_message(0, 8, compressed_speed_distance(500, 1600));
+
+        let result = write_and_read(&[defn], &[mesg])?;
+        let x = &result[0];
+        assert_eq!(3, x.expanded_fields.len());
+        assert_eq!(6, x.expanded_fields[0].field_defn_num);  // speed, scale 1000
+        assert!(matches!(&x.expanded_fields[0].data, FitFieldData::FitUint16(v) if v[..] == [5000]));
+        assert_eq!(5, x.expanded_fields[1].field_defn_num);  // distance, scale 100
+        assert!(matches!(&x.expanded_fields[1].data, FitFieldData::FitUint32(v) if v[..] == [10000]));
+        // The speed is itself expanded into enhanced_speed.
+        assert_eq!(73, x.expanded_fields[2].field_defn_num);
+        Ok(())
+    }
+
+    #[test]
+    fn test_accumulator() {
+        let mut acc: FitAccumulator = Default::default();
+        assert_eq!(10, acc.accumulate(10, 8));
+        assert_eq!(250, acc.accumulate(250, 8));
+        assert_eq!(260, acc.accumulate(4, 8));  // Rolled over.
+        acc.set(1000);
+        assert_eq!(1001, acc.accumulate(1001 & 0xFF, 8));
+    }
+
+    #[test]
+    fn test_accumulate_distance() -> FitResult<()> {
+        let defns = [record_definition(0, 5, 4, FitDataType::FitUint32),
+            record_definition(1, 8, 3, FitDataType::FitByte)];
+        let mesgs = [
+            record_message(0, 5, FitFieldData::FitUint32(smallvec![4000])),  // 40 m in full.
+            record_message(1, 8, compressed_speed_distance(0, 4000)),  // 250 m
+            record_message(1, 8, compressed_speed_distance(0, 100)),  // Rolled over, 262.25 m
+        ];
+        let result = write_and_read(&defns, &mesgs)?;
+        assert_eq!(3, result.len());
+
+        let distance = |x: &FitDataMessage| x.expanded_fields.iter()
+            .find(|f| f.field_defn_num == 5)
+            .and_then(|f| f.data.first_integer());
+        assert_eq!(Some(25000), distance(&result[1]));
+        assert_eq!(Some(26225), distance(&result[2]));
+        Ok(())
+    }
+}
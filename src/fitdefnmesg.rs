@@ -8,31 +8,45 @@ use crate::fittypes::{ Endianness, FitFileContext,
                                    FitDefinitionMessage,
                                    FitDataType};
 
+use crate::fiterror::{FitErrorKind, FitResult};
 use crate::fitread::{fit_read_u8, fit_read_u16};
 use crate::fitwrite::{fit_write_u8, fit_write_u16};
 
 
 fn read_field_defn( context: &mut FitFileContext, reader: &mut dyn Read)
-                    -> Result< Arc<FitFieldDefinition>, std::io::Error> {
+                    -> FitResult<Arc<FitFieldDefinition>> {
     let field_defn_num = fit_read_u8(context, reader)?;
     if field_defn_num == 0xFF {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Invalid field: defn_num=255"));
+        return Err(FitErrorKind::InvalidFieldDefinition("defn_num=255").into());
     }
     let size_in_bytes = fit_read_u8(context, reader)?;
     if size_in_bytes == 0x0 {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Invalid field: size=0"));
+        return Err(FitErrorKind::InvalidFieldDefinition("size=0").into());
     }
     let base_type = fit_read_u8(context, reader)?;
 
-    let mut field_defn: FitFieldDefinition = Default::default();
+    let mut field_defn = FitFieldDefinition{ raw_base_type: Some(base_type), ..Default::default() };
 
     let base_type_num = base_type & 0x1F;
     //let base_type_is_endian = base_type & 0x80;
 
-    field_defn.data_type = Some(FitDataType::from_type_id(base_type_num)?);
-    if field_defn.data_type.is_none() {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Invalid field: data type>16"));
-    }
+    field_defn.data_type = match FitDataType::from_type_id(base_type_num) {
+        Ok(x) if x.fits_size(size_in_bytes) => Some(x),
+        result => {
+            let (kind, is_error) = match result {
+                Ok(_) => (FitErrorKind::InvalidFieldDefinition("size is not a multiple of the base type"),
+                          context.checks.field_sizes),
+                Err(_) => (FitErrorKind::InvalidBaseType(base_type), context.checks.valid_base_types),
+            };
+            if is_error {
+                return Err(kind.into());
+            }
+            // Keep the bytes, so that the field can be written as it was.
+            warn!("Field {} is read as bytes: {}", field_defn_num, kind);
+            context.warnings.push(kind.into());
+            Some(FitDataType::FitByte)
+        },
+    };
     field_defn.size_in_bytes = size_in_bytes;
     field_defn.field_defn_num = field_defn_num;
 
@@ -40,11 +54,19 @@ fn read_field_defn( context: &mut FitFileContext, reader: &mut dyn Read)
 }
 
 fn write_field_defn( context: &mut FitFileContext, writer: &mut dyn Write, field_defn: &FitFieldDefinition )
-                     -> Result< (), std::io::Error>
+                     -> FitResult<()>
 {
-    let base_type_num = (field_defn.data_type.unwrap()).type_id();
-    let base_type_is_endian = field_defn.data_type.unwrap().data_size() > 1;
-    let base_type = base_type_num | ( if base_type_is_endian {0x80} else {0x00} );
+    let data_type = field_defn.data_type.unwrap();
+    let base_type_num = data_type.type_id();
+    let base_type_is_endian = data_type.data_size() > 1;
+    // The byte as read is kept, unless the type has been changed since. A field read as bytes,
+    // because its base type was invalid, keeps the byte it had.
+    let read_as_bytes = |raw: u8| data_type == FitDataType::FitByte
+        && !FitDataType::from_type_id(raw & 0x1F).is_ok_and(|x| x.fits_size(field_defn.size_in_bytes));
+    let base_type = match field_defn.raw_base_type {
+        Some(raw) if raw & 0x1F == base_type_num || read_as_bytes(raw) => raw,
+        _ => base_type_num | ( if base_type_is_endian {0x80} else {0x00} ),
+    };
 
     fit_write_u8(context, writer, field_defn.field_defn_num)?;
     fit_write_u8(context, writer, field_defn.size_in_bytes)?;
@@ -55,7 +77,7 @@ fn write_field_defn( context: &mut FitFileContext, writer: &mut dyn Write, field
 
 
 fn read_dev_field_defn( context: &mut FitFileContext, reader: &mut dyn Read)
-                        -> Result< Arc<FitDeveloperFieldDefinition>, std::io::Error> {
+                        -> FitResult<Arc<FitDeveloperFieldDefinition>> {
     let field_defn_num = fit_read_u8(context, reader)?;
     let size_in_bytes = fit_read_u8(context, reader)?;
     let dev_data_index = fit_read_u8(context, reader)?;
@@ -68,7 +90,7 @@ fn read_dev_field_defn( context: &mut FitFileContext, reader: &mut dyn Read)
 }
 
 fn write_dev_field_defn( context: &mut FitFileContext, writer: &mut dyn Write, field_defn: &FitDeveloperFieldDefinition )
-                         -> Result< (), std::io::Error>
+                         -> FitResult<()>
 {
     fit_write_u8(context, writer, field_defn.field_defn_num)?;
     fit_write_u8(context, writer, field_defn.size_in_bytes)?;
@@ -78,8 +100,8 @@ fn write_dev_field_defn( context: &mut FitFileContext, writer: &mut dyn Write, f
 
 pub fn read_definition_message( context: &mut FitFileContext, reader: &mut dyn Read,
                             local_message_type: u8, is_developer: bool)
-                            -> Result< Arc<FitDefinitionMessage>, std::io::Error> {
-    let _reserved0 = fit_read_u8(context, reader)?;  // Read and discard a reserved byte
+                            -> FitResult<Arc<FitDefinitionMessage>> {
+    let reserved = fit_read_u8(context, reader)?;
 
     let architecture = fit_read_u8(context, reader)?;
     let endian:Endianness = if architecture == 1 { Endianness::Big } else { Endianness::Little };
@@ -93,9 +115,11 @@ pub fn read_definition_message( context: &mut FitFileContext, reader: &mut dyn R
              local_message_type, global_message_number, number_of_fields, context.data_bytes_read);
 
     let mut defn_mesg = FitDefinitionMessage {
+        reserved,
         architecture: endian,
         global_message_number,
         local_message_type,
+        is_developer,
         ..Default::default()
     };
 
@@ -119,9 +143,9 @@ pub fn read_definition_message( context: &mut FitFileContext, reader: &mut dyn R
 }
 
 pub fn write_definition_message( context: &mut FitFileContext, writer: &mut dyn Write, defn_mesg: &FitDefinitionMessage)
-                             -> Result< (), std::io::Error>
+                             -> FitResult<()>
 {
-    let is_developer = !defn_mesg.dev_field_defns.is_empty();
+    let is_developer = defn_mesg.is_developer || !defn_mesg.dev_field_defns.is_empty();
     assert!(defn_mesg.local_message_type <= 0x0F);
 
     let record_hdr = defn_mesg.local_message_type |
@@ -129,7 +153,7 @@ pub fn write_definition_message( context: &mut FitFileContext, writer: &mut dyn
         0x40; // Definition message
 
     fit_write_u8(context, writer, record_hdr)?;  // Write header byte
-    fit_write_u8(context, writer, 0u8)?;  // Write a reserved byte
+    fit_write_u8(context, writer, defn_mesg.reserved)?;
 
     match defn_mesg.architecture {
         Endianness::Big => fit_write_u8(context, writer, 1u8)?,
@@ -0,0 +1,408 @@
+// Developer fields: data added to messages by applications, e.g. Connect IQ data fields.
+//
+// An application is identified by a developer_data_id message (207), and each of its fields is
+// described by a field_description message (206). These messages must be written before any
+// message that uses the fields.
+//
+// let mut developer_data = FitDeveloperData::new();
+// let app = developer_data.register_app(FitDeveloperApp{ application_id, application_version: 1, ..Default::default() })?;
+// developer_data.add_field(app, FitDevDataDescription{ field_name: "power".to_string(), base_type: Some(FitDataType::FitUint16), ..Default::default() })?;
+// for mesg in developer_data.messages() { writer.write_message(mesg)?; }
+// developer_data.set_value(&mut record, "power", 250.0)?;
+
+use std::sync::Arc;
+
+use crate::fitconvert::{FitConversion, physical_to_field_data};
+use crate::fitenums;
+use crate::fiterror::{FitErrorKind, FitResult};
+use crate::fitmessages::{DeveloperDataId, FieldDescription};
+use crate::fittypes::{FitDataMessage, FitDataType, FitDevDataDescription, FitDevDataField, FitFieldData, FitUuid};
+
+/// An application that adds developer fields.
+#[derive(Clone, Debug, Default)]
+pub struct FitDeveloperApp {
+    pub application_id: FitUuid,
+    pub application_version: u32,
+    pub developer_id: Option<FitUuid>,
+    pub manufacturer_id: Option<u16>,
+}
+
+struct RegisteredApp {
+    app: FitDeveloperApp,
+    fields: Vec<Arc<FitDevDataDescription>>,
+}
+
+/// The developer applications of a file, and their fields.
+#[derive(Default)]
+pub struct FitDeveloperData {
+    apps: Vec<RegisteredApp>,  // Indexed by developer data index.
+}
+
+impl FitDeveloperData {
+    pub fn new() -> FitDeveloperData {
+        Default::default()
+    }
+
+    /// Register an application, returning its developer data index.
+    pub fn register_app(&mut self, app: FitDeveloperApp) -> FitResult<u8> {
+        if self.apps.len() >= 255 {
+            return Err(FitErrorKind::InvalidFieldDefinition("too many developer applications").into());
+        }
+        if self.apps.iter().any(|x| x.app.application_id == app.application_id) {
+            return Err(FitErrorKind::InvalidFieldDefinition("application is already registered").into());
+        }
+        self.apps.push(RegisteredApp{ app, fields: Vec::new() });
+        Ok((self.apps.len() - 1) as u8)
+    }
+
+    /// Add a field of an application. The field number and developer data index are assigned,
+    /// the name, base type, units, scale, offset, array and native field are taken from the description.
+    pub fn add_field(&mut self, dev_data_index: u8, mut field: FitDevDataDescription)
+        -> FitResult<Arc<FitDevDataDescription>>
+    {
+        if field.field_name.is_empty() || self.field(&field.field_name).is_some() {
+            return Err(FitErrorKind::InvalidFieldDefinition("developer field names must be unique").into());
+        }
+        if field.base_type.is_none() {
+            return Err(FitErrorKind::InvalidFieldDefinition("developer field has no base type").into());
+        }
+        // The field description message holds the scale as uint8 and the offset as sint8.
+        if matches!(field.scale, Some(x) if x.fract() != 0.0 || !(1.0..=254.0).contains(&x)) {
+            return Err(FitErrorKind::InvalidFieldDefinition("scale must be a whole number from 1 to 254").into());
+        }
+        if matches!(field.offset, Some(x) if x.fract() != 0.0 || !(-128.0..=126.0).contains(&x)) {
+            return Err(FitErrorKind::InvalidFieldDefinition("offset must be a whole number from -128 to 126").into());
+        }
+        if field.native_field_num.is_some() != field.native_mesg_num.is_some() {
+            return Err(FitErrorKind::InvalidFieldDefinition("native field needs a message and field number").into());
+        }
+        let app = match self.apps.get_mut(dev_data_index as usize) {
+            Some(x) => x,
+            None => return Err(FitErrorKind::InvalidFieldDefinition("unknown developer data index").into()),
+        };
+        if app.fields.len() >= 255 {
+            return Err(FitErrorKind::InvalidFieldDefinition("too many developer fields").into());
+        }
+
+        field.dev_data_index = dev_data_index;
+        field.field_defn_num = app.fields.len() as u8;
+        field.application_id = Some(app.app.application_id);
+        field.application_version = Some(app.app.application_version);
+        let field = Arc::new(field);
+        app.fields.push(field.clone());
+        Ok(field)
+    }
+
+    /// The description of the field with the given name.
+    pub fn field(&self, name: &str) -> Option<&Arc<FitDevDataDescription>> {
+        self.apps.iter().flat_map(|x| x.fields.iter()).find(|x| x.field_name == name)
+    }
+
+    /// The developer_data_id and field_description messages, to write before the fields are used.
+    pub fn messages(&self) -> Vec<FitDataMessage> {
+        let mut mesgs = Vec::new();
+        for (i, app) in self.apps.iter().enumerate() {
+            mesgs.push(FitDataMessage::from(&DeveloperDataId {
+                developer_id: app.app.developer_id.map(|x| x.0.to_vec()),
+                application_id: Some(app.app.application_id.0.to_vec()),
+                manufacturer_id: app.app.manufacturer_id.map(fitenums::Manufacturer::from),
+                developer_data_index: Some(i as u8),
+                application_version: Some(app.app.application_version),
+            }));
+            for field in &app.fields {
+                mesgs.push(FitDataMessage::from(&field_description(field)));
+            }
+        }
+        mesgs
+    }
+
+    /// The value of a field, given the physical values.
+    pub fn value(&self, name: &str, values: &[f64]) -> FitResult<FitDevDataField> {
+        let desc = self.known_field(name)?;
+        let data_type = desc.base_type.unwrap();
+        if matches!(data_type, FitDataType::FitString) {
+            return Err(FitErrorKind::InvalidValue(format!("{} is a string", name)).into());
+        }
+        if values.is_empty() {
+            return Err(FitErrorKind::InvalidValue(format!("no values for {}", name)).into());
+        }
+        if values.len() > 1 && desc.is_array != Some(true) {
+            return Err(FitErrorKind::InvalidValue(format!("{} is not an array", name)).into());
+        }
+        // A value that does not fit the type would be stored as some other value, or as invalid.
+        let conversion = FitConversion::from_dev_field(desc);
+        for value in values {
+            let raw = conversion.to_raw(*value).round();
+            let is_valid = match data_type.valid_range() {
+                Some(range) => range.contains(&raw),
+                None => !raw.is_nan(),
+            };
+            if !is_valid {
+                return Err(FitErrorKind::InvalidValue(
+                    format!("{} is out of range for {} of type {}", value, name, data_type.name())).into());
+            }
+        }
+        let values: Vec<Option<f64>> = values.iter().map(|x| Some(*x)).collect();
+        let data = physical_to_field_data(&conversion, data_type, &values);
+        Ok(FitDevDataField{ field_defn_num: desc.field_defn_num, data, description: Some(desc.clone()) })
+    }
+
+    /// The value of a string field.
+    pub fn string_value(&self, name: &str, value: &str) -> FitResult<FitDevDataField> {
+        let desc = self.known_field(name)?;
+        if desc.base_type != Some(FitDataType::FitString) {
+            return Err(FitErrorKind::InvalidValue(format!("{} is not a string", name)).into());
+        }
+        let data = FitFieldData::from_string(value);
+        Ok(FitDevDataField{ field_defn_num: desc.field_defn_num, data, description: Some(desc.clone()) })
+    }
+
+    /// Add a value to a message, replacing any previous value of the field.
+    pub fn set_value(&self, mesg: &mut FitDataMessage, name: &str, value: f64) -> FitResult<()> {
+        let field = self.value(name, &[value])?;
+        set_dev_field(mesg, field);
+        Ok(())
+    }
+
+    /// Add the values of an array field to a message, replacing any previous value of the field.
+    pub fn set_values(&self, mesg: &mut FitDataMessage, name: &str, values: &[f64]) -> FitResult<()> {
+        let field = self.value(name, values)?;
+        set_dev_field(mesg, field);
+        Ok(())
+    }
+
+    /// Add a string value to a message, replacing any previous value of the field.
+    pub fn set_string_value(&self, mesg: &mut FitDataMessage, name: &str, value: &str) -> FitResult<()> {
+        let field = self.string_value(name, value)?;
+        set_dev_field(mesg, field);
+        Ok(())
+    }
+
+    fn known_field(&self, name: &str) -> FitResult<&Arc<FitDevDataDescription>> {
+        match self.field(name) {
+            Some(x) => Ok(x),
+            None => Err(FitErrorKind::InvalidValue(format!("no developer field named {}", name)).into()),
+        }
+    }
+}
+
+fn set_dev_field(mesg: &mut FitDataMessage, field: FitDevDataField) {
+    let index = field.description.as_ref().unwrap().dev_data_index;
+    mesg.dev_fields.retain(|x| !(x.field_defn_num == field.field_defn_num
+        && matches!(&x.description, Some(d) if d.dev_data_index == index)));
+    mesg.dev_fields.push(field);
+}
+
+fn field_description(field: &FitDevDataDescription) -> FieldDescription {
+    let data_type = field.base_type.unwrap();
+    let base_type_is_endian = data_type.data_size() > 1;
+    let base_type = data_type.type_id() | if base_type_is_endian { 0x80 } else { 0x00 };
+    FieldDescription {
+        developer_data_index: Some(field.dev_data_index),
+        field_definition_number: Some(field.field_defn_num),
+        fit_base_type_id: Some(fitenums::FitBaseType::from(base_type)),
+        field_name: Some(field.field_name.clone()),
+        array: field.is_array.filter(|x| *x).map(|_| 1),
+        components: field.components.clone(),
+        scale: field.scale.map(|x| x as u8),
+        offset: field.offset.map(|x| x as i8),
+        units: field.units.clone(),
+        bits: field.bits.clone(),
+        accumulate: field.accumulate.clone(),
+        fit_base_unit_id: field.fit_base_unit_id.map(fitenums::FitBaseUnit::from),
+        native_mesg_num: field.native_mesg_num.map(fitenums::MesgNum::from),
+        native_field_num: field.native_field_num,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::fitconvert::field_data_to_physical;
+    use crate::fitfile::{FitReader, FitWriter};
+    use crate::fiterror::FitResult;
+    use crate::fittypes::{FitFileHeader, FitRecord};
+    use crate::fitmessages::Record;
+    use std::convert::TryFrom;
+
+    fn developer_data() -> FitDeveloperData {
+        let mut developer_data = FitDeveloperData::new();
+        let app = developer_data.register_app(FitDeveloperApp{
+            application_id: "12345678-9abc-def0-1234-56789abcdef0".parse().unwrap(),
+            application_version: 2,
+            ..Default::default() }).unwrap();
+        developer_data.add_field(app, FitDevDataDescription{
+            field_name: "power".to_string(),
+            base_type: Some(FitDataType::FitUint16),
+            units: Some("watts".to_string()),
+            native_mesg_num: Some(20),
+            native_field_num: Some(7),
+            ..Default::default()
+        }).unwrap();
+        developer_data.add_field(app, FitDevDataDescription{
+            field_name: "depth".to_string(),
+            base_type: Some(FitDataType::FitSint32),
+            scale: Some(100.0),
+            offset: Some(10.0),
+            ..Default::default()
+        }).unwrap();
+        developer_data.add_field(app, FitDevDataDescription{
+            field_name: "mood".to_string(),
+            base_type: Some(FitDataType::FitString),
+            ..Default::default()
+        }).unwrap();
+        developer_data
+    }
+
+    #[test]
+    fn test_register() {
+        let mut developer_data = developer_data();
+        assert_eq!(1, developer_data.field("depth").unwrap().field_defn_num);
+        assert_eq!(4, developer_data.messages().len());
+
+        let field = |name: &str, scale| FitDevDataDescription{
+            field_name: name.to_string(), base_type: Some(FitDataType::FitUint8), scale, ..Default::default() };
+        assert!(developer_data.add_field(0, field("power", None)).is_err());
+        assert!(developer_data.add_field(1, field("cadence", None)).is_err());
+        assert!(developer_data.add_field(0, field("cadence", Some(0.5))).is_err());
+        assert!(developer_data.add_field(0, field("cadence", Some(2.0))).is_ok());
+        let app_id = developer_data.field("power").unwrap().application_id.unwrap();
+        assert!(developer_data.register_app(FitDeveloperApp{ application_id: app_id, ..Default::default() }).is_err());
+
+        let mut mesg = FitDataMessage::default();
+        assert!(developer_data.set_value(&mut mesg, "speed", 1.0).is_err());
+        assert!(developer_data.set_value(&mut mesg, "mood", 1.0).is_err());
+        developer_data.set_value(&mut mesg, "power", 200.0).unwrap();
+        developer_data.set_value(&mut mesg, "power", 250.0).unwrap();
+        assert_eq!(1, mesg.dev_fields.len());
+        assert!(matches!(&mesg.dev_fields[0].data, FitFieldData::FitUint16(x) if x[..] == [250]));
+    }
+
+    #[test]
+    fn test_write_read() -> FitResult<()> {
+        let developer_data = developer_data();
+        let mut writer = FitWriter::new(Vec::new(), FitFileHeader{ header_size: 14, ..Default::default() });
+        for mesg in developer_data.messages() {
+            writer.write_message(mesg)?;
+        }
+        let mut record = FitDataMessage::from(&Record{ timestamp: Some(1000), ..Default::default() });
+        developer_data.set_value(&mut record, "power", 250.0)?;
+        developer_data.set_value(&mut record, "depth", 1.5)?;
+        developer_data.set_string_value(&mut record, "mood", "happy")?;
+        writer.write_message(record)?;
+        writer.finish()?;
+
+        let records = FitReader::new(writer.target().as_slice()).collect::<FitResult<Vec<FitRecord>>>()?;
+        let mesg = records.iter().find_map(|x| match x {
+            FitRecord::DataRecord(m) if m.global_message_number == 20 => Some(m),
+            _ => None,
+        }).unwrap();
+        assert_eq!(3, mesg.dev_fields.len());
+        let desc = mesg.dev_fields[1].description.as_ref().unwrap();
+        assert_eq!("depth", desc.field_name);
+        assert_eq!(Some(100.0), desc.scale);
+        let depth = field_data_to_physical(&FitConversion::from_dev_field(desc), &mesg.dev_fields[1].data).unwrap();
+        assert!((depth[0].unwrap() - 1.5).abs() < 1e-9);
+        let power = mesg.dev_fields[0].description.as_ref().unwrap();
+        assert_eq!((Some(20), Some(7)), (power.native_mesg_num, power.native_field_num));
+        assert_eq!("12345678-9abc-def0-1234-56789abcdef0", power.application_id.unwrap().to_string());
+        assert_eq!(Some(2), power.application_version);
+        assert!(matches!(&mesg.dev_fields[2].data, FitFieldData::FitString(x, _) if x == "happy"));
+        // The device recorded no power, so the developer field stands in for it.
+        assert_eq!(Some(250), Record::try_from(mesg).unwrap().power);
+        Ok(())
+    }
+
+    #[test]
+    fn test_long_string() -> FitResult<()> {
+        let developer_data = developer_data();
+        let mut writer = FitWriter::new(Vec::new(), FitFileHeader{ header_size: 14, ..Default::default() });
+        for mesg in developer_data.messages() {
+            writer.write_message(mesg)?;
+        }
+        let mut record = FitDataMessage::from(&Record{ timestamp: Some(1000), ..Default::default() });
+        // 300 bytes of two byte characters, cut to 254 bytes so that the terminator fits.
+        developer_data.set_string_value(&mut record, "mood", &"é".repeat(150))?;
+        writer.write_message(record)?;
+        writer.finish()?;
+
+        let records = FitReader::new(writer.target().as_slice()).collect::<FitResult<Vec<FitRecord>>>()?;
+        let mesg = records.iter().find_map(|x| match x {
+            FitRecord::DataRecord(m) if m.global_message_number == 20 => Some(m),
+            _ => None,
+        }).unwrap();
+        assert!(matches!(&mesg.dev_fields[0].data, FitFieldData::FitString(x, 255) if *x == "é".repeat(127)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_native_and_array() -> FitResult<()> {
+        let mut developer_data = developer_data();
+        developer_data.add_field(0, FitDevDataDescription{
+            field_name: "zones".to_string(),
+            base_type: Some(FitDataType::FitUint8),
+            is_array: Some(true),
+            fit_base_unit_id: Some(0),
+            ..Default::default()
+        })?;
+        let mut writer = FitWriter::new(Vec::new(), FitFileHeader{ header_size: 14, ..Default::default() });
+        for mesg in developer_data.messages() {
+            writer.write_message(mesg)?;
+        }
+        let mut record = FitDataMessage::from(&Record{ timestamp: Some(1000), power: Some(180), ..Default::default() });
+        developer_data.set_value(&mut record, "power", 250.0)?;
+        assert!(developer_data.set_values(&mut record, "power", &[1.0, 2.0]).is_err());
+        // Values that do not fit the uint16, or would be its invalid value.
+        for x in [70000.0, 65535.0, -1.0, f64::NAN] {
+            let e = developer_data.set_value(&mut record, "power", x).unwrap_err();
+            assert!(matches!(e.kind, FitErrorKind::InvalidValue(_)));
+        }
+        developer_data.set_value(&mut record, "power", 65534.0)?;
+        developer_data.set_value(&mut record, "power", 250.0)?;
+        developer_data.set_values(&mut record, "zones", &[1.0, 2.0, 3.0])?;
+        writer.write_message(record)?;
+        writer.finish()?;
+
+        let records = FitReader::new(writer.target().as_slice()).collect::<FitResult<Vec<FitRecord>>>()?;
+        let mesg = records.iter().find_map(|x| match x {
+            FitRecord::DataRecord(m) if m.global_message_number == 20 => Some(m),
+            _ => None,
+        }).unwrap();
+        // A value recorded by the device is kept.
+        assert_eq!(Some(180), Record::try_from(mesg).unwrap().power);
+        let zones = &mesg.dev_fields[1];
+        assert_eq!(Some(true), zones.description.as_ref().unwrap().is_array);
+        assert_eq!(Some(0), zones.description.as_ref().unwrap().fit_base_unit_id);
+        assert!(matches!(&zones.data, FitFieldData::FitUint8(x) if x[..] == [1, 2, 3]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_uuid() {
+        let uuid: FitUuid = "12345678-9ABC-def0-1234-56789abcdef0".parse().unwrap();
+        assert_eq!(0x12, uuid.0[0]);
+        assert_eq!(0xf0, uuid.0[15]);
+        assert_eq!("12345678-9abc-def0-1234-56789abcdef0", uuid.to_string());
+        assert!("12345678-9abc-def0-1234-56789abcdef".parse::<FitUuid>().is_err());
+        assert!("12345678-9abc-def0-1234-56789abcdefg".parse::<FitUuid>().is_err());
+        assert!("1234-5678-9abc-def0-123456789abcdef0".parse::<FitUuid>().is_err());
+        assert!("12345678-9abc-def0-1234-56789abcde+f".parse::<FitUuid>().is_err());
+        assert!("+2345678-9abc-def0-1234-56789abcdef0".parse::<FitUuid>().is_err());
+
+        assert_eq!(Some(uuid), FitUuid::from_field_data(&FitFieldData::FitByte(uuid.0[..].into())));
+        assert_eq!(None, FitUuid::from_field_data(&FitFieldData::FitByte(smallvec![0xFF; 16])));
+        assert_eq!(None, FitUuid::from_field_data(&FitFieldData::FitByte(smallvec![1; 15])));
+    }
+
+    #[test]
+    fn test_undescribed() {
+        // Values of fields that have not been described are not written.
+        let developer_data = developer_data();
+        let mut writer = FitWriter::new(Vec::new(), FitFileHeader{ header_size: 14, ..Default::default() });
+        let mut record = FitDataMessage::from(&Record{ timestamp: Some(1000), ..Default::default() });
+        developer_data.set_value(&mut record, "power", 250.0).unwrap();
+        let e = writer.write_message(record).unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::DefinitionMismatch(_)));
+    }
+}
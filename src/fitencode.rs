@@ -0,0 +1,372 @@
+// Encoding data messages without managing definitions by hand.
+//
+// The encoder keeps track of the definition of each of the 16 local message types. A definition
+// message is only emitted when a message has a layout that is not currently defined, in which
+// case the least recently used local message type is redefined.
+//
+// With compressed timestamps enabled, a message with a timestamp at most 31 s after the previous
+// one is written with a compressed timestamp header instead of its timestamp field. These
+// headers can only refer to local message types 0 to 3.
+//
+// let mut encoder = FitEncoder::new();
+// file.records.extend(encoder.encode_typed(&record)?);
+
+use std::sync::Arc;
+
+use crate::fiterror::{FitErrorKind, FitResult};
+use crate::fittypes::{Endianness, FitDataField, FitDataMessage, FitDefinitionMessage, FitDeveloperFieldDefinition,
+                      FitFieldData, FitFieldDefinition, FitRecord};
+
+/// The number of local message types in a normal record header.
+pub const LOCAL_MESSAGE_TYPES: usize = 16;
+
+/// The number of local message types in a compressed timestamp header.
+pub const COMPRESSED_LOCAL_MESSAGE_TYPES: usize = 4;
+
+const TIMESTAMP: u8 = 253;
+
+struct LocalMessage {
+    definition: Arc<FitDefinitionMessage>,
+    last_used: u64,
+}
+
+pub struct FitEncoder {
+    architecture: Endianness,
+    local_messages: Vec<LocalMessage>,  // Indexed by local message type.
+    uses: u64,
+    compressed_timestamps: bool,
+    timestamp: Option<u32>,  // The last timestamp written, compressed timestamps are relative to it.
+}
+
+impl Default for FitEncoder {
+    fn default() -> Self { FitEncoder::new() }
+}
+
+impl FitEncoder {
+    pub fn new() -> FitEncoder {
+        FitEncoder{ architecture: Endianness::Little, local_messages: Vec::new(), uses: 0,
+            compressed_timestamps: false, timestamp: None }
+    }
+
+    /// Definitions are written with the given byte order.
+    pub fn with_architecture(architecture: Endianness) -> FitEncoder {
+        FitEncoder{ architecture, ..FitEncoder::new() }
+    }
+
+    /// Use compressed timestamp headers where possible.
+    pub fn set_compressed_timestamps(&mut self, enabled: bool) {
+        self.compressed_timestamps = enabled;
+    }
+
+    /// The records to write for a message: a definition if the layout of the message is not
+    /// defined, then the data message with its local message type set.
+    pub fn encode(&mut self, mut mesg: FitDataMessage) -> FitResult<Vec<FitRecord>> {
+        // A compressed message that was read has its timestamp outside of the fields.
+        if let Some(x) = mesg.timestamp.take() {
+            if !mesg.fields.iter().any(|f| f.field_defn_num == TIMESTAMP) {
+                mesg.fields.insert(0, FitDataField{ field_defn_num: TIMESTAMP, data: FitFieldData::FitUint32(smallvec![x]) });
+            }
+        }
+
+        let mut local_message_types = LOCAL_MESSAGE_TYPES;
+        match mesg.fields.iter().find(|f| f.field_defn_num == TIMESTAMP).map(|f| &f.data) {
+            Some(FitFieldData::FitUint32(x)) if !x.is_empty() => {
+                let timestamp = x[0];
+                let is_close = matches!(self.timestamp, Some(prev) if timestamp >= prev && timestamp - prev <= 0x1F);
+                if self.compressed_timestamps && is_close && x.len() == 1 && timestamp != 0xFFFFFFFF {
+                    mesg.fields.retain(|f| f.field_defn_num != TIMESTAMP);
+                    mesg.timestamp = Some(timestamp);
+                    local_message_types = COMPRESSED_LOCAL_MESSAGE_TYPES;
+                }
+                self.timestamp = Some(timestamp);
+            },
+            _ => {},
+        }
+
+        let definition = self.definition_for(&mesg)?;
+        self.uses += 1;
+        let mut records = Vec::new();
+
+        let existing = self.local_messages.iter().take(local_message_types)
+            .position(|x| same_layout(&x.definition, &definition));
+        let local_message_type = match existing {
+            Some(i) => i,
+            None => {
+                let i = if self.local_messages.len() < local_message_types {
+                    self.local_messages.len()
+                } else {
+                    self.local_messages.iter().take(local_message_types).enumerate()
+                        .min_by_key(|(_, x)| x.last_used).map(|(i, _)| i).unwrap()
+                };
+                let mut definition = definition;
+                definition.local_message_type = i as u8;
+                let definition = Arc::new(definition);
+                let local_message = LocalMessage{ definition: definition.clone(), last_used: 0 };
+                if i < self.local_messages.len() {
+                    self.local_messages[i] = local_message;
+                } else {
+                    self.local_messages.push(local_message);
+                }
+                records.push(FitRecord::DefinitionMessage(definition));
+                i
+            },
+        };
+        self.local_messages[local_message_type].last_used = self.uses;
+
+        mesg.local_message_type = local_message_type as u8;
+        records.push(FitRecord::DataRecord(mesg));
+        Ok(records)
+    }
+
+    /// Encode a typed message, e.g. fitmessages::Record.
+    pub fn encode_typed<T>(&mut self, mesg: &T) -> FitResult<Vec<FitRecord>>
+        where for<'a> FitDataMessage: From<&'a T>
+    {
+        self.encode(FitDataMessage::from(mesg))
+    }
+
+    /// Forget all definitions, e.g. when starting a new file.
+    pub fn reset(&mut self) {
+        self.local_messages.clear();
+        self.uses = 0;
+        self.timestamp = None;
+    }
+
+    /// The definition describing the fields of the message.
+    fn definition_for(&self, mesg: &FitDataMessage) -> FitResult<FitDefinitionMessage> {
+        if mesg.fields.len() > 255 || mesg.dev_fields.len() > 255 {
+            return Err(FitErrorKind::InvalidFieldDefinition("too many fields").into());
+        }
+        let mut definition = FitDefinitionMessage {
+            architecture: self.architecture,
+            global_message_number: mesg.global_message_number,
+            ..Default::default()
+        };
+        for field in &mesg.fields {
+            definition.field_defns.push(Arc::new(FitFieldDefinition {
+                field_defn_num: field.field_defn_num,
+                size_in_bytes: field_size(field.field_defn_num, field.data.size_in_bytes())?,
+                data_type: Some(field.data.data_type()),
+                raw_base_type: None,
+            }));
+        }
+        for field in &mesg.dev_fields {
+            let dev_data_index = match &field.description {
+                Some(x) => x.dev_data_index,
+                None => return Err(FitErrorKind::InvalidFieldDefinition("developer field without description").into()),
+            };
+            definition.dev_field_defns.push(Arc::new(FitDeveloperFieldDefinition {
+                field_defn_num: field.field_defn_num,
+                size_in_bytes: field_size(field.field_defn_num, field.data.size_in_bytes())?,
+                dev_data_index,
+            }));
+        }
+        Ok(definition)
+    }
+}
+
+fn field_size(field_defn_num: u8, size: usize) -> FitResult<u8> {
+    if field_defn_num == 0xFF {
+        return Err(FitErrorKind::InvalidFieldDefinition("defn_num=255").into());
+    }
+    match size {
+        0 => Err(FitErrorKind::InvalidFieldDefinition("size=0").into()),
+        1..=255 => Ok(size as u8),
+        _ => Err(FitErrorKind::InvalidFieldDefinition("size>255").into()),
+    }
+}
+
+/// True if the definitions are the same, apart from the local message type.
+fn same_layout(a: &FitDefinitionMessage, b: &FitDefinitionMessage) -> bool {
+    a.architecture == b.architecture && a.global_message_number == b.global_message_number
+        && a.field_defns == b.field_defns && a.dev_field_defns == b.dev_field_defns
+}
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::fittypes::FitFileContext;
+    use crate::fitrecord::{read_record, write_record};
+    use crate::fitmessages::Record;
+
+    fn message(global_message_number: u16, data: FitFieldData) -> FitDataMessage {
+        FitDataMessage {
+            global_message_number,
+            fields: vec![FitDataField{ field_defn_num: 0, data }],
+            ..Default::default()
+        }
+    }
+
+    fn local_types(records: &[FitRecord]) -> Vec<(bool, u8)> {
+        records.iter().map(|x| match x {
+            FitRecord::DefinitionMessage(d) => (true, d.local_message_type),
+            FitRecord::DataRecord(d) => (false, d.local_message_type),
+            _ => panic!("Unexpected record"),
+        }).collect()
+    }
+
+    #[test]
+    fn test_definitions() {
+        let mut encoder = FitEncoder::new();
+        let a = encoder.encode(message(20, FitFieldData::FitUint8(smallvec![1]))).unwrap();
+        let b = encoder.encode(message(20, FitFieldData::FitUint8(smallvec![2]))).unwrap();
+        let c = encoder.encode(message(20, FitFieldData::FitUint16(smallvec![3]))).unwrap();
+        let d = encoder.encode(message(21, FitFieldData::FitUint8(smallvec![4]))).unwrap();
+        assert_eq!(vec![(true, 0), (false, 0)], local_types(&a));
+        assert_eq!(vec![(false, 0)], local_types(&b));
+        assert_eq!(vec![(true, 1), (false, 1)], local_types(&c));
+        assert_eq!(vec![(true, 2), (false, 2)], local_types(&d));
+
+        // The records can be written and read back.
+        let mut context = FitFileContext::default();
+        let mut buf = Vec::new();
+        for rec in a.iter().chain(&b).chain(&c).chain(&d) {
+            write_record(&mut context, &mut buf, rec).unwrap();
+        }
+        let mut read_context = FitFileContext::default();
+        let mut reader = buf.as_slice();
+        let mut count = 0;
+        while !reader.is_empty() {
+            read_record(&mut read_context, &mut reader).unwrap();
+            count += 1;
+        }
+        assert_eq!(7, count);
+    }
+
+    #[test]
+    fn test_least_recently_used() {
+        let mut encoder = FitEncoder::new();
+        for i in 0..16 {
+            encoder.encode(message(i, FitFieldData::FitUint8(smallvec![0]))).unwrap();
+        }
+        // Message 0 is used again, so message 1 is the least recently used.
+        assert_eq!(vec![(false, 0)], local_types(&encoder.encode(message(0, FitFieldData::FitUint8(smallvec![0]))).unwrap()));
+        let records = encoder.encode(message(100, FitFieldData::FitUint8(smallvec![0]))).unwrap();
+        assert_eq!(vec![(true, 1), (false, 1)], local_types(&records));
+        let records = encoder.encode(message(1, FitFieldData::FitUint8(smallvec![0]))).unwrap();
+        assert_eq!(vec![(true, 2), (false, 2)], local_types(&records));
+    }
+
+    #[test]
+    fn test_typed() {
+        let mut encoder = FitEncoder::new();
+        let record = Record{ timestamp: Some(1000), heart_rate: Some(150), ..Default::default() };
+        let records = encoder.encode_typed(&record).unwrap();
+        assert_eq!(2, records.len());
+        match &records[0] {
+            FitRecord::DefinitionMessage(d) => {
+                assert_eq!(Record::MESG_NUM, d.global_message_number);
+                assert_eq!(2, d.field_defns.len());
+            },
+            _ => panic!("Expected a definition"),
+        }
+
+        let empty = FitFieldData::FitUint8(smallvec![]);
+        assert!(encoder.encode(message(20, empty)).is_err());
+    }
+
+    fn timestamped(timestamp: u32, heart_rate: u8) -> FitDataMessage {
+        FitDataMessage {
+            global_message_number: 20,
+            fields: vec![
+                FitDataField{ field_defn_num: 253, data: FitFieldData::FitUint32(smallvec![timestamp]) },
+                FitDataField{ field_defn_num: 3, data: FitFieldData::FitUint8(smallvec![heart_rate]) },
+            ],
+            ..Default::default()
+        }
+    }
+
+    fn write_all(encoder: &mut FitEncoder, mesgs: Vec<FitDataMessage>) -> Vec<u8> {
+        let mut context = FitFileContext::default();
+        let mut buf = Vec::new();
+        for mesg in mesgs {
+            for rec in encoder.encode(mesg).unwrap() {
+                write_record(&mut context, &mut buf, &rec).unwrap();
+            }
+        }
+        buf
+    }
+
+    #[test]
+    fn test_compressed_timestamps() {
+        let times = [1000, 1001, 1005, 1040, 1041, 1072, 1104];
+        let mesgs = || times.iter().map(|t| timestamped(*t, 150)).collect::<Vec<_>>();
+        let plain = write_all(&mut FitEncoder::new(), mesgs());
+
+        let mut encoder = FitEncoder::new();
+        encoder.set_compressed_timestamps(true);
+        let records: Vec<FitRecord> = mesgs().into_iter().flat_map(|x| encoder.encode(x).unwrap()).collect();
+        let compressed: Vec<bool> = records.iter().filter_map(|x| match x {
+            FitRecord::DataRecord(d) => Some(d.timestamp.is_some()),
+            _ => None,
+        }).collect();
+        // The first has no previous timestamp, 1040 and 1104 are more than 31 s later.
+        assert_eq!(vec![false, true, true, false, true, true, false], compressed);
+
+        encoder.reset();
+        let buf = write_all(&mut encoder, mesgs());
+        assert!(buf.len() < plain.len());
+
+        // Timestamps are read back from the compressed headers.
+        let mut context = FitFileContext::default();
+        let mut reader = buf.as_slice();
+        let mut read_times = Vec::new();
+        while !reader.is_empty() {
+            if let FitRecord::DataRecord(x) = read_record(&mut context, &mut reader).unwrap() {
+                let timestamp = x.fields.iter().chain(&x.expanded_fields).find(|f| f.field_defn_num == 253).unwrap();
+                read_times.push(timestamp.data.first_integer().unwrap() as u32);
+            }
+        }
+        assert_eq!(times.to_vec(), read_times);
+    }
+
+    #[test]
+    fn test_compressed_timestamp_errors() {
+        let mut context = FitFileContext::default();
+        let mut buf = Vec::new();
+        let mut encoder = FitEncoder::new();
+        for rec in encoder.encode(timestamped(1000, 150)).unwrap() {
+            write_record(&mut context, &mut buf, &rec).unwrap();
+        }
+        let records = encoder.encode(FitDataMessage{
+            global_message_number: 20,
+            fields: vec![FitDataField{ field_defn_num: 3, data: FitFieldData::FitUint8(smallvec![150]) }],
+            ..Default::default()
+        }).unwrap();
+        write_record(&mut context, &mut buf, &records[0]).unwrap();
+        let mut mesg = match records.into_iter().nth(1) {
+            Some(FitRecord::DataRecord(x)) => x,
+            _ => panic!("Expected a data message"),
+        };
+
+        mesg.timestamp = Some(1032);
+        let e = write_record(&mut context, &mut buf, &FitRecord::DataRecord(mesg)).unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::CompressedTimestamp(_)));
+    }
+
+    #[test]
+    fn test_mismatch() {
+        // Writing data that does not match the definition is an error.
+        let mut encoder = FitEncoder::new();
+        let records = encoder.encode(message(20, FitFieldData::FitUint8(smallvec![1]))).unwrap();
+        let mut context = FitFileContext::default();
+        let mut buf = Vec::new();
+        write_record(&mut context, &mut buf, &records[0]).unwrap();
+
+        let mesg = message(21, FitFieldData::FitUint8(smallvec![1]));
+        let e = write_record(&mut context, &mut buf, &FitRecord::DataRecord(mesg)).unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::DefinitionMismatch(_)));
+
+        let mesg = message(20, FitFieldData::FitUint16(smallvec![1]));
+        let e = write_record(&mut context, &mut buf, &FitRecord::DataRecord(mesg)).unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::DefinitionMismatch(_)));
+
+        let mut mesg = message(20, FitFieldData::FitUint8(smallvec![1]));
+        mesg.local_message_type = 1;
+        let e = write_record(&mut context, &mut buf, &FitRecord::DataRecord(mesg)).unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::UndefinedLocalMessage(1)));
+        // Nothing is written for the rejected messages, only the definition.
+        assert_eq!(9, buf.len());
+    }
+}
@@ -0,0 +1,72 @@
+// Enums for the types of the profile, generated from types.json by build.rs.
+//
+// Each enum has a variant per named value and Unknown for values that are not in the profile.
+// Value names are converted to CamelCase, names starting with a digit are prefixed with N
+// (e.g. N3WayCalfRaise) and a value named "unknown" is UnknownValue.
+//
+// let sport = Sport::from(1u8);  // Sport::Running
+// let sport: Sport = "running".parse()?;
+// let raw = u8::from(sport);
+//
+// Bitfield types are flag sets with a constant per flag, e.g. FileFlags::READ | FileFlags::WRITE.
+
+use std::fmt;
+use std::ops;
+use std::str::FromStr;
+
+/// Flags are shown as their names separated by |, followed by any unknown bits.
+fn format_flags(f: &mut fmt::Formatter<'_>, names: &[&str], unknown_bits: u64) -> fmt::Result {
+    let mut parts: Vec<String> = names.iter().map(|x| x.to_string()).collect();
+    if unknown_bits != 0 || parts.is_empty() {
+        parts.push(unknown_bits.to_string());
+    }
+    write!(f, "{}", parts.join("|"))
+}
+
+include!(concat!(env!("OUT_DIR"), "/types.rs"));
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+
+    #[test]
+    fn test_from_raw() {
+        assert_eq!(Sport::Running, Sport::from(1u8));
+        assert_eq!(1u8, u8::from(Sport::Running));
+        assert_eq!(Sport::Unknown(200), Sport::from(200u8));
+        assert_eq!(200u8, u8::from(Sport::Unknown(200)));
+        assert_eq!(Manufacturer::Garmin, Manufacturer::from(1u16));
+        assert_eq!(MesgNum::Record, MesgNum::from(20u16));
+    }
+
+    #[test]
+    fn test_names() {
+        assert_eq!("running", Sport::Running.to_string());
+        assert_eq!("200", Sport::Unknown(200).to_string());
+        assert_eq!(Ok(Sport::Cycling), "cycling".parse::<Sport>());
+        assert_eq!(Ok(Sport::Unknown(200)), "200".parse::<Sport>());
+        assert!("not_a_sport".parse::<Sport>().is_err());
+
+        assert_eq!(Some("3_way_calf_raise"), CalfRaiseExerciseName::N3WayCalfRaise.name());
+        assert_eq!(Some("unknown"), BatteryStatus::UnknownValue.name());
+        assert_eq!(None, BatteryStatus::Unknown(100).name());
+    }
+
+    #[test]
+    fn test_flags() {
+        let flags = FileFlags::READ | FileFlags::WRITE;
+        assert_eq!(6u8, u8::from(flags));
+        assert!(flags.contains(FileFlags::READ));
+        assert!(!flags.contains(FileFlags::ERASE));
+        assert_eq!(vec!["read", "write"], flags.names());
+        assert_eq!("read|write", flags.to_string());
+        assert_eq!("read|16", FileFlags(0x12).to_string());
+        assert_eq!("0", FileFlags(0).to_string());
+
+        assert_eq!(Ok(flags), "read|write".parse::<FileFlags>());
+        assert_eq!(Ok(FileFlags(0x12)), "read|16".parse::<FileFlags>());
+        assert!("read|delete".parse::<FileFlags>().is_err());
+        assert_eq!(SportBits0::RUNNING | SportBits0::CYCLING, SportBits0::from(6u8));
+    }
+}
@@ -0,0 +1,143 @@
+// Errors when reading and writing FIT files.
+
+use std::fmt;
+
+/// What went wrong.
+#[derive(Debug)]
+pub enum FitErrorKind {
+    Io(std::io::Error),
+    Truncated,                                    // The data ended in the middle of a record.
+    InvalidHeader(&'static str),
+    HeaderCrc { expected: u16, computed: u16 },
+    FileCrc { expected: u16, computed: u16 },
+    UndefinedLocalMessage(u8),                    // A data message without a definition.
+    DefinitionMismatch(String),                   // The fields of a data message differ from its definition.
+    CompressedTimestamp(&'static str),            // A compressed timestamp header cannot be written.
+    DataSize { expected: u32, actual: u64 },      // Data written differs from the size in the header.
+    InvalidBaseType(u8),
+    InvalidFieldDefinition(&'static str),
+    ReservedBitSet(u8),                           // The record header byte.
+    EndiannessNotSet,
+    InvalidValue(String),                         // A value failed a check, see fitcheck.
+    Profile(String),
+    IndexMismatch,                                // The data at an indexed offset is not the message indexed.
+}
+
+/// An error, with where in the file it happened if that is known.
+#[derive(Debug)]
+pub struct FitError {
+    pub kind: FitErrorKind,
+    pub offset: Option<u64>,        // Byte offset of the start of the record.
+    pub record_index: Option<u64>,  // The header is record 0.
+}
+
+pub type FitResult<T> = Result<T, FitError>;
+
+impl FitError {
+    pub fn new(kind: FitErrorKind) -> FitError {
+        FitError { kind, offset: None, record_index: None }
+    }
+
+    /// Set the position, unless it is already known.
+    pub fn at(mut self, offset: u64, record_index: u64) -> FitError {
+        self.offset.get_or_insert(offset);
+        self.record_index.get_or_insert(record_index);
+        self
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        matches!(self.kind, FitErrorKind::Truncated)
+    }
+}
+
+impl From<FitErrorKind> for FitError {
+    fn from(kind: FitErrorKind) -> Self {
+        FitError::new(kind)
+    }
+}
+
+impl From<std::io::Error> for FitError {
+    fn from(e: std::io::Error) -> Self {
+        if e.kind() == std::io::ErrorKind::UnexpectedEof {
+            FitError::new(FitErrorKind::Truncated)
+        } else {
+            FitError::new(FitErrorKind::Io(e))
+        }
+    }
+}
+
+/// So that FIT errors can be returned from functions returning io::Result.
+impl From<FitError> for std::io::Error {
+    fn from(e: FitError) -> Self {
+        match e.kind {
+            FitErrorKind::Io(x) => x,
+            FitErrorKind::Truncated => std::io::Error::new(std::io::ErrorKind::UnexpectedEof, e.to_string()),
+            _ => std::io::Error::new(std::io::ErrorKind::InvalidData, e.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for FitErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FitErrorKind::Io(e) => write!(f, "{}", e),
+            FitErrorKind::Truncated => write!(f, "Unexpected end of data"),
+            FitErrorKind::InvalidHeader(x) => write!(f, "Invalid header: {}", x),
+            FitErrorKind::HeaderCrc { expected, computed } =>
+                write!(f, "Header CRC is invalid: expected 0x{:x}, computed 0x{:x}", expected, computed),
+            FitErrorKind::FileCrc { expected, computed } =>
+                write!(f, "Bad CRC: expected 0x{:x}, computed 0x{:x}", expected, computed),
+            FitErrorKind::UndefinedLocalMessage(x) => write!(f, "No definition for local message type {}", x),
+            FitErrorKind::DefinitionMismatch(x) => write!(f, "Data does not match definition: {}", x),
+            FitErrorKind::CompressedTimestamp(x) => write!(f, "Cannot use a compressed timestamp: {}", x),
+            FitErrorKind::DataSize { expected, actual } =>
+                write!(f, "Data size is {} bytes, the header gives {}", actual, expected),
+            FitErrorKind::InvalidBaseType(x) => write!(f, "Invalid FIT data type {}", x),
+            FitErrorKind::InvalidFieldDefinition(x) => write!(f, "Invalid field: {}", x),
+            FitErrorKind::ReservedBitSet(x) => write!(f, "Reserved bit is set in record header 0x{:x}", x),
+            FitErrorKind::EndiannessNotSet => write!(f, "Endianness not set"),
+            FitErrorKind::InvalidValue(x) => write!(f, "{}", x),
+            FitErrorKind::Profile(x) => write!(f, "Profile error: {}", x),
+            FitErrorKind::IndexMismatch => write!(f, "Data does not match the index"),
+        }
+    }
+}
+
+impl fmt::Display for FitError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)?;
+        if let Some(index) = self.record_index {
+            write!(f, " in record {}", index)?;
+        }
+        if let Some(offset) = self.offset {
+            write!(f, " at byte {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for FitError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.kind {
+            FitErrorKind::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_from_io() {
+        let e = FitError::from(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, "eof"));
+        assert!(e.is_truncated());
+        let e = FitError::from(std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied"));
+        assert!(matches!(e.kind, FitErrorKind::Io(_)));
+
+        let e = FitError::new(FitErrorKind::UndefinedLocalMessage(3)).at(100, 5).at(0, 0);
+        assert_eq!("No definition for local message type 3 in record 5 at byte 100", e.to_string());
+        assert_eq!(std::io::ErrorKind::InvalidData, std::io::Error::from(e).kind());
+    }
+}
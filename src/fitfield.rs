@@ -1,140 +1,84 @@
+use std::convert::TryInto;
 use std::io::{Read, Write};
 
-use crate::fittypes::{FitDataType, FitFieldData, FitFileContext};
-use crate::fitread::{fit_read_i8, fit_read_u8, fit_read_u16, fit_read_i16, fit_read_i32,
-                     fit_read_u32, fit_read_string, fit_read_f32, fit_read_f64,
-                     fit_read_i64, fit_read_u64};
+use smallvec::{Array, SmallVec};
+
+use crate::fiterror::{FitErrorKind, FitResult};
+use crate::fittypes::{Endianness, FitDataType, FitFieldData, FitFileContext};
+use crate::fitread::fit_read_bytes;
 use crate::fitwrite::{fit_write_u8, fit_write_u16, fit_write_i8, fit_write_i16,
                       fit_write_i32, fit_write_u32, fit_write_string, fit_write_f32,
                       fit_write_f64, fit_write_u64, fit_write_i64};
 
+/// Read count values of a field, or a string of count bytes.
 pub fn read_fit_field( context: &mut FitFileContext, reader: &mut dyn Read,
                    data_type: FitDataType, count: u8)
-                   -> Result< FitFieldData, std::io::Error >
+                   -> FitResult<FitFieldData>
 {
-    //reader.read_u16_into::<NativeEndian>(&mut buffer[..])?;
-    match data_type {
-        FitDataType::FitEnum => {
-            let mut v: Vec<u8> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_u8(context, reader)?);
-            }
-            Ok(FitFieldData::FitEnum(v))
-        },
-        FitDataType::FitSint8 => {
-            let mut v: Vec<i8> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_i8(context, reader)?);
-            }
-            Ok(FitFieldData::FitSint8(v))
-        },
-        FitDataType::FitUint8 => {
-            let mut v: Vec<u8> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_u8(context, reader)?);
-            }
-            Ok(FitFieldData::FitUint8(v))
-        },
-        FitDataType::FitSint16 => {
-            let mut v: Vec<i16> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_i16(context, reader)?);
-            }
-            Ok(FitFieldData::FitSint16(v))
-        },
-        FitDataType::FitUint16 => {
-            let mut v: Vec<u16> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_u16(context, reader)?);
-            }
-            Ok(FitFieldData::FitUint16(v))
-        },
-        FitDataType::FitSint32 => {
-            let mut v: Vec<i32> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_i32(context, reader)?);
-            }
-            Ok(FitFieldData::FitSint32(v))
-        },
-        FitDataType::FitUint32 => {
-            let mut v: Vec<u32> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_u32(context, reader)?);
-            }
-            Ok(FitFieldData::FitUint32(v))
-        },
+    let len = match data_type.data_size() {
+        0 => count as usize,
+        x => count as usize * x as usize,
+    };
+    let data = fit_read_bytes(context, reader, len)?;
+    decode_fit_field(context.architecture, data_type, &data)
+}
+
+/// Decode the values of a field from its bytes. Bytes after the last whole value are ignored.
+pub fn decode_fit_field(architecture: Option<Endianness>, data_type: FitDataType, data: &[u8])
+                        -> FitResult<FitFieldData>
+{
+    let endianness = match architecture {
+        Some(x) => x,
+        None if data_type.data_size() <= 1 => Endianness::Little,
+        None => return Err(FitErrorKind::EndiannessNotSet.into()),
+    };
+    Ok(match data_type {
+        FitDataType::FitEnum => FitFieldData::FitEnum(SmallVec::from_slice(data)),
+        FitDataType::FitSint8 => FitFieldData::FitSint8(data.iter().map(|x| *x as i8).collect()),
+        FitDataType::FitUint8 => FitFieldData::FitUint8(SmallVec::from_slice(data)),
+        FitDataType::FitSint16 => FitFieldData::FitSint16(decode(data, endianness, i16::from_le_bytes, i16::from_be_bytes)),
+        FitDataType::FitUint16 => FitFieldData::FitUint16(decode(data, endianness, u16::from_le_bytes, u16::from_be_bytes)),
+        FitDataType::FitSint32 => FitFieldData::FitSint32(decode(data, endianness, i32::from_le_bytes, i32::from_be_bytes)),
+        FitDataType::FitUint32 => FitFieldData::FitUint32(decode(data, endianness, u32::from_le_bytes, u32::from_be_bytes)),
         FitDataType::FitString => {
-            let v = fit_read_string(context, reader, &count)?;
-            Ok(FitFieldData::FitString(v,count))
-        },
-        FitDataType::FitF32 => {
-            let mut v: Vec<f32> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_f32(context, reader)?);
-            }
-            Ok(FitFieldData::FitF32(v))
-        },
-        FitDataType::FitF64 => {
-            let mut v: Vec<f64> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_f64(context, reader)?);
-            }
-            Ok(FitFieldData::FitF64(v))
-        },
-        FitDataType::FitU8z => {
-            let mut v: Vec<u8> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_u8(context, reader)?);
-            }
-            Ok(FitFieldData::FitU8z(v))
-        },
-        FitDataType::FitU16z => {
-            let mut v: Vec<u16> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_u16(context, reader)?);
-            }
-            Ok(FitFieldData::FitU16z(v))
-        },
-        FitDataType::FitU32z => {
-            let mut v: Vec<u32> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_u32(context, reader)?);
-            }
-            Ok(FitFieldData::FitU32z(v))
-        },
-        FitDataType::FitByte => {
-            let mut v: Vec<u8> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_u8(context, reader)?);
-            }
-            Ok(FitFieldData::FitByte(v))
-        },
-        FitDataType::FitSInt64 => {
-            let mut v: Vec<i64> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_i64(context, reader)?);
-            }
-            Ok(FitFieldData::FitSInt64(v))
-        },
-        FitDataType::FitUint64 => {
-            let mut v: Vec<u64> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_u64(context, reader)?);
-            }
-            Ok(FitFieldData::FitUint64(v))
-        },
-        FitDataType::FitUint64z => {
-            let mut v: Vec<u64> = Vec::new();
-            for _i in 0..count {
-                v.push(fit_read_u64(context, reader)?);
-            }
-            Ok(FitFieldData::FitUint64z(v))
-        },
+            // Drop the null padding. Text that is not UTF-8 is kept as bytes, so that it can
+            // be written as it was.
+            let len = data.iter().rposition(|x| *x != 0).map_or(0, |x| x + 1);
+            match std::str::from_utf8(&data[..len]) {
+                Ok(s) => FitFieldData::FitString(s.to_string(), data.len() as u8),
+                Err(_) => {
+                    warn!("String is not UTF-8, read as bytes");
+                    FitFieldData::FitByte(SmallVec::from_slice(data))
+                },
+            }
+        },
+        FitDataType::FitF32 => FitFieldData::FitF32(decode(data, endianness, f32::from_le_bytes, f32::from_be_bytes)),
+        FitDataType::FitF64 => FitFieldData::FitF64(decode(data, endianness, f64::from_le_bytes, f64::from_be_bytes)),
+        FitDataType::FitU8z => FitFieldData::FitU8z(SmallVec::from_slice(data)),
+        FitDataType::FitU16z => FitFieldData::FitU16z(decode(data, endianness, u16::from_le_bytes, u16::from_be_bytes)),
+        FitDataType::FitU32z => FitFieldData::FitU32z(decode(data, endianness, u32::from_le_bytes, u32::from_be_bytes)),
+        FitDataType::FitByte => FitFieldData::FitByte(SmallVec::from_slice(data)),
+        FitDataType::FitSInt64 => FitFieldData::FitSInt64(decode(data, endianness, i64::from_le_bytes, i64::from_be_bytes)),
+        FitDataType::FitUint64 => FitFieldData::FitUint64(decode(data, endianness, u64::from_le_bytes, u64::from_be_bytes)),
+        FitDataType::FitUint64z => FitFieldData::FitUint64z(decode(data, endianness, u64::from_le_bytes, u64::from_be_bytes)),
+    })
+}
+
+fn decode<A: Array, const N: usize>(data: &[u8], endianness: Endianness,
+                                    from_le: impl Fn([u8; N]) -> A::Item, from_be: impl Fn([u8; N]) -> A::Item) -> SmallVec<A> {
+    let from = |x: [u8; N]| match endianness {
+        Endianness::Little => from_le(x),
+        Endianness::Big => from_be(x),
+    };
+    // Most fields hold a single value.
+    if let Ok(x) = data.try_into() {
+        return smallvec![from(x)];
     }
+    data.chunks_exact(N).map(|x| from(x.try_into().unwrap())).collect()
 }
 
 pub fn write_fit_field(context: &mut FitFileContext, writer: &mut dyn Write, field: &FitFieldData)
-                   -> Result< (), std::io::Error >
+                   -> FitResult<()>
 {
     match field {
         FitFieldData::FitEnum(x) |
@@ -211,3 +155,29 @@ pub fn write_fit_field(context: &mut FitFileContext, writer: &mut dyn Write, fie
     }
 }
 
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode() -> FitResult<()> {
+        let data = [0x01, 0x02, 0x03, 0x04];
+        assert!(matches!(decode_fit_field(Some(Endianness::Little), FitDataType::FitUint16, &data)?,
+                         FitFieldData::FitUint16(x) if x[..] == [0x0201, 0x0403]));
+        assert!(matches!(decode_fit_field(Some(Endianness::Big), FitDataType::FitUint16, &data)?,
+                         FitFieldData::FitUint16(x) if x[..] == [0x0102, 0x0304]));
+        assert!(matches!(decode_fit_field(Some(Endianness::Big), FitDataType::FitSint32, &[0xFF, 0xFF, 0xFF, 0xFE])?,
+                         FitFieldData::FitSint32(x) if x[..] == [-2]));
+        assert!(matches!(decode_fit_field(None, FitDataType::FitString, b"abc\0\0")?,
+                         FitFieldData::FitString(x, 5) if x == "abc"));
+        assert!(decode_fit_field(None, FitDataType::FitUint16, &data).is_err());
+
+        // Reading gives the same values, and counts the bytes.
+        let mut context = FitFileContext{ architecture: Some(Endianness::Little), ..Default::default() };
+        let values = read_fit_field(&mut context, &mut data.as_ref(), FitDataType::FitUint16, 2)?;
+        assert!(matches!(values, FitFieldData::FitUint16(x) if x[..] == [0x0201, 0x0403]));
+        assert_eq!(4, context.data_bytes_read);
+        Ok(())
+    }
+}
@@ -2,18 +2,22 @@
 
 // std imports
 use std::fs::{File, OpenOptions};
-use std::io::{BufReader, BufWriter, Read, Write, Seek};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::sync::Arc;
 
 use byteorder::{LittleEndian,  ReadBytesExt, WriteBytesExt};
 
 use crate::profile;
 
-use crate::fittypes::{Endianness, FitFile, FitFileContext, FitRecord, FitFileHeader};
-use crate::fitcrc;
+use crate::fittypes::{Checks, FitDataMessage, FitFile, FitFileContext, FitRecord, FitFileHeader};
+use crate::fitcrc::FitCrc;
+use crate::fiterror::{FitError, FitErrorKind, FitResult};
 
-use crate::fitheader::{read_global_header};
-use crate::fitrecord::{read_record, write_record, print_rec};
+use crate::fitheader::{read_global_header, write_global_header};
+use crate::fitrecord::{read_record, read_record_if, write_record, print_rec_with_options, DumpOptions};
 use crate::fitcheck::{check_rec};
+use crate::fitrecover::recover_read;
+use crate::fitencode::FitEncoder;
 
 
 impl FitFile {
@@ -22,130 +26,334 @@ impl FitFile {
     }
 }
 
-struct FitFileReader<R: Read> {
+/// Reads the records of a FIT file one at a time, without keeping them in memory.
+///
+/// The records are the header, the definition and data messages, then EndOfFile once the CRC
+/// has been checked. Chained files follow each other, each starting with its header.
+/// Iteration stops at the end of the data or after the first error. Reading is done a few
+/// bytes at a time, so give it a buffered source.
+///
+/// let reader = FitReader::new(BufReader::new(File::open(path)?));
+/// for rec in reader { let rec = rec?; ... }
+pub struct FitReader<R: Read> {
     source: R,
     context: FitFileContext,
     data_size: Option<u32>,
+    files_read: u32,  // Files read up to and including the CRC.
+    pending: Option<u8>,  // A byte read to check for another file.
+    finished: bool,
+    filter: Option<MessageFilter>,  // If set, data messages it does not want are skipped.
 }
 
-impl<R: Read> FitFileReader<R> {
-    pub fn new(source: R) -> FitFileReader<R> {
-        return FitFileReader{source,
-            context: Default::default(),
-            data_size: None};
+type MessageFilter = Box<dyn Fn(u16) -> bool + Send + Sync>;
+
+impl FitReader<BufReader<File>> {
+    pub fn open(path: &str) -> FitResult<FitReader<BufReader<File>>> {
+        Ok(FitReader::new(BufReader::new(File::open(path)?)))
+    }
+}
+
+impl<R: Read> FitReader<R> {
+    pub fn new(source: R) -> FitReader<R> {
+        let context = FitFileContext {
+            profile: profile::shared(),
+            ..Default::default()
+        };
+        FitReader{source,
+            context,
+            data_size: None,
+            files_read: 0,
+            pending: None,
+            finished: false,
+            filter: None}
     }
 
-    pub fn read_global_header(&mut self) -> std::io::Result< FitFileHeader > {
-        let header = read_global_header(&mut self.context, &mut self.source)?;
+    pub fn read_global_header(&mut self) -> FitResult<FitFileHeader> {
+        if self.files_read > 0 {
+            self.context.start_next_file();
+        }
+        let header = match self.pending.take() {
+            Some(byte) => read_global_header(&mut self.context, &mut [byte].as_ref().chain(&mut self.source))?,
+            None => read_global_header(&mut self.context, &mut self.source)?,
+        };
         self.data_size = Some(header.data_size);
         return Ok(header);
     }
 
-    pub fn read_next(&mut self) -> std::io::Result<FitRecord>  {
-        if self.data_size.is_none() {
-            return Ok(FitRecord::HeaderRecord(
-                read_global_header(&mut self.context, &mut self.source)? ) );
+    pub fn read_next(&mut self) -> FitResult<FitRecord>  {
+        let data_size = match self.data_size {
+            Some(x) => x,
+            None => return Ok(FitRecord::HeaderRecord(self.read_global_header()?)),
+        };
+        while self.context.data_bytes_read < data_size {
+            let rec = match &self.filter {
+                Some(wanted) => read_record_if(&mut self.context, &mut self.source, wanted.as_ref())?,
+                None => Some(read_record(&mut self.context, &mut self.source)?),
+            };
+            if let Some(rec) = rec {
+                return Ok(rec);
+            }
         }
-        println!("Read: {} len: {}", self.context.data_bytes_read , self.data_size.unwrap());
-        if self.data_size.is_some() && (self.context.data_bytes_read < self.data_size.unwrap()) {
-            return read_record(&mut self.context, &mut self.source);
+        let offset = self.context.file_offset();
+        let record_index = self.context.records_read;
+        let file_crc = self.source.read_u16::<LittleEndian>()
+            .map_err(|e| FitError::from(e).at(offset, record_index))?;
+        let computed_crc = self.context.crc.digest();
+        if file_crc == computed_crc {
+            // The next record is the header of a chained file, if there is one.
+            self.data_size = None;
+            self.files_read += 1;
+            Ok(FitRecord::EndOfFile(file_crc))
         } else {
-            let file_crc = self.source.read_u16::<LittleEndian>()?;
-            let computed_crc = self.context.crc.digest();
-            if file_crc == computed_crc {
-                return Ok(FitRecord::EndOfFile(file_crc));
-            } else {
-                return Err(std::io::Error::new(std::io::ErrorKind::Other, "Bad CRC"));
+            Err(FitError::new(FitErrorKind::FileCrc{ expected: file_crc, computed: computed_crc })
+                .at(offset, record_index))
+        }
+    }
+
+    /// True if all data has been read, i.e. there is no further chained file.
+    pub fn at_end(&mut self) -> FitResult<bool> {
+        if self.pending.is_some() || self.data_size.is_some() {
+            return Ok(false);
+        }
+        let mut buf = [0u8; 1];
+        loop {
+            match self.source.read(&mut buf) {
+                Ok(0) => return Ok(true),
+                Ok(_) => {
+                    self.pending = Some(buf[0]);
+                    return Ok(false);
+                },
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(FitError::from(e).at(self.context.file_offset() + 2, 0)),
             }
         }
+    }
+
+    /// The number of files read, including their CRC.
+    pub fn files_read(&self) -> u32   {self.files_read}
+
+    /// The decoding state, e.g. the current definitions and developer fields.
+    pub fn context(&self) -> &FitFileContext   {&self.context}
+
+    /// Which problems are errors. Those that are not are kept in the context's warnings.
+    pub fn set_checks(&mut self, checks: Checks)   {self.context.checks = checks}
 
+    /// Only return the data messages whose global message number is wanted, the others are
+    /// skipped without decoding them. Developer data messages are always decoded.
+    pub fn set_message_filter<F: Fn(u16) -> bool + Send + Sync + 'static>(&mut self, wanted: F) {
+        self.filter = Some(Box::new(wanted));
     }
 
     pub fn source(&self) -> &R   {&self.source}
+
+    pub fn into_inner(self) -> R   {self.source}
+}
+
+impl<R: Read> Iterator for FitReader<R> {
+    type Item = FitResult<FitRecord>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        if self.files_read > 0 {
+            match self.at_end() {
+                Ok(true) => {
+                    self.finished = true;
+                    return None;
+                },
+                Ok(false) => {},
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                },
+            }
+        }
+        let rec = self.read_next();
+        self.finished = rec.is_err();
+        Some(rec)
+    }
 }
 
-struct FitFileWriter<W: Write + Seek> {
+/// Writes a FIT file to any Write, e.g. stdout or a socket, without seeking.
+///
+/// The CRC is computed as the bytes are written. The header holds the size of the data, so
+/// either the data is kept in memory until finish() is called, or the size is given in advance.
+///
+/// let mut writer = FitWriter::new(std::io::stdout(), header);
+/// writer.write_message(mesg)?;
+/// writer.finish()?;
+pub struct FitWriter<W: Write> {
     target: W,
     context: FitFileContext,
     header: FitFileHeader,
+    data_size: Option<u32>,  // Set if the header has been written.
+    data: Vec<u8>,  // The data, until the header is written.
+    data_bytes_written: u32,
+    crc: FitCrc,
+    encoder: FitEncoder,
 }
 
-impl<W: Read + Write + Seek> FitFileWriter<W> {
-    pub fn new(target: W) -> FitFileWriter<W> {
-        FitFileWriter{target, context: Default::default(),
-        header: Default::default(),
+impl<W: Write> FitWriter<W> {
+    /// A writer that keeps the data in memory, and writes it once it is finished.
+    pub fn new(target: W, header: FitFileHeader) -> FitWriter<W> {
+        FitWriter{target,
+            context: Default::default(),
+            header,
+            data_size: None,
+            data: Vec::new(),
+            data_bytes_written: 0,
+            crc: FitCrc::new(),
+            encoder: FitEncoder::new(),
         }
     }
 
-    pub fn write_global_header(&mut self, header: &FitFileHeader) -> std::io::Result<()> {
-        self.header = header.clone();
-        write_record(&mut self.context, &mut self.target,
-                     &FitRecord::HeaderRecord(self.header))
+    /// A writer that writes the data immediately, which must be data_size bytes in total.
+    pub fn with_data_size(target: W, header: FitFileHeader, data_size: u32) -> FitResult<FitWriter<W>> {
+        let mut writer = FitWriter::new(target, header);
+        writer.header.data_size = data_size;
+        writer.write_header()?;
+        writer.data_size = Some(data_size);
+        Ok(writer)
+    }
+
+    /// Write a definition or data message. The header and CRC are written by the writer, so
+    /// header and EndOfFile records are skipped.
+    pub fn write_record(&mut self, rec: &FitRecord) -> FitResult<()> {
+        if matches!(rec, FitRecord::HeaderRecord(_) | FitRecord::EndOfFile(_)) {
+            return Ok(());
+        }
+        let mut bytes = Vec::new();
+        write_record(&mut self.context, &mut bytes, rec)?;
+
+        let written = self.data_bytes_written as usize + bytes.len();
+        match self.data_size {
+            Some(size) => {
+                if written > size as usize {
+                    return Err(FitErrorKind::DataSize{ expected: size, actual: written as u64 }.into());
+                }
+                self.crc.consume(&bytes);
+                self.target.write_all(&bytes)?;
+            },
+            None => self.data.extend_from_slice(&bytes),
+        }
+        self.data_bytes_written = written as u32;
+        Ok(())
     }
 
-    pub fn write_next(&mut self, rec: &FitRecord) -> std::io::Result<()>  {
-        write_record(&mut self.context, &mut self.target, rec)
+    /// Write a data message, with definitions as needed, see FitEncoder.
+    pub fn write_message(&mut self, mesg: FitDataMessage) -> FitResult<()> {
+        for rec in self.encoder.encode(mesg)? {
+            self.write_record(&rec)?;
+        }
+        Ok(())
     }
 
-    pub fn finalize(&mut self) -> std::io::Result<()>  {
+    /// Write the header if the data was kept in memory, then the CRC. Returns the CRC.
+    pub fn finish(&mut self) -> FitResult<u16> {
+        match self.data_size {
+            Some(size) => {
+                if self.data_bytes_written != size {
+                    return Err(FitErrorKind::DataSize{ expected: size, actual: self.data_bytes_written as u64 }.into());
+                }
+            },
+            None => {
+                self.header.data_size = self.data_bytes_written;
+                self.write_header()?;
+                self.crc.consume(&self.data);
+                self.target.write_all(&self.data)?;
+                self.data.clear();
+            },
+        }
+        let crc = self.crc.digest();
+        self.target.write_u16::<LittleEndian>(crc)?;
         self.target.flush()?;
-        // Update data size, write new header.
-        self.header.data_size = self.context.data_bytes_written;
-        self.target.seek(std::io::SeekFrom::Start(0) )?;
-        write_record(&mut self.context, &mut self.target,
-                     &FitRecord::HeaderRecord(self.header))?;
-        self.target.flush()?;  // Required.
+        Ok(crc)
+    }
 
-        // compute new crc
-        let crc_out = fitcrc::crc_for_file(&mut self.target )?;  // "inadvisable"
-        self.target.seek(std::io::SeekFrom::End(0) )?;
-        self.target.write_u16::<LittleEndian>(crc_out)
+    fn write_header(&mut self) -> FitResult<()> {
+        let mut bytes = Vec::new();
+        write_global_header(&mut FitFileContext::default(), &mut bytes, &self.header)?;
+        self.crc.consume(&bytes);
+        Ok(self.target.write_all(&bytes)?)
     }
 
+    /// The encoder used by write_message, e.g. to enable compressed timestamps.
+    pub fn encoder(&mut self) -> &mut FitEncoder   {&mut self.encoder}
+
     pub fn target(&self) -> &W   {&self.target}
 
+    pub fn into_inner(self) -> W   {self.target}
 }
 
-pub fn read_file_filename(path: &str) -> std::io::Result<FitFile> {
+pub fn read_file_filename(path: &str) -> FitResult<FitFile> {
     println!("Opening file: {}", path);
     let mut file = File::open(path)?;
 
     return read_file_read(&mut file);
 }
 
-pub fn read_file_read(source: &mut dyn Read) -> std::io::Result<FitFile> {
-    let mut my_file: FitFile = FitFile::new();
-    let mut context: FitFileContext = Default::default();
-
-    let mut reader = BufReader::new(source);
-    my_file.header = read_global_header(&mut context, &mut reader)?;
+/// Read all records of a file. Damaged parts of the file are skipped, see fitrecover.
+pub fn read_file_read(source: &mut dyn Read) -> FitResult<FitFile> {
+    let recovery = recover_read(source)?;
+    for lost in &recovery.lost {
+        println!("Skipping bytes {}..{}: {}", lost.range.start, lost.range.end, lost.error);
+    }
+    if !recovery.data_size_valid {
+        println!("Data size {} in header is invalid", recovery.file.header.data_size);
+    }
+    match recovery.crc_valid {
+        Some(valid) => println!("CRC: valid {}", valid),
+        None => println!("CRC: not found"),
+    }
 
-    // Read data, total file size is header + data + crc
-    let len_to_read =  my_file.header.data_size;
-    while context.data_bytes_read < len_to_read {
-        let rec = read_record(&mut context, &mut reader);
-        match rec {
-            Ok(v) => {
-                my_file.records.push(v);
+    Ok(recovery.file)
+}
 
-            },
-            Err(e) => println!("Skipping bad rec {}", e),
+/// Read chained files, i.e. several FIT files one after the other.
+pub fn read_chained_files(source: &mut dyn Read) -> FitResult<Vec<FitFile>> {
+    let mut files: Vec<FitFile> = Vec::new();
+    for rec in FitReader::new(BufReader::new(source)) {
+        match rec? {
+            FitRecord::HeaderRecord(header) => files.push(FitFile{ header, records: Vec::new() }),
+            FitRecord::EndOfFile(_) => {},
+            rec => files.last_mut().unwrap().records.push(rec),
         }
     }
+    Ok(files)
+}
 
-    // Read directly as we don't want the crc value included in the crc computation.
-    let crc = reader.read_u16::<LittleEndian>()?;
-    println!("CRC: Computed 0x{:x}, Provided 0x{:x}", context.crc.digest(), crc);
+/// Write a file, setting the data size in the header and the CRC.
+pub fn write_file_write(target: &mut dyn Write, file: &FitFile) -> FitResult<()> {
+    let mut writer = FitWriter::new(target, file.header.clone());
+    for rec in &file.records {
+        writer.write_record(rec)?;
+    }
+    writer.finish()?;
+    Ok(())
+}
 
-    Ok(my_file)
+/// Write chained files, each with its own header and CRC.
+pub fn write_chained_files(target: &mut dyn Write, files: &[FitFile]) -> FitResult<()> {
+    for file in files {
+        write_file_write(target, file)?;
+    }
+    Ok(())
 }
 
-pub fn read_file(path: &str) -> std::io::Result<FitFile> {
+pub fn read_file(path: &str) -> FitResult<FitFile> {
+    read_file_with_options(path, &Default::default())
+}
+
+pub fn read_file_with_options(path: &str, options: &DumpOptions) -> FitResult<FitFile> {
     let mut my_file: FitFile = FitFile::new();
-    let mut context: FitFileContext = Default::default();
     let p = match profile::build_profile(){
-        Ok(p) => {p},
-        Err(e) => {return Err(std::io::Error::new(std::io::ErrorKind::Other, e));},
+        Ok(p) => {Arc::new(p)},
+        Err(e) => {return Err(FitErrorKind::Profile(e).into());},
+    };
+    let mut context = FitFileContext {
+        profile: Some(p.clone()),
+        ..Default::default()
     };
 
     println!("Opening file: {}", path);
@@ -158,19 +366,12 @@ pub fn read_file(path: &str) -> std::io::Result<FitFile> {
     debug!("Read header: {:?}", my_file.header);
 
     let file_out = OpenOptions::new()
-        .read(true)
         .write(true)
         .create(true)
         .truncate(true)
         .open("/tmp/fit_out.fit")?;
 
-    let mut writer = BufWriter::new(file_out);
-    let mut out_context: FitFileContext = Default::default();
-    out_context.architecture = Some(Endianness::Little);
-    let mut out_header = (my_file.header).clone();
-
-    let new_header_rec = FitRecord::HeaderRecord(out_header.clone());
-    write_record(&mut out_context, &mut writer, &new_header_rec)?;
+    let mut writer = FitWriter::new(BufWriter::new(file_out), my_file.header.clone());
 
     let mut num_rec = 1;  // Count the header as one record.
 
@@ -180,9 +381,9 @@ pub fn read_file(path: &str) -> std::io::Result<FitFile> {
         let rec = read_record(&mut context, &mut reader);
         match rec {
             Ok(v) => {
-                print_rec(&v, &p);
+                print_rec_with_options(&v, &p, options);
                 match check_rec(&context, &v ) {
-                    Ok(_) => { write_record(&mut out_context, &mut writer, &v) ?;},
+                    Ok(_) => { writer.write_record(&v) ?;},
                     Err(e) => println!("Skipping bad values in rec {}", e),
                 }
 
@@ -192,18 +393,7 @@ pub fn read_file(path: &str) -> std::io::Result<FitFile> {
         num_rec = num_rec + 1;
     }
 
-    writer.flush()?;
-    // Update data size, write new header.
-
-    out_header.data_size = out_context.data_bytes_written;
-    writer.seek(std::io::SeekFrom::Start(0) )?;
-    write_record(&mut out_context, &mut writer, &FitRecord::HeaderRecord(out_header))?;
-    writer.flush()?;  // Required.
-
-    // compute new crc
-    let crc_out = fitcrc::crc_for_file(writer.get_mut() )?;  // "inadvisable"
-    writer.seek(std::io::SeekFrom::End(0) )?;
-    writer.write_u16::<LittleEndian>(crc_out)?;
+    let crc_out = writer.finish()?;
     println!("Write CRC: 0x{:x}", crc_out);
     println!("Info: Read {:} records from {:} bytes", num_rec, context.data_bytes_read);
 
@@ -219,9 +409,11 @@ pub fn read_file(path: &str) -> std::io::Result<FitFile> {
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
-    use crate::fittypes::{FitFieldData};
-    use std::io::{Cursor, SeekFrom};
-    use crate::fitcrc::FitCrc;
+    use crate::fittypes::{FitDataType, FitFieldData};
+    use std::io::{Cursor, Seek, SeekFrom};
+    use crate::fitconvert::{FitConversion, field_data_to_physical};
+    use crate::fitrecord::print_rec;
+    use crate::fittest::get_developer_data_fit;
 
     /// This sample file is settings.fit from the FitSDKRelease_20.90.00
     fn get_settings_fit() -> Vec<u8> {
@@ -246,13 +438,6 @@ mod tests {
         gEChAIBAAMBAAQBAAUp5gejAAA1tSnlz2MAAQAaAdWh").unwrap()
     }
 
-    // DeveloperData.fit
-    fn get_developer_data_fit() -> Vec<u8> {
-        base64::decode("DiBoBqIAAAAuRklUvtBAAAEAAAQBAoQAAQACAoQDBIwAAA8EIykAAAalQAABAM8CARANAw\
-        ECAAEBAgMFCA0VIjdZkOl5YtsAQAABAM4FAAECAQECAgECAxEHCAoHAAAAAWRvdWdobnV0c19lYXJuZWQAZG91Z2hud\
-        XRzAGAAAQAUBAMBAgQBAgUEhgYChAEAAQAAjFgAAMc4uYABAI9aAAMsgI5AAgCQXAAFqTiKEAPTng=").unwrap()
-    }
-
     #[test]
     fn test_read_settings() {
         let settings_fit = get_settings_fit();
@@ -298,17 +483,78 @@ mod tests {
         }
     }
 
+    /// Physical value of a field of the first data message with the given global number.
+    fn physical_value(file_data: &FitFile, pf: &profile::ProfileData,
+                      global_message_number: u16, field_defn_num: u8) -> f64 {
+        let data_message = file_data.records.iter()
+            .find_map(|x| match x {
+                FitRecord::DataRecord(m) if m.global_message_number == global_message_number => Some(m),
+                _ => None,
+            }).unwrap();
+        let field = data_message.fields.iter()
+            .chain(data_message.expanded_fields.iter())
+            .find(|x| x.field_defn_num == field_defn_num).unwrap();
+        let field_desc = pf.get_message(global_message_number).unwrap()
+            .resolve_field(pf, field_defn_num, data_message).unwrap();
+        let conversion = FitConversion::from_field(field_desc);
+        field_data_to_physical(&conversion, &field.data).unwrap()[0].unwrap()
+    }
+
+    fn assert_close(expected: f64, actual: f64, tolerance: f64) {
+        assert!((expected - actual).abs() < tolerance, "Expected {} got {}", expected, actual);
+    }
+
+    #[test]
+    fn test_physical_values() {
+        let pf = profile::build_profile().unwrap();
+
+        let settings = read_file_read(&mut get_settings_fit().as_slice()).unwrap();
+        assert_close(90.0, physical_value(&settings, &pf, 3, 4), 1e-9);  // user_profile.weight, kg
+        assert_close(1.9, physical_value(&settings, &pf, 3, 3), 1e-9);  // user_profile.height, m
+
+        let activity = read_file_read(&mut get_activity_fit().as_slice()).unwrap();
+        assert_close(278.2, physical_value(&activity, &pf, 20, 2), 1e-9);  // record.altitude, m
+        assert_close(278.2, physical_value(&activity, &pf, 20, 78), 1e-9);  // record.enhanced_altitude
+        assert_close(41.5139261, physical_value(&activity, &pf, 20, 0), 1e-7);  // record.position_lat, deg
+        assert_close(-73.1485908, physical_value(&activity, &pf, 20, 1), 1e-7);  // record.position_long, deg
+        assert_close(0.02, physical_value(&activity, &pf, 20, 5), 1e-9);  // record.distance, m
+    }
+
+    #[test]
+    fn test_typed_messages() {
+        use crate::fitmessages::{Record, UserProfile};
+        use std::convert::TryFrom;
+
+        let activity = read_file_read(&mut get_activity_fit().as_slice()).unwrap();
+        let record = activity.records.iter()
+            .find_map(|x| match x {
+                FitRecord::DataRecord(m) => Record::try_from(m).ok(),
+                _ => None,
+            }).unwrap();
+        assert_close(278.2, record.altitude.unwrap(), 1e-9);
+        assert_close(278.2, record.enhanced_altitude.unwrap(), 1e-9);
+        assert_close(41.5139261, record.position_lat.unwrap(), 1e-7);
+        assert_close(0.02, record.distance.unwrap(), 1e-9);
+
+        let settings = read_file_read(&mut get_settings_fit().as_slice()).unwrap();
+        let user_profile = settings.records.iter()
+            .find_map(|x| match x {
+                FitRecord::DataRecord(m) => UserProfile::try_from(m).ok(),
+                _ => None,
+            }).unwrap();
+        assert_close(90.0, user_profile.weight.unwrap(), 1e-9);
+        assert_close(1.9, user_profile.height.unwrap(), 1e-9);
+    }
+
     #[test]
-    fn test_read_write() -> Result<(), std::io::Error> {
+    fn test_read_write() -> FitResult<()> {
         init();
         let settings_fit = get_settings_fit();
         let mut in_cursor = Cursor::new(settings_fit.clone());
 
-        let mut reader = FitFileReader::new(&mut in_cursor);
-        let out_cursor = Cursor::new(Vec::new());
-        let mut writer = FitFileWriter::new(out_cursor);
+        let mut reader = FitReader::new(&mut in_cursor);
         let header = reader.read_global_header()?;
-        writer.write_global_header(&header)?;
+        let mut writer = FitWriter::new(Vec::new(), header);
 
         loop {
             let field = reader.read_next()?;
@@ -316,21 +562,21 @@ mod tests {
                 FitRecord::HeaderRecord(_) => { panic!("BAD header record"); },
                 FitRecord::DataRecord(_) |
                 FitRecord::DefinitionMessage(_) => {
-                    writer.write_next(&field)?;
+                    writer.write_record(&field)?;
                 },
                 FitRecord::EndOfFile(_) => { break; },
             }
         }
-        writer.finalize()?;
+        writer.finish()?;
 
-        let buf = writer.target().get_ref().clone();
+        let buf = writer.into_inner();
 
         assert_eq!(settings_fit.len(), buf.len());
         assert_eq!(settings_fit, buf);
         Ok(())
     }
 
-    fn dump_file( fit_data: &Vec<u8>) -> Result<(), std::io::Error> {
+    fn dump_file( fit_data: &Vec<u8>) -> FitResult<()> {
         init();
         println!("Test data: {} bytes", fit_data.len());
         let pf =  profile::build_profile().unwrap();
@@ -339,7 +585,7 @@ mod tests {
         assert!(FitCrc::check_crc(&mut in_cursor, 0, fit_data.len() as u64)?);
         in_cursor.seek(SeekFrom::Start(0))?;
 
-        let mut reader = FitFileReader::new(&mut in_cursor);
+        let mut reader = FitReader::new(&mut in_cursor);
         let header = reader.read_global_header()?;
 
         print_rec(&FitRecord::HeaderRecord(header), &pf);
@@ -358,24 +604,364 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_reader_iterator() {
+        let records: Vec<FitRecord> = FitReader::new(get_activity_fit().as_slice())
+            .collect::<FitResult<Vec<FitRecord>>>().unwrap();
+        let activity = read_file_read(&mut get_activity_fit().as_slice()).unwrap();
+
+        assert_eq!(activity.records.len() + 2, records.len());
+        assert!(matches!(records.first(), Some(FitRecord::HeaderRecord(_))));
+        assert!(matches!(records.last(), Some(FitRecord::EndOfFile(_))));
+        let data_messages = records.iter().filter(|x| matches!(x, FitRecord::DataRecord(_))).count();
+        assert_eq!(activity.records.iter().filter(|x| matches!(x, FitRecord::DataRecord(_))).count(), data_messages);
+
+        // A bad CRC is an error, after which iteration stops.
+        let mut bad_crc = get_activity_fit();
+        let len = bad_crc.len();
+        bad_crc[len - 1] ^= 0xFF;
+        let mut reader = FitReader::new(bad_crc.as_slice());
+        assert!(reader.by_ref().take(records.len() - 1).all(|x| x.is_ok()));
+        let e = reader.next().unwrap().unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::FileCrc{ .. }));
+        assert_eq!(Some(len as u64 - 2), e.offset);
+        assert_eq!(Some(records.len() as u64 - 1), e.record_index);
+        assert!(reader.next().is_none());
+
+        // As is running out of data.
+        let truncated = &get_activity_fit()[..100];
+        let results: Vec<FitResult<FitRecord>> = FitReader::new(truncated).collect();
+        assert!(results.last().unwrap().as_ref().unwrap_err().is_truncated());
+        assert!(results[..results.len() - 1].iter().all(|x| x.is_ok()));
+    }
+
+    #[test]
+    fn test_error_positions() {
+        // The header is 12 bytes, the first record defines local message 0.
+        // Make it a data message for local message 5.
+        let mut data = get_activity_fit();
+        assert_eq!(0x40, data[12]);
+        data[12] = 0x05;
+        let e = FitReader::new(data.as_slice()).find_map(|x| x.err()).unwrap();
+        assert!(matches!(e.kind, FitErrorKind::UndefinedLocalMessage(5)));
+        assert_eq!(Some(12), e.offset);
+        assert_eq!(Some(1), e.record_index);
+        assert_eq!("No definition for local message type 5 in record 1 at byte 12", e.to_string());
+
+        let mut data = get_activity_fit();
+        data[9] = b'X';
+        let e = FitReader::new(data.as_slice()).find_map(|x| x.err()).unwrap();
+        assert!(matches!(e.kind, FitErrorKind::InvalidHeader(_)));
+        assert_eq!(Some(0), e.offset);
+        assert_eq!(Some(0), e.record_index);
+    }
+
+    #[test]
+    fn test_invalid_base_types() -> FitResult<()> {
+        // In the first definition, give field 1 an unknown base type and make field 2 a uint32
+        // of 2 bytes.
+        let mut data = get_settings_fit();
+        assert_eq!([1, 2, 0x84, 2, 2, 0x84], data[18..24]);
+        data[20] = 0x9F;
+        data[23] = 0x86;
+        let crc_pos = data.len() - 2;
+        let crc = crate::fitcrc::compute(&data[..crc_pos]);
+        data[crc_pos..].copy_from_slice(&crc.to_le_bytes());
+
+        // By default a field size that does not fit the base type is a warning.
+        let mut sizes_only = data.clone();
+        sizes_only[20] = 0x84;
+        let crc = crate::fitcrc::compute(&sizes_only[..crc_pos]);
+        sizes_only[crc_pos..].copy_from_slice(&crc.to_le_bytes());
+        let mut reader = FitReader::new(sizes_only.as_slice());
+        assert!(reader.by_ref().all(|x| x.is_ok()));
+        assert_eq!(1, reader.context().warnings.len());
+        let mut reader = FitReader::new(sizes_only.as_slice());
+        reader.set_checks(Checks{ field_sizes: true, ..Default::default() });
+        let e = reader.find_map(|x| x.err()).unwrap();
+        assert!(matches!(e.kind, FitErrorKind::InvalidFieldDefinition(_)));
+
+        let e = FitReader::new(data.as_slice()).find_map(|x| x.err()).unwrap();
+        assert!(matches!(e.kind, FitErrorKind::InvalidBaseType(0x9F)));
+        assert_eq!(Some(12), e.offset);
+
+        // Leniently, the fields are read as bytes and written back unchanged.
+        let mut reader = FitReader::new(data.as_slice());
+        reader.set_checks(Checks{ valid_base_types: false, ..Default::default() });
+        let header = reader.read_global_header()?;
+        let mut writer = FitWriter::new(Vec::new(), header);
+        let mut bytes_fields = 0;
+        for rec in reader.by_ref() {
+            let rec = rec?;
+            if let FitRecord::DataRecord(mesg) = &rec {
+                bytes_fields += mesg.fields.iter()
+                    .filter(|x| matches!(&x.data, FitFieldData::FitByte(b) if b.len() == 2)).count();
+            }
+            writer.write_record(&rec)?;
+        }
+        writer.finish()?;
+        assert_eq!(data, writer.into_inner());
+
+        let warnings = &reader.context().warnings;
+        assert_eq!(2, warnings.len());
+        assert!(matches!(warnings[0].kind, FitErrorKind::InvalidBaseType(0x9F)));
+        assert!(matches!(warnings[1].kind, FitErrorKind::InvalidFieldDefinition(_)));
+        assert_eq!((Some(12), Some(1)), (warnings[1].offset, warnings[1].record_index));
+        assert_eq!(2, bytes_fields);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_dev_base_type() -> FitResult<()> {
+        // Give the field description an unknown base type.
+        let mut data = get_developer_data_fit();
+        assert_eq!([0, 0, 1], data[94..97]);
+        data[96] = 0x1F;
+        let crc_pos = data.len() - 2;
+        let crc = crate::fitcrc::compute(&data[..crc_pos]);
+        data[crc_pos..].copy_from_slice(&crc.to_le_bytes());
+
+        let e = FitReader::new(data.as_slice()).find_map(|x| x.err()).unwrap();
+        assert!(matches!(e.kind, FitErrorKind::InvalidBaseType(0x1F)));
+        assert_eq!(Some(93), e.offset);
+
+        // Leniently, the field is read as bytes without a description and written back unchanged.
+        let mut reader = FitReader::new(data.as_slice());
+        reader.set_checks(Checks{ valid_base_types: false, ..Default::default() });
+        let header = reader.read_global_header()?;
+        let mut writer = FitWriter::new(Vec::new(), header);
+        let mut dev_fields = 0;
+        for rec in reader.by_ref() {
+            let rec = rec?;
+            if let FitRecord::DataRecord(mesg) = &rec {
+                for field in &mesg.dev_fields {
+                    assert!(field.description.is_none() && matches!(field.data, FitFieldData::FitByte(_)));
+                    dev_fields += 1;
+                }
+            }
+            writer.write_record(&rec)?;
+        }
+        writer.finish()?;
+        assert_eq!(data, writer.into_inner());
+        assert_eq!(3, dev_fields);
+        let warnings = &reader.context().warnings;
+        assert_eq!(1, warnings.len());
+        assert!(matches!(warnings[0].kind, FitErrorKind::InvalidBaseType(0x1F)));
+        Ok(())
+    }
+
+    /// Read the records of a file and write them again.
+    fn read_write(data: &[u8]) -> FitResult<Vec<u8>> {
+        let mut reader = FitReader::new(data);
+        let header = reader.read_global_header()?;
+        let mut writer = FitWriter::new(Vec::new(), header);
+        for rec in reader {
+            writer.write_record(&rec?)?;
+        }
+        writer.finish()?;
+        Ok(writer.into_inner())
+    }
+
+    /// A file with the given data.
+    fn fit_file(header_size: u8, crc: Option<u16>, data: &[u8]) -> Vec<u8> {
+        let extra = header_size.saturating_sub(14) as usize;
+        let header = FitFileHeader{ header_size, protocol_version: 0x10, profile_version: 2132,
+            data_size: data.len() as u32, type_signature: *b".FIT", crc, extra: vec![0xAA; extra] };
+        let mut file = Vec::new();
+        write_global_header(&mut FitFileContext::default(), &mut file, &header).unwrap();
+        file.extend_from_slice(data);
+        let crc = crate::fitcrc::compute(&file);
+        file.extend_from_slice(&crc.to_le_bytes());
+        file
+    }
+
+    #[test]
+    fn test_lossless() -> FitResult<()> {
+        for data in [get_settings_fit(), get_activity_fit(), get_developer_data_fit()].iter() {
+            assert_eq!(data, &read_write(data)?);
+        }
+
+        // Things a writer would not do: a nonzero reserved byte, a base type without the endian
+        // bit, a developer flag with no developer fields, and a string that is not UTF-8.
+        let settings = get_settings_fit();
+        let mut data = settings[12..settings.len() - 2].to_vec();
+        assert_eq!([0x40, 0, 1, 0, 0, 4, 1, 2, 0x84], data[..9]);
+        data[1] = 7;
+        data[8] = 0x04;
+        data[0] = 0x60;
+        data.insert(18, 0);
+        data.extend_from_slice(&[0x41, 0, 0, 0x00, 0xFF, 1, 0, 4, 0x07, 0x01, b'a', 0xFF, 0, 0]);
+
+        // Headers with extra bytes, and without a CRC.
+        for file in [fit_file(16, Some(0), &data), fit_file(14, Some(0), &data), fit_file(16, None, &data)].iter() {
+            assert_eq!(file, &read_write(file)?);
+        }
+        let file = read_file_read(&mut fit_file(16, None, &data).as_slice())?;
+        assert_eq!(vec![0xAA, 0xAA], file.header.extra);
+        let string_field = file.records.iter().rev().find_map(|x| match x {
+            FitRecord::DataRecord(m) => Some(&m.fields[0].data),
+            _ => None,
+        }).unwrap();
+        assert!(matches!(string_field, FitFieldData::FitByte(x) if x[..] == [b'a', 0xFF, 0, 0]));
+
+        // A base type that has been changed is written as the new type.
+        let mut defn = FitReader::new(settings.as_slice()).find_map(|x| match x {
+            Ok(FitRecord::DefinitionMessage(d)) => Some(d),
+            _ => None,
+        }).unwrap();
+        let mut field = (*defn.field_defns[0]).clone();
+        assert_eq!(Some(0x84), field.raw_base_type);
+        field.data_type = Some(FitDataType::FitSint16);
+        Arc::make_mut(&mut defn).field_defns[0] = Arc::new(field);
+        let mut bytes = Vec::new();
+        write_record(&mut FitFileContext::default(), &mut bytes, &FitRecord::DefinitionMessage(defn))?;
+        assert_eq!([1, 2, 0x83, 2, 2, 0x84], bytes[6..12]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_chained_files() {
+        let single = get_activity_fit();
+        let len = single.len() as u64;
+        let mut chained = single.clone();
+        chained.extend_from_slice(&single);
+
+        let records = FitReader::new(single.as_slice()).count();
+        let mut reader = FitReader::new(chained.as_slice());
+        let results: Vec<FitResult<FitRecord>> = reader.by_ref().collect();
+        assert_eq!(2 * records, results.len());
+        assert!(results.iter().all(|x| x.is_ok()));
+        assert!(matches!(results[records], Ok(FitRecord::HeaderRecord(_))));
+        assert_eq!(2, reader.files_read());
+
+        let files = read_chained_files(&mut chained.as_slice()).unwrap();
+        assert_eq!(2, files.len());
+        assert_eq!(records - 2, files[0].records.len());
+        assert_eq!(records - 2, files[1].records.len());
+
+        // Positions of errors are in the stream, definitions are not kept from the first file.
+        let mut data = chained.clone();
+        data[single.len() + 12] = 0x00;
+        let e = FitReader::new(data.as_slice()).find_map(|x| x.err()).unwrap();
+        assert!(matches!(e.kind, FitErrorKind::UndefinedLocalMessage(0)));
+        assert_eq!(Some(len + 12), e.offset);
+        assert_eq!(Some(1), e.record_index);
+
+        let mut out = Vec::new();
+        write_chained_files(&mut out, &files).unwrap();
+        let rewritten = read_chained_files(&mut out.as_slice()).unwrap();
+        assert_eq!(2, rewritten.len());
+        assert_eq!(files[1].records.len(), rewritten[1].records.len());
+    }
+
+    #[test]
+    fn test_streaming_writer() {
+        let file = read_file_read(&mut get_activity_fit().as_slice()).unwrap();
+        let mut buffered = Vec::new();
+        write_file_write(&mut buffered, &file).unwrap();
+        let data_size = (buffered.len() - file.header.header_size as usize - 2) as u32;
+
+        // Given the data size, the data is written as it arrives.
+        let mut writer = FitWriter::with_data_size(Vec::new(), file.header.clone(), data_size).unwrap();
+        assert_eq!(file.header.header_size as usize, writer.target().len());
+        for rec in &file.records {
+            writer.write_record(rec).unwrap();
+        }
+        let crc = writer.finish().unwrap();
+        let streamed = writer.into_inner();
+        assert_eq!(buffered, streamed);
+        assert_eq!(crc.to_le_bytes(), streamed[streamed.len() - 2..]);
+        assert!(FitReader::new(streamed.as_slice()).all(|x| x.is_ok()));
+
+        // Writing more or less than the data size is an error.
+        let mut writer = FitWriter::with_data_size(Vec::new(), file.header.clone(), 10).unwrap();
+        let e = file.records.iter().find_map(|x| writer.write_record(x).err()).unwrap();
+        assert!(matches!(e.kind, FitErrorKind::DataSize{ expected: 10, .. }));
+        let mut writer = FitWriter::with_data_size(Vec::new(), file.header.clone(), data_size + 1).unwrap();
+        let e = writer.finish().unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::DataSize{ actual: 0, .. }));
+    }
+
+    #[test]
+    fn test_write_messages() {
+        use crate::fitmessages::{FileId, Record};
+        use crate::fitenums::File;
+
+        let mut writer = FitWriter::new(Vec::new(), FitFileHeader{ header_size: 14, ..Default::default() });
+        writer.write_message(FitDataMessage::from(&FileId{ r#type: Some(File::Activity), ..Default::default() })).unwrap();
+        for i in 0..10 {
+            let record = Record{ timestamp: Some(1000 + i), heart_rate: Some(100 + i as u8), ..Default::default() };
+            writer.write_message(FitDataMessage::from(&record)).unwrap();
+        }
+        writer.finish().unwrap();
+
+        let records = FitReader::new(writer.target().as_slice())
+            .collect::<FitResult<Vec<FitRecord>>>().unwrap();
+        let data_messages = records.iter().filter(|x| matches!(x, FitRecord::DataRecord(_))).count();
+        let definitions = records.iter().filter(|x| matches!(x, FitRecord::DefinitionMessage(_))).count();
+        assert_eq!(11, data_messages);
+        assert_eq!(2, definitions);
+    }
+
+    #[test]
+    fn test_recover() {
+        use crate::fitrecover::recover;
+
+        let records = FitReader::new(get_activity_fit().as_slice()).count();
+        let clean = recover(&get_activity_fit()).unwrap();
+        assert!(clean.is_clean());
+        assert_eq!(records - 2, clean.file.records.len());
+
+        // The record at 146 is the first of 15 data messages before the next definition at 449.
+        let mut data = get_activity_fit();
+        data[146] = 0x0F;
+        let recovery = recover(&data).unwrap();
+        assert_eq!(1, recovery.lost.len());
+        assert_eq!(146..449, recovery.lost[0].range);
+        assert!(matches!(recovery.lost[0].error.kind, FitErrorKind::UndefinedLocalMessage(15)));
+        assert_eq!(clean.file.records.len() - 15, recovery.file.records.len());
+        assert_eq!(Some(false), recovery.crc_valid);
+
+        // A device that stopped while recording, without setting the data size.
+        let mut data = get_activity_fit()[..600].to_vec();
+        data[4..8].copy_from_slice(&[0, 0, 0, 0]);
+        let recovery = recover(&data).unwrap();
+        assert!(!recovery.data_size_valid);
+        assert_eq!(1, recovery.lost.len());
+        assert_eq!(591..600, recovery.lost[0].range);
+        assert!(recovery.lost[0].error.is_truncated());
+        assert_eq!(None, recovery.crc_valid);
+
+        // Without a data size the CRC is found at the end.
+        let mut data = get_activity_fit();
+        let len = data.len();
+        data[4..8].copy_from_slice(&[0, 0, 0, 0]);
+        let crc = crate::fitcrc::compute(&data[..len - 2]);
+        data[len - 2..].copy_from_slice(&crc.to_le_bytes());
+        let recovery = recover(&data).unwrap();
+        assert!(recovery.lost.is_empty());
+        assert_eq!(Some(true), recovery.crc_valid);
+        assert_eq!(clean.file.records.len(), recovery.file.records.len());
+    }
+
     fn init() {
         let _ = env_logger::builder().is_test(true).try_init();
     }
 
     #[test]
-    fn test_dump_settings() -> Result<(), std::io::Error> {
+    fn test_dump_settings() -> FitResult<()> {
         init();
         dump_file(&get_settings_fit() )
     }
 
     #[test]
-    fn test_dump_activity() -> Result<(), std::io::Error> {
+    fn test_dump_activity() -> FitResult<()> {
         init();
         dump_file(&get_activity_fit() )
     }
 
     #[test]
-    fn test_dump_developer_data() -> Result<(), std::io::Error> {
+    fn test_dump_developer_data() -> FitResult<()> {
         init();
         dump_file(&get_developer_data_fit() )
     }
@@ -8,10 +8,22 @@ use byteorder::{LittleEndian,  ReadBytesExt, WriteBytesExt};
 // Local imports
 use crate::fittypes::{FitFileContext, FitFileHeader, Endianness};
 use crate::fitcrc;
+use crate::fiterror::{FitErrorKind, FitResult};
 use crate::fitread::{fit_read_u8, fit_read_u16};
 use crate::fitwrite::{fit_write_u8, fit_write_u16};
 
-pub fn read_global_header(context: &mut FitFileContext, reader: &mut dyn Read) -> std::io::Result< FitFileHeader > {
+/// Read the file header, errors are at record 0.
+pub fn read_global_header(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<FitFileHeader> {
+    context.header_size = 0;
+    context.records_read = 0;
+    let file_start = context.file_start;
+    let header = read_header(context, reader).map_err(|e| e.at(file_start, 0))?;
+    context.header_size = header.header_size as u32;
+    context.records_read = 1;
+    Ok(header)
+}
+
+fn read_header(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<FitFileHeader> {
 
     let mut header_buf: [u8; 12] = [0; 12];
 
@@ -33,7 +45,7 @@ pub fn read_global_header(context: &mut FitFileContext, reader: &mut dyn Read) -
 
     let expected_signature : [u8;4] = ['.' as u8, 'F' as u8, 'I' as u8, 'T' as u8 ];
     if header.type_signature != expected_signature {
-        return Err( std::io::Error::new(std::io::ErrorKind::Other, "Invalid FIT signature"));
+        return Err(FitErrorKind::InvalidHeader("invalid FIT signature").into());
     }
 
     context.data_bytes_read = 12;
@@ -41,30 +53,34 @@ pub fn read_global_header(context: &mut FitFileContext, reader: &mut dyn Read) -
     // CRC is not present in older FIT formats.
     if header.header_size >= 14 {
         context.architecture = Some(Endianness::Little);
-        header.crc = fit_read_u16(context, reader)?;
-        context.data_bytes_read += 2;
+        let crc = fit_read_u16(context, reader)?;
+        header.crc = Some(crc);
 
         let actual_crc = fitcrc::compute(&header_buf);
         //debug!("Actual: {} Expected: {}", actual_crc, my_file.header.crc);
-        if (header.crc != 0) && (actual_crc != header.crc) {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Header CRC is invalid"));
+        if (crc != 0) && (actual_crc != crc) {
+            return Err(FitErrorKind::HeaderCrc{ expected: crc, computed: actual_crc }.into());
         }
 
         debug!("Header CRC OK");
     }
 
-    if header.header_size as u32 > context.data_bytes_read {
-        while header.header_size as u32 > context.data_bytes_read {
-            fit_read_u8(context, reader)?;
-        }
+    // Keep any bytes that a later protocol may add.
+    while header.header_size as u32 > context.data_bytes_read {
+        header.extra.push(fit_read_u8(context, reader)?);
     }
     context.data_bytes_read = 0;
     Ok( header )
 }
 
 pub fn write_global_header(context: &mut FitFileContext, writer: &mut dyn Write, header: &FitFileHeader)
-                       -> Result< (), std::io::Error>
+                       -> FitResult<()>
 {
+    let base_size = if header.header_size >= 14 { 14 } else { 12 };
+    if header.header_size as usize != base_size + header.extra.len() {
+        return Err(FitErrorKind::InvalidHeader("header size is invalid").into());
+    }
+
     let mut header_buf: [u8; 12] = [0; 12];
     {
         let mut header_writer = vec![];
@@ -87,14 +103,16 @@ pub fn write_global_header(context: &mut FitFileContext, writer: &mut dyn Write,
 
     // CRC is not present in older FIT formats.
     if header.header_size >= 14 {
-        let crc = fitcrc::compute(&header_buf);
+        let crc = match header.crc {
+            Some(0) => 0,
+            _ => fitcrc::compute(&header_buf),
+        };
         fit_write_u16(context, writer, crc)?;
     }
+    for x in &header.extra {
+        fit_write_u8(context, writer, *x)?;
+    }
 
     context.data_bytes_written = 0;
-
-    if header.header_size as u32 > 14 {
-        return Err(std::io::Error::new(std::io::ErrorKind::Other, "Header size is invalid"));
-    }
     Ok( () )
 }
@@ -0,0 +1,316 @@
+// An index of the data messages in a file, to decode any of them without reading from the start.
+
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Arc;
+
+use byteorder::{LittleEndian, ReadBytesExt};
+
+use crate::profile::{self, ProfileData};
+use crate::fitdatamesg::{accumulated_fields, is_developer_data, read_data_message, skip_accumulating_data_message};
+use crate::fitdefnmesg::read_definition_message;
+use crate::fiterror::{FitError, FitErrorKind, FitResult};
+use crate::fitheader::read_global_header;
+use crate::fitrecord::{at_record, read_record, read_record_header, RecordHeader};
+use crate::fittypes::{FitDataMessage, FitDataType, FitDefinitionMessage, FitFileContext, FitFileDeveloperId, FitRecord, FitAccumulator};
+
+const TIMESTAMP: u8 = 253;
+
+/// The definitions and developer data in force at a point in the file. It is shared by the
+/// entries that follow, until a definition or developer data message changes it.
+#[derive(Debug)]
+struct FitIndexState {
+    file_start: u64,
+    header_size: u32,
+    field_definitions: HashMap<u8, Arc<FitDefinitionMessage>>,
+    developer_ids: HashMap<u8, FitFileDeveloperId>,
+}
+
+/// Where a data message is, and what is needed to decode it.
+#[derive(Clone, Debug)]
+pub struct FitIndexEntry {
+    pub offset: u64,  // Byte offset of the record header in the stream.
+    pub record_index: u64,  // In its file, the header is record 0.
+    pub local_message_type: u8,
+    pub global_message_number: u16,
+    pub timestamp: Option<u32>,  // If the message has one, from its fields or a compressed header.
+    prev_timestamp: u32,  // The reference for a compressed timestamp header.
+    state: Arc<FitIndexState>,
+    accumulators: Arc<[((u16, u8), FitAccumulator)]>,  // Before the message, shared until they change.
+}
+
+impl FitIndexEntry {
+    /// The definition of the message.
+    pub fn definition(&self) -> Option<&Arc<FitDefinitionMessage>> {
+        self.state.field_definitions.get(&self.local_message_type)
+    }
+
+    /// The definitions of all local message types at the message.
+    pub fn definitions(&self) -> &HashMap<u8, Arc<FitDefinitionMessage>> {
+        &self.state.field_definitions
+    }
+}
+
+/// The data messages of a file, including chained files, found in one pass that only decodes
+/// timestamps, developer data and the messages with accumulated fields, e.g. distance from
+/// compressed_speed_distance. Messages can then be decoded from any Read + Seek source with the
+/// same data, with the accumulated fields totalled from the start of the file.
+///
+/// let index = FitIndex::build(BufReader::new(File::open(path)?))?;
+/// let mut file = BufReader::new(File::open(path)?);
+/// let lap = index.read_messages(&mut file, index.time_range(start, end))?;
+#[derive(Debug)]
+pub struct FitIndex {
+    entries: Vec<FitIndexEntry>,
+    files: u32,
+    profile: Option<Arc<ProfileData>>,
+}
+
+impl FitIndex {
+    /// Read through the source, checking the CRC of each file. Give it a buffered source.
+    pub fn build<R: Read>(mut source: R) -> FitResult<FitIndex> {
+        let mut index = FitIndex{ entries: Vec::new(), files: 0, profile: profile::shared() };
+        let mut context = FitFileContext{ profile: index.profile.clone(), ..Default::default() };
+        let mut pending = None;
+        loop {
+            if index.files > 0 {
+                context.start_next_file();
+            }
+            let header = match pending.take() {
+                Some(byte) => read_global_header(&mut context, &mut [byte].as_ref().chain(&mut source))?,
+                None => read_global_header(&mut context, &mut source)?,
+            };
+
+            let mut state: Option<Arc<FitIndexState>> = None;
+            let mut accumulators: Arc<[((u16, u8), FitAccumulator)]> = Arc::new([]);
+            let mut accumulating: HashMap<u8, Vec<usize>> = HashMap::new();  // By local message type.
+            while context.data_bytes_read < header.data_size {
+                let snapshot = state.get_or_insert_with(|| Arc::new(FitIndexState{
+                    file_start: context.file_start,
+                    header_size: context.header_size,
+                    field_definitions: context.field_definitions.clone(),
+                    developer_ids: context.developer_ids.clone(),
+                })).clone();
+                let offset = context.file_offset();
+                let record_index = context.records_read;
+                let prev_timestamp = context.timestamp;
+                match at_record(&mut context, |context| index_record(context, &mut source, &mut accumulating))? {
+                    Some((local_message_type, global_message_number, has_timestamp)) => {
+                        index.entries.push(FitIndexEntry{ offset, record_index, local_message_type,
+                            global_message_number, timestamp: has_timestamp.then_some(context.timestamp),
+                            prev_timestamp, state: snapshot, accumulators: accumulators.clone() });
+                        if is_developer_data(global_message_number) {
+                            state = None;
+                        } else if accumulators.len() != context.accumulators.len()
+                            || accumulators.iter().any(|(k, v)| context.accumulators.get(k) != Some(v)) {
+                            accumulators = context.accumulators.iter().map(|(k, v)| (*k, *v)).collect();
+                        }
+                    },
+                    None => state = None,
+                }
+            }
+
+            let offset = context.file_offset();
+            let record_index = context.records_read;
+            let expected = source.read_u16::<LittleEndian>()
+                .map_err(|e| FitError::from(e).at(offset, record_index))?;
+            let computed = context.crc.digest();
+            if expected != computed {
+                return Err(FitError::new(FitErrorKind::FileCrc{ expected, computed }).at(offset, record_index));
+            }
+            index.files += 1;
+
+            // Another file may follow.
+            let mut buf = [0u8; 1];
+            if source.read(&mut buf).map_err(|e| FitError::from(e).at(offset + 2, 0))? == 0 {
+                return Ok(index);
+            }
+            pending = Some(buf[0]);
+        }
+    }
+
+    /// The data messages, in the order of the file.
+    pub fn entries(&self) -> &[FitIndexEntry]   {&self.entries}
+
+    /// The number of chained files.
+    pub fn files(&self) -> u32   {self.files}
+
+    /// The entries from the first with a timestamp at or after start, up to the first with a
+    /// timestamp at or after end. Messages without a timestamp in between are included.
+    pub fn time_range(&self, start: u32, end: u32) -> &[FitIndexEntry] {
+        let at_or_after = |entries: &[FitIndexEntry], t: u32| entries.iter()
+            .position(|x| x.timestamp.is_some_and(|x| x >= t))
+            .unwrap_or(entries.len());
+        let first = at_or_after(&self.entries, start);
+        let last = first + at_or_after(&self.entries[first..], end);
+        &self.entries[first..last]
+    }
+
+    /// Decode the message of an entry.
+    pub fn read_message<R: Read + Seek>(&self, source: &mut R, entry: &FitIndexEntry) -> FitResult<FitDataMessage> {
+        let mut mesgs = self.read_messages(source, std::iter::once(entry))?;
+        Ok(mesgs.remove(0))
+    }
+
+    /// Decode the messages of the entries. Messages that follow each other are read without seeking.
+    pub fn read_messages<'a, R, I>(&self, source: &mut R, entries: I) -> FitResult<Vec<FitDataMessage>>
+        where R: Read + Seek, I: IntoIterator<Item = &'a FitIndexEntry>
+    {
+        let mut mesgs = Vec::new();
+        let mut context: Option<FitFileContext> = None;
+        for entry in entries {
+            let context = match context.take() {
+                Some(x) if x.file_offset() == entry.offset => context.insert(x),
+                _ => {
+                    source.seek(SeekFrom::Start(entry.offset))?;
+                    context.insert(FitFileContext{
+                        data_bytes_read: (entry.offset - entry.state.file_start - entry.state.header_size as u64) as u32,
+                        field_definitions: entry.state.field_definitions.clone(),
+                        developer_ids: entry.state.developer_ids.clone(),
+                        timestamp: entry.prev_timestamp,
+                        profile: self.profile.clone(),
+                        accumulators: entry.accumulators.iter().copied().collect(),
+                        header_size: entry.state.header_size,
+                        records_read: entry.record_index,
+                        file_start: entry.state.file_start,
+                        ..Default::default()
+                    })
+                },
+            };
+            match read_record(context, source)? {
+                FitRecord::DataRecord(mesg) if mesg.global_message_number == entry.global_message_number => mesgs.push(mesg),
+                _ => return Err(FitError::new(FitErrorKind::IndexMismatch).at(entry.offset, entry.record_index)),
+            }
+        }
+        Ok(mesgs)
+    }
+}
+
+/// Read a record, skipping data messages other than developer data, but decoding the fields that
+/// update the accumulators. Gives the local and global message numbers of a data message and
+/// whether it has a timestamp, None for a definition. The accumulated fields of each local type
+/// are kept in `accumulating`.
+fn index_record<R: Read>(context: &mut FitFileContext, source: &mut R, accumulating: &mut HashMap<u8, Vec<usize>>)
+    -> FitResult<Option<(u8, u16, bool)>>
+{
+    match read_record_header(context, source)? {
+        RecordHeader::Definition{ local_message_type, is_developer } => {
+            read_definition_message(context, source, local_message_type, is_developer)?;
+            accumulating.remove(&local_message_type);
+            Ok(None)
+        },
+        RecordHeader::Data{ local_message_type, timestamp } => {
+            let defn = match context.field_definitions.get(&local_message_type) {
+                Some(x) => x.clone(),
+                None => return Err(FitErrorKind::UndefinedLocalMessage(local_message_type).into()),
+            };
+            let accumulated = accumulating.entry(local_message_type).or_insert_with(||
+                context.profile.as_ref().map(|x| accumulated_fields(x, &defn)).unwrap_or_default());
+            if is_developer_data(defn.global_message_number) {
+                read_data_message(context, source, local_message_type, timestamp)?;
+            } else {
+                skip_accumulating_data_message(context, source, local_message_type, timestamp, accumulated)?;
+            }
+            let has_timestamp = timestamp.is_some() || defn.field_defns.iter()
+                .any(|x| x.field_defn_num == TIMESTAMP && x.data_type == Some(FitDataType::FitUint32));
+            Ok(Some((local_message_type, defn.global_message_number, has_timestamp)))
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitfile::FitWriter;
+    use crate::fitmessages::{Event, Record};
+    use crate::fittest::{debug_strings, get_developer_data_fit, make_activity_fit, read_data_messages};
+    use crate::fittypes::FitFileHeader;
+    use std::convert::TryFrom;
+    use std::io::Cursor;
+
+    #[test]
+    fn test_random_access() -> FitResult<()> {
+        let mut data = make_activity_fit();
+        data.extend(make_activity_fit());
+        let expected = debug_strings(&read_data_messages(&data));
+
+        let index = FitIndex::build(data.as_slice())?;
+        assert_eq!(2, index.files());
+        assert_eq!(expected.len(), index.entries().len());
+        assert_eq!(2, index.entries().iter().filter(|x| x.global_message_number == Record::MESG_NUM)
+            .map(|x| x.definition().unwrap().field_defns.len()).collect::<std::collections::HashSet<_>>().len());
+
+        // Any message can be read on its own, in any order.
+        let mut source = Cursor::new(&data);
+        for (i, entry) in index.entries().iter().enumerate().rev() {
+            assert_eq!(expected[i], format!("{:?}", index.read_message(&mut source, entry)?));
+        }
+        assert_eq!(expected, debug_strings(&index.read_messages(&mut source, index.entries())?));
+        let events: Vec<&FitIndexEntry> = index.entries().iter().filter(|x| x.global_message_number == Event::MESG_NUM).collect();
+        assert_eq!(16, events.len());
+        assert_eq!(Some(1023), events[0].timestamp);
+        assert_eq!(format!("{:?}", index.read_message(&mut source, events[9])?), debug_strings(&index.read_messages(&mut source, events)?)[9]);
+
+        let range = index.time_range(1100, 1200);
+        assert_eq!(12, range.len());
+        assert_eq!(Some(1100), range[0].timestamp);
+        assert!(range.iter().all(|x| (1100..1200).contains(&x.timestamp.unwrap())));
+        let mesgs = index.read_messages(&mut source, range)?;
+        let first = index.entries().iter().position(|x| x.offset == range[0].offset).unwrap();
+        assert_eq!(expected[first..first + 12], debug_strings(&mesgs)[..]);
+        assert!(index.time_range(2000, 3000).is_empty());
+
+        // The index does not match other data.
+        let mut other = data.clone();
+        let entry = &index.entries()[5];
+        let (local, _) = entry.definitions().iter()
+            .find(|(_, x)| x.global_message_number != entry.global_message_number).unwrap();
+        other[entry.offset as usize] = *local;
+        let e = index.read_message(&mut Cursor::new(&other), entry).unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::IndexMismatch));
+        assert_eq!(Some(entry.offset), e.offset);
+
+        let last = data.len() - 1;
+        data[last] ^= 0xFF;
+        let e = FitIndex::build(data.as_slice()).unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::FileCrc{ .. }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_accumulated_fields() -> FitResult<()> {
+        // cycles rolls over at 256 and accumulates into total_cycles.
+        let mut writer = FitWriter::new(Vec::new(), FitFileHeader{ header_size: 14, ..Default::default() });
+        for i in 0..20u32 {
+            let record = Record{ timestamp: Some(1000 + i), cycles: Some((i * 50 % 256) as u8), ..Default::default() };
+            writer.write_message(FitDataMessage::from(&record)).unwrap();
+        }
+        writer.finish().unwrap();
+        let data = writer.into_inner();
+        let expected = debug_strings(&read_data_messages(&data));
+
+        let index = FitIndex::build(data.as_slice())?;
+        let mut source = Cursor::new(&data);
+        let last = index.read_message(&mut source, index.entries().last().unwrap())?;
+        assert_eq!(Some(950), Record::try_from(&last).unwrap().total_cycles);
+        for (i, entry) in index.entries().iter().enumerate().rev() {
+            assert_eq!(expected[i], format!("{:?}", index.read_message(&mut source, entry)?));
+        }
+        let range = index.time_range(1010, 1015);
+        assert_eq!(expected[10..15], debug_strings(&index.read_messages(&mut source, range)?)[..]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_developer_data() -> FitResult<()> {
+        let data = get_developer_data_fit();
+        let expected = debug_strings(&read_data_messages(&data));
+        let index = FitIndex::build(data.as_slice())?;
+        let mut source = Cursor::new(&data);
+        let last = index.entries().last().unwrap();
+        let mesg = index.read_message(&mut source, last)?;
+        assert_eq!(expected.last().unwrap(), &format!("{:?}", mesg));
+        assert_eq!("doughnuts_earned", mesg.dev_fields[0].description.as_ref().unwrap().field_name);
+        Ok(())
+    }
+}
@@ -0,0 +1,114 @@
+// Calls listeners with the data messages of chosen types, like the FIT SDK's message broadcaster.
+
+use std::collections::{HashMap, HashSet};
+use std::io::Read;
+
+use crate::fiterror::FitResult;
+use crate::fitfile::FitReader;
+use crate::fittypes::{FitDataMessage, FitRecord};
+
+/// Dispatches data messages to the listeners for their global message number. When reading, the
+/// messages that nobody listens to are skipped without decoding them, so that e.g. pulling the
+/// session summaries out of many files is cheap.
+///
+/// let mut sessions = Vec::new();
+/// let mut dispatcher = FitDispatcher::new();
+/// dispatcher.add_listener(Session::MESG_NUM, |mesg| sessions.extend(Session::try_from(mesg).ok()));
+/// dispatcher.read(&mut FitReader::open(path)?)?;
+#[derive(Default)]
+pub struct FitDispatcher<'a> {
+    listeners: HashMap<u16, Vec<Listener<'a>>>,
+}
+
+type Listener<'a> = Box<dyn FnMut(&FitDataMessage) + 'a>;
+
+impl<'a> FitDispatcher<'a> {
+    pub fn new() -> FitDispatcher<'a> {
+        Default::default()
+    }
+
+    /// Call the listener with each data message of the global message number, in the order read.
+    pub fn add_listener<F: FnMut(&FitDataMessage) + 'a>(&mut self, global_message_number: u16, listener: F) {
+        self.listeners.entry(global_message_number).or_default().push(Box::new(listener));
+    }
+
+    pub fn listens_to(&self, global_message_number: u16) -> bool {
+        self.listeners.contains_key(&global_message_number)
+    }
+
+    /// Call the listeners for the message, if there are any.
+    pub fn dispatch(&mut self, mesg: &FitDataMessage) {
+        if let Some(listeners) = self.listeners.get_mut(&mesg.global_message_number) {
+            for listener in listeners {
+                listener(mesg);
+            }
+        }
+    }
+
+    /// Read to the end, including chained files, and dispatch the data messages. This sets the
+    /// message filter of the reader, so that other messages are skipped. Returns the number of
+    /// files read.
+    pub fn read<R: Read>(&mut self, reader: &mut FitReader<R>) -> FitResult<u32> {
+        let wanted: HashSet<u16> = self.listeners.keys().copied().collect();
+        reader.set_message_filter(move |x| wanted.contains(&x));
+        for rec in reader.by_ref() {
+            if let FitRecord::DataRecord(mesg) = rec? {
+                self.dispatch(&mesg);
+            }
+        }
+        Ok(reader.files_read())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitmessages::{Event, Record, Session};
+    use crate::fittest::{debug_strings, get_developer_data_fit, make_activity_fit, read_data_messages};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_dispatch() -> FitResult<()> {
+        let mut data = make_activity_fit();
+        data.extend(make_activity_fit());
+        let all = read_data_messages(&data);
+
+        // Each message type on its own reads the same as when everything is decoded, including
+        // the timestamps of the compressed messages after skipped ones.
+        for global_message_number in [Record::MESG_NUM, Event::MESG_NUM, Session::MESG_NUM] {
+            let mut mesgs = Vec::new();
+            let mut dispatcher = FitDispatcher::new();
+            dispatcher.add_listener(global_message_number, |x| mesgs.push(format!("{:?}", x)));
+            assert_eq!(2, dispatcher.read(&mut FitReader::new(data.as_slice()))?);
+            drop(dispatcher);
+            assert_eq!(debug_strings(all.iter().filter(|x| x.global_message_number == global_message_number)), mesgs);
+        }
+
+        let mut sessions = Vec::new();
+        let mut event_count = 0;
+        let mut dispatcher = FitDispatcher::new();
+        dispatcher.add_listener(Session::MESG_NUM, |x| sessions.extend(Session::try_from(x).ok()));
+        dispatcher.add_listener(Event::MESG_NUM, |_| event_count += 1);
+        assert!(!dispatcher.listens_to(Record::MESG_NUM));
+        dispatcher.read(&mut FitReader::new(data.as_slice()))?;
+        drop(dispatcher);
+        assert_eq!(16, event_count);
+        assert_eq!(2, sessions.len());
+        assert_eq!(Some(400.0), sessions[0].total_elapsed_time);
+        Ok(())
+    }
+
+    #[test]
+    fn test_developer_data_is_read() -> FitResult<()> {
+        let data = get_developer_data_fit();
+        let mut names = Vec::new();
+        let mut dispatcher = FitDispatcher::new();
+        dispatcher.add_listener(Record::MESG_NUM, |x| names.extend(
+            x.dev_fields.iter().filter_map(|f| f.description.as_ref().map(|d| d.field_name.clone()))));
+        dispatcher.read(&mut FitReader::new(data.as_slice()))?;
+        drop(dispatcher);
+        assert_eq!(3, names.len());
+        assert!(names.iter().all(|x| x == "doughnuts_earned"));
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+// Typed structs for the messages of the profile, generated from messages.json by build.rs.
+//
+// Each message has a struct with one Option per field, None if the field is missing or invalid.
+// Fields with a scale, offset or in semicircles hold physical values as f64, others hold the raw
+// value. Fields of a profile type are enums from fitenums. A subfield is set as well as the main
+// field when the message's reference fields select it, e.g. workout_step.duration_time when the
+// duration_type is time. When writing, a subfield is used if the main field is not set.
+//
+// let record = Record::try_from(&data_message)?;
+// let data_message = FitDataMessage::from(&record);
+
+use std::convert::TryFrom;
+
+use crate::fitenums;
+use crate::fitconvert::{FitConversion, field_data_to_physical, physical_to_field_data};
+use crate::fittypes::{FitDataField, FitDataMessage, FitDataType, FitFieldData};
+
+/// The data of a valid field, including fields expanded from components.
+fn field_data(mesg: &FitDataMessage, field_defn_num: u8) -> Option<&FitFieldData> {
+    mesg.fields.iter().chain(mesg.expanded_fields.iter())
+        .find(|x| x.field_defn_num == field_defn_num && x.data.is_valid())
+        .map(|x| &x.data)
+}
+
+fn physical(mesg: &FitDataMessage, field_defn_num: u8, conversion: &FitConversion) -> Option<f64> {
+    field_data_to_physical(conversion, field_data(mesg, field_defn_num)?)?.first().copied().flatten()
+}
+
+fn physical_array(mesg: &FitDataMessage, field_defn_num: u8, conversion: &FitConversion) -> Option<Vec<Option<f64>>> {
+    field_data_to_physical(conversion, field_data(mesg, field_defn_num)?)
+}
+
+fn raw(mesg: &FitDataMessage, field_defn_num: u8) -> Option<u64> {
+    let data = field_data(mesg, field_defn_num)?;
+    if data.is_valid_at(0) { data.first_integer() } else { None }
+}
+
+fn raw_array(mesg: &FitDataMessage, field_defn_num: u8) -> Option<Vec<Option<u64>>> {
+    let data = field_data(mesg, field_defn_num)?;
+    Some(data.integer_values()?.into_iter().zip(data.valid_mask())
+        .map(|(v, valid)| if valid { Some(v) } else { None })
+        .collect())
+}
+
+/// Whether the reference field of a subfield has one of the values.
+fn refers_to(mesg: &FitDataMessage, field_defn_num: u8, values: &[u64]) -> bool {
+    raw(mesg, field_defn_num).is_some_and(|x| values.contains(&x))
+}
+
+fn string(mesg: &FitDataMessage, field_defn_num: u8) -> Option<String> {
+    match field_data(mesg, field_defn_num)? {
+        FitFieldData::FitString(x, _) => Some(x.clone()),
+        _ => None,
+    }
+}
+
+fn bytes(mesg: &FitDataMessage, field_defn_num: u8) -> Option<Vec<u8>> {
+    Some(field_data(mesg, field_defn_num)?.integer_values()?.into_iter().map(|v| v as u8).collect())
+}
+
+fn from_physical(field_defn_num: u8, conversion: &FitConversion, data_type: FitDataType, values: &[Option<f64>])
+    -> FitDataField
+{
+    FitDataField{ field_defn_num, data: physical_to_field_data(conversion, data_type, values) }
+}
+
+fn from_raw(field_defn_num: u8, data_type: FitDataType, values: &[Option<u64>]) -> FitDataField {
+    let values: Vec<u64> = values.iter().map(|v| v.unwrap_or_else(|| data_type.invalid_value())).collect();
+    FitDataField{ field_defn_num, data: FitFieldData::from_integer_values(data_type, &values) }
+}
+
+fn from_string(field_defn_num: u8, value: &str) -> FitDataField {
+    FitDataField{ field_defn_num, data: FitFieldData::from_string(value) }
+}
+
+fn from_bytes(field_defn_num: u8, value: &[u8]) -> FitDataField {
+    FitDataField{ field_defn_num, data: FitFieldData::FitByte(value.into()) }
+}
+
+include!(concat!(env!("OUT_DIR"), "/messages.rs"));
+
+#[cfg(test)]
+mod tests {
+    // Note this useful idiom: importing names from outer (for mod tests) scope.
+    use super::*;
+    use crate::fiterror::FitResult;
+    use crate::fitfile::{FitReader, FitWriter};
+    use crate::fittypes::{FitFileHeader, FitRecord};
+
+    fn assert_close(expected: f64, actual: f64) {
+        assert!((expected - actual).abs() < 1e-9, "Expected {} got {}", expected, actual);
+    }
+
+    #[test]
+    fn test_record() {
+        let mesg = FitDataMessage {
+            global_message_number: 20,
+            fields: vec![
+                FitDataField { field_defn_num: 253, data: FitFieldData::FitUint32(smallvec![1000]) },
+                FitDataField { field_defn_num: 0, data: FitFieldData::FitSint32(smallvec![1073741824]) },
+                FitDataField { field_defn_num: 2, data: FitFieldData::FitUint16(smallvec![3891]) },
+                FitDataField { field_defn_num: 3, data: FitFieldData::FitUint8(smallvec![0xFF]) },
+                FitDataField { field_defn_num: 13, data: FitFieldData::FitSint8(smallvec![-5]) },
+                FitDataField { field_defn_num: 17, data: FitFieldData::FitUint8(smallvec![32, 0xFF]) },
+            ],
+            ..Default::default()
+        };
+        let record = Record::try_from(&mesg).unwrap();
+        assert_eq!(Some(1000), record.timestamp);
+        assert_close(90.0, record.position_lat.unwrap());
+        assert_close(278.2, record.altitude.unwrap());
+        assert_eq!(None, record.heart_rate);
+        assert_eq!(Some(-5), record.temperature);
+        assert_eq!(Some(vec![Some(2.0), None]), record.speed_1s);
+        assert_eq!(None, record.position_long);
+
+        assert!(Session::try_from(&mesg).is_err());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let record = Record {
+            timestamp: Some(1000),
+            position_long: Some(-73.14859078265727),
+            altitude: Some(278.2),
+            heart_rate: Some(150),
+            speed_1s: Some(vec![None, Some(1.5)]),
+            ..Default::default()
+        };
+        let mesg = FitDataMessage::from(&record);
+        assert_eq!(Record::MESG_NUM, mesg.global_message_number);
+        assert_eq!(5, mesg.fields.len());
+        let altitude = mesg.fields.iter().find(|x| x.field_defn_num == 2).unwrap();
+        assert!(matches!(&altitude.data, FitFieldData::FitUint16(x) if x[..] == [3891]));
+
+        let decoded = Record::try_from(&mesg).unwrap();
+        assert_eq!(record.timestamp, decoded.timestamp);
+        assert_eq!(record.heart_rate, decoded.heart_rate);
+        assert_eq!(record.speed_1s, decoded.speed_1s);
+        assert_close(record.altitude.unwrap(), decoded.altitude.unwrap());
+        assert!((record.position_long.unwrap() - decoded.position_long.unwrap()).abs() < 1e-7);
+
+        let file_id = FileId {
+            r#type: Some(fitenums::File::Activity),
+            manufacturer: Some(fitenums::Manufacturer::Garmin),
+            product_name: Some("Edge".to_string()),
+            ..Default::default()
+        };
+        let mesg = FitDataMessage::from(&file_id);
+        assert!(matches!(&mesg.fields[0].data, FitFieldData::FitEnum(x) if x[..] == [4]));
+        assert!(matches!(&mesg.fields[1].data, FitFieldData::FitUint16(x) if x[..] == [1]));
+        assert!(matches!(&mesg.fields[2].data, FitFieldData::FitString(x, 5) if x == "Edge"));
+        assert_eq!(file_id, FileId::try_from(&mesg).unwrap());
+    }
+
+    #[test]
+    fn test_long_string() -> FitResult<()> {
+        // 300 bytes of two byte characters, cut to 254 bytes so that the terminator fits.
+        let file_id = FileId{ product_name: Some("é".repeat(150)), ..Default::default() };
+        let mut writer = FitWriter::new(Vec::new(), FitFileHeader{ header_size: 14, ..Default::default() });
+        writer.write_message(FitDataMessage::from(&file_id))?;
+        writer.finish()?;
+        let mesg = FitReader::new(writer.target().as_slice()).find_map(|x| match x {
+            Ok(FitRecord::DataRecord(m)) => Some(m),
+            _ => None,
+        }).unwrap();
+        assert_eq!(Some("é".repeat(127)), FileId::try_from(&mesg).unwrap().product_name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_bitfield() {
+        let file_capabilities = FileCapabilities {
+            r#type: Some(fitenums::File::Activity),
+            flags: Some(fitenums::FileFlags::READ | fitenums::FileFlags::ERASE),
+            ..Default::default()
+        };
+        let mesg = FitDataMessage::from(&file_capabilities);
+        assert!(matches!(&mesg.fields[1].data, FitFieldData::FitU8z(x) if x[..] == [10]));
+        let decoded = FileCapabilities::try_from(&mesg).unwrap();
+        assert_eq!(vec!["read", "erase"], decoded.flags.unwrap().names());
+    }
+
+    #[test]
+    fn test_subfields() {
+        let step = WorkoutStep {
+            duration_type: Some(fitenums::WktStepDuration::Time),
+            duration_time: Some(90.5),
+            ..Default::default()
+        };
+        let mesg = FitDataMessage::from(&step);
+        assert!(matches!(&mesg.fields[1].data, FitFieldData::FitUint32(x) if x[..] == [90500]));
+        let decoded = WorkoutStep::try_from(&mesg).unwrap();
+        assert_eq!(Some(90500), decoded.duration_value);
+        assert_close(90.5, decoded.duration_time.unwrap());
+        assert_eq!(None, decoded.duration_distance);
+
+        // The main field is written rather than a subfield.
+        let step = WorkoutStep { duration_distance: Some(1000.0), ..decoded };
+        let decoded = WorkoutStep::try_from(&FitDataMessage::from(&step)).unwrap();
+        assert_close(90.5, decoded.duration_time.unwrap());
+
+        let device_info = DeviceInfo {
+            manufacturer: Some(fitenums::Manufacturer::Garmin),
+            product: Some(1),
+            ..Default::default()
+        };
+        let decoded = DeviceInfo::try_from(&FitDataMessage::from(&device_info)).unwrap();
+        assert_eq!(Some(fitenums::GarminProduct::Hrm1), decoded.garmin_product);
+        assert_eq!(Some(1), decoded.product);
+
+        let device_info = DeviceInfo { manufacturer: Some(fitenums::Manufacturer::Development), ..device_info };
+        let decoded = DeviceInfo::try_from(&FitDataMessage::from(&device_info)).unwrap();
+        assert_eq!(None, decoded.garmin_product);
+        assert_eq!(Some(1), decoded.product);
+    }
+}
@@ -2,9 +2,19 @@ use std::io::Read;
 
 use byteorder::{LittleEndian, BigEndian,  ReadBytesExt};
 
+use crate::fiterror::{FitErrorKind, FitResult};
 use crate::fittypes::{Endianness, FitFileContext};
 
-pub fn fit_read_u8(context: &mut FitFileContext, reader: &mut dyn Read) -> Result<u8, std::io::Error> {
+/// Read len bytes in one go, e.g. all the fields of a data message.
+pub fn fit_read_bytes(context: &mut FitFileContext, reader: &mut dyn Read, len: usize) -> FitResult<Vec<u8>> {
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    context.data_bytes_read += len as u32;
+    context.crc.consume(&buf);
+    Ok(buf)
+}
+
+pub fn fit_read_u8(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<u8> {
     let byte = reader.read_u8()?;
     context.data_bytes_read = context.data_bytes_read + 1;
     context.crc.consume(&[byte]);
@@ -12,14 +22,14 @@ pub fn fit_read_u8(context: &mut FitFileContext, reader: &mut dyn Read) -> Resul
 }
 
 
-pub fn fit_read_i8(context: &mut FitFileContext, reader: &mut dyn Read) -> Result<i8, std::io::Error> {
+pub fn fit_read_i8(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<i8> {
     let byte = reader.read_u8()?;
     context.data_bytes_read = context.data_bytes_read + 1;
     context.crc.consume(&[byte]);
     return Ok(byte as i8);
 }
 
-pub fn fit_read_u16(context: &mut FitFileContext, reader: &mut dyn Read) -> Result<u16, std::io::Error> {
+pub fn fit_read_u16(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<u16> {
 
     let mut buf: [u8; 2] = [0; 2];
     reader.read_exact(&mut buf)?;
@@ -28,7 +38,7 @@ pub fn fit_read_u16(context: &mut FitFileContext, reader: &mut dyn Read) -> Resu
     let v = match context.architecture {
         Some(Endianness::Little) => rdr.read_u16::<LittleEndian>()?,
         Some(Endianness::Big) => rdr.read_u16::<BigEndian>()?,
-        None => return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None => return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_read = context.data_bytes_read + 2;
     context.crc.consume(&buf);
@@ -36,7 +46,7 @@ pub fn fit_read_u16(context: &mut FitFileContext, reader: &mut dyn Read) -> Resu
 }
 
 
-pub fn fit_read_i16(context: &mut FitFileContext, reader: &mut dyn Read) -> Result<i16, std::io::Error> {
+pub fn fit_read_i16(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<i16> {
 
     let mut buf: [u8; 2] = [0; 2];
     reader.read_exact(&mut buf)?;
@@ -45,14 +55,14 @@ pub fn fit_read_i16(context: &mut FitFileContext, reader: &mut dyn Read) -> Resu
     let v = match context.architecture {
         Some(Endianness::Little) => rdr.read_i16::<LittleEndian>()?,
         Some(Endianness::Big) => rdr.read_i16::<BigEndian>()?,
-        None => return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None => return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_read = context.data_bytes_read + 2;
     context.crc.consume(& buf);
     return Ok(v);
 }
 
-pub fn fit_read_u32(context: &mut FitFileContext, reader: &mut dyn Read) -> Result<u32, std::io::Error> {
+pub fn fit_read_u32(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<u32> {
 
     let mut buf: [u8; 4] = [0; 4];
     reader.read_exact(&mut buf)?;
@@ -61,14 +71,14 @@ pub fn fit_read_u32(context: &mut FitFileContext, reader: &mut dyn Read) -> Resu
     let v = match context.architecture {
         Some(Endianness::Little) => rdr.read_u32::<LittleEndian>()?,
         Some(Endianness::Big) => rdr.read_u32::<BigEndian>()?,
-        None => return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None => return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_read = context.data_bytes_read + 4;
     context.crc.consume(& buf);
     return Ok(v);
 }
 
-pub fn fit_read_i32(context: &mut FitFileContext, reader: &mut dyn Read) -> Result<i32, std::io::Error> {
+pub fn fit_read_i32(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<i32> {
 
     let mut buf: [u8; 4] = [0; 4];
     reader.read_exact(&mut buf)?;
@@ -77,14 +87,14 @@ pub fn fit_read_i32(context: &mut FitFileContext, reader: &mut dyn Read) -> Resu
     let v = match context.architecture {
         Some(Endianness::Little) => rdr.read_i32::<LittleEndian>()?,
         Some(Endianness::Big) => rdr.read_i32::<BigEndian>()?,
-        None => return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None => return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_read = context.data_bytes_read + 4;
     context.crc.consume(& buf);
     return Ok(v);
 }
 
-pub fn fit_read_u64(context: &mut FitFileContext, reader: &mut dyn Read) -> Result<u64, std::io::Error> {
+pub fn fit_read_u64(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<u64> {
 
     let mut buf: [u8; 8] = [0; 8];
     reader.read_exact(&mut buf)?;
@@ -93,7 +103,7 @@ pub fn fit_read_u64(context: &mut FitFileContext, reader: &mut dyn Read) -> Resu
     let v = match context.architecture {
         Some(Endianness::Little) => rdr.read_u64::<LittleEndian>()?,
         Some(Endianness::Big) => rdr.read_u64::<BigEndian>()?,
-        None => return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None => return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_read = context.data_bytes_read + 8;
     context.crc.consume(& buf);
@@ -102,7 +112,7 @@ pub fn fit_read_u64(context: &mut FitFileContext, reader: &mut dyn Read) -> Resu
 
 
 
-pub fn fit_read_i64(context: &mut FitFileContext, reader: &mut dyn Read) -> Result<i64, std::io::Error> {
+pub fn fit_read_i64(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<i64> {
 
     let mut buf: [u8; 8] = [0; 8];
     reader.read_exact(&mut buf)?;
@@ -111,14 +121,14 @@ pub fn fit_read_i64(context: &mut FitFileContext, reader: &mut dyn Read) -> Resu
     let v = match context.architecture {
         Some(Endianness::Little) => rdr.read_i64::<LittleEndian>()?,
         Some(Endianness::Big) => rdr.read_i64::<BigEndian>()?,
-        None => return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None => return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_read = context.data_bytes_read + 8;
     context.crc.consume(& buf);
     return Ok(v);
 }
 
-pub fn fit_read_f32(context: &mut FitFileContext, reader: &mut dyn Read) -> Result<f32, std::io::Error> {
+pub fn fit_read_f32(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<f32> {
 
     let mut buf: [u8; 4] = [0; 4];
     reader.read_exact(&mut buf)?;
@@ -127,14 +137,14 @@ pub fn fit_read_f32(context: &mut FitFileContext, reader: &mut dyn Read) -> Resu
     let v = match context.architecture {
         Some(Endianness::Little) => rdr.read_f32::<LittleEndian>()?,
         Some(Endianness::Big) => rdr.read_f32::<BigEndian>()?,
-        None => return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None => return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_read = context.data_bytes_read + 4;
     context.crc.consume(& buf);
     return Ok(v);
 }
 
-pub fn fit_read_f64(context: &mut FitFileContext, reader: &mut dyn Read) -> Result<f64, std::io::Error> {
+pub fn fit_read_f64(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<f64> {
 
     let mut buf: [u8; 8] = [0; 8];
     reader.read_exact(&mut buf)?;
@@ -143,7 +153,7 @@ pub fn fit_read_f64(context: &mut FitFileContext, reader: &mut dyn Read) -> Resu
     let v = match context.architecture {
         Some(Endianness::Little) => rdr.read_f64::<LittleEndian>()?,
         Some(Endianness::Big) => rdr.read_f64::<BigEndian>()?,
-        None => return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None => return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_read = context.data_bytes_read + 8;
     context.crc.consume(& buf);
@@ -152,7 +162,7 @@ pub fn fit_read_f64(context: &mut FitFileContext, reader: &mut dyn Read) -> Resu
 
 
 // From UTF-8 encoded binary string, null-terminated.
-pub fn fit_read_string(context: &mut FitFileContext, reader: &mut dyn Read, width: &u8) -> Result<String, std::io::Error> {
+pub fn fit_read_string(context: &mut FitFileContext, reader: &mut dyn Read, width: &u8) -> FitResult<String> {
 
     let mut buf: Vec<u8> = Vec::new();
     let len = *width as usize;
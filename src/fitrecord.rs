@@ -4,15 +4,30 @@ use std::io::{Read, Write};
 use serde_json::{Value, Map};
 
 use crate::fittypes::{Endianness, FitFileContext, FitFieldData, FitRecord, INVALID_U32, base_datetime};
+use crate::fiterror::{FitErrorKind, FitResult};
 use crate::fitread::{fit_read_u8};
 
 use crate::profile::ProfileData;
+use crate::fitconvert::FitConversion;
 use crate::fitheader;
 use crate::fitdatamesg;
 use crate::fitdefnmesg;
 
 use byteorder::{LittleEndian, WriteBytesExt};
 
+/// How invalid values are shown when records are printed.
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub enum InvalidValues {
+    #[default]
+    Null,  // Shown as null.
+    Omit,  // Fields without any valid value are left out, invalid array elements are null.
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct DumpOptions {
+    pub invalid_values: InvalidValues,
+}
+
 fn convert_timestamp(x: Value) -> Value {
     match &x {
         Value::Number(v) => {
@@ -43,6 +58,13 @@ fn handle_fit_enum_value( x: Value, type_name: &str, p: &ProfileData )-> Value{
         Value::Number(v) => {
 
             if let Some(field_value) = v.as_u64() {
+                if let Some((names, remainder)) = p.flag_names(type_name, field_value as u32) {
+                    let mut flags: Vec<Value> = names.into_iter().map(Value::from).collect();
+                    if remainder != 0 {
+                        flags.push(Value::from(remainder));
+                    }
+                    return Value::from(flags);
+                }
                 match p.value_name(type_name, field_value as u32) {
                     None => { x },
                     Some(str) => { Value::from(str) },
@@ -62,8 +84,8 @@ fn handle_fit_enum_value( x: Value, type_name: &str, p: &ProfileData )-> Value{
     }
 }
 
-fn handle_fit_scale_offset( x: Value, scale: &Option<f64>, offset: &Option<f64> )-> Value{
-    if scale.is_none() && offset.is_none() {
+fn handle_fit_conversion( x: Value, conversion: &FitConversion )-> Value{
+    if conversion.is_identity() {
         return x;
     }
 
@@ -72,15 +94,7 @@ fn handle_fit_scale_offset( x: Value, scale: &Option<f64>, offset: &Option<f64>
         Value::Number(v) => {
 
             if let Some(field_value) = v.as_f64() {
-                let mut value_copy = field_value;
-                if let Some(offset_f) = offset {
-                    value_copy = value_copy - *offset_f;
-                }
-                if let Some(scale_f) = scale {
-                    value_copy = value_copy / *scale_f;
-                }
-
-                Value::from(value_copy)
+                Value::from(conversion.to_physical(field_value))
             } else {
                 Value::Number(v)
             }
@@ -88,7 +102,7 @@ fn handle_fit_scale_offset( x: Value, scale: &Option<f64>, offset: &Option<f64>
         Value::Array(xa) => {
             let mut ret = Vec::new();
             for v in xa {
-                ret.push(handle_fit_scale_offset(v, scale, offset));
+                ret.push(handle_fit_conversion(v, conversion));
             }
             Value::from(ret)
         },
@@ -96,16 +110,8 @@ fn handle_fit_scale_offset( x: Value, scale: &Option<f64>, offset: &Option<f64>
     }
 }
 
-fn handle_fit_units( x: Value, units: &str )-> Value {
-    if units == "semicircles" {
-        handle_fit_scale_offset(x, &Some(1.0e7), &None)
-    } else {
-        x
-    }
-}
-
 
-fn handle_fit_value<T: Clone>(x: &Vec<T>) -> Value
+fn handle_fit_value<T: Clone>(x: &[T]) -> Value
     where Value: std::convert::From<T> + std::convert::From< Vec<T> >
 {
     if x.is_empty() {
@@ -113,7 +119,7 @@ fn handle_fit_value<T: Clone>(x: &Vec<T>) -> Value
     } else if x.len() == 1 {
         return Value::from(x[0].clone() );
     } else {
-        return (x.clone()).into();
+        return x.to_vec().into();
     }
 }
 
@@ -138,7 +144,21 @@ fn field_to_value(field_data: &FitFieldData) -> Value {
         FitFieldData::FitUint64z(x) => handle_fit_value(x),
     }
 }
-fn to_json(rec: &FitRecord, pf: &ProfileData) -> (String, Value){
+
+/// The value of a field with invalid values replaced by null.
+fn valid_field_to_value(field_data: &FitFieldData) -> Value {
+    let mask = field_data.valid_mask();
+    match field_to_value(field_data) {
+        Value::Array(xa) => {
+            Value::from(xa.into_iter().zip(mask)
+                .map(|(v, valid)| if valid { v } else { Value::Null })
+                .collect::<Vec<Value>>())
+        },
+        x => if field_data.is_valid() { x } else { Value::Null },
+    }
+}
+
+fn to_json(rec: &FitRecord, pf: &ProfileData, options: &DumpOptions) -> (String, Value){
     match rec {
         FitRecord::HeaderRecord(header) => {
             let mut map = Map::new();
@@ -158,42 +178,40 @@ fn to_json(rec: &FitRecord, pf: &ProfileData) -> (String, Value){
             let message = pf.get_message(data_message.global_message_number);
             let mut field_vec: Vec<Value> = vec!();
             let mut fields = Map::new();
-            for ifield in &data_message.fields {
+            for ifield in data_message.fields.iter().chain(data_message.expanded_fields.iter()) {
                 let field_name: String;
                 let mut field_units = None;
                 let mut field_desc = None;
                 let mut field_type = None;
-                let mut field_scale = None;
-                let mut field_offset = None;
+                let mut conversion: FitConversion = Default::default();
                 if message.is_some() {
-                    field_desc = message.unwrap().find_field(ifield.field_defn_num);
+                    field_desc = message.unwrap().resolve_field(pf, ifield.field_defn_num, data_message);
                 }
                 if field_desc.is_some() {
                     field_name = field_desc.unwrap().field_name.clone();
                     field_units = field_desc.unwrap().units.clone();
                     field_type = Some(field_desc.unwrap().field_type.clone());
-                    field_scale = field_desc.unwrap().scale.clone();
-                    field_offset = field_desc.unwrap().offset.clone();
+                    conversion = FitConversion::from_field(field_desc.unwrap());
                 } else {
                     let field_string = format!("Field_{}", ifield.field_defn_num);
                     field_name = field_string;
                 }
-                let mut value = field_to_value( &ifield.data );
+                if options.invalid_values == InvalidValues::Omit && !ifield.data.is_valid() {
+                    continue;
+                }
+                let mut value = valid_field_to_value( &ifield.data );
 
                 if let Some(ft) = field_type {
                     value = handle_fit_enum_value(value, &ft, pf)
                 }
-                value = handle_fit_scale_offset(value,  &field_scale, &field_offset );
+                value = handle_fit_conversion(value, &conversion);
 
-                if let Some(field_units_str) = &field_units {
-                    value = handle_fit_units(value, field_units_str);
-                }
                 if short_form {
                     fields.insert(field_name, value);
                 } else {
                     let mut field_map = Map::new();
                     field_map.insert("name".to_string(), Value::from(field_name));
-                    if let Some(field_units_str) = field_units {
+                    if let Some(field_units_str) = conversion.units(field_units.as_deref()) {
                         field_map.insert("units".to_string(), Value::from(field_units_str));
                     }
                     field_map.insert("value".to_string(), value);
@@ -205,36 +223,39 @@ fn to_json(rec: &FitRecord, pf: &ProfileData) -> (String, Value){
             for ifield in &data_message.dev_fields {
                 let field_name;
                 let field_units;
-                let field_scale;
-                let field_offset;
+                let conversion;
+                let application_id;
                 if let Some(desc) = &ifield.description {
                     field_name = desc.field_name.clone();
                     field_units = desc.units.clone();
-                    field_scale = desc.scale;
-                    field_offset = desc.offset;
+                    conversion = FitConversion::from_dev_field(desc);
+                    application_id = desc.application_id;
                 } else {
                     field_name = format!("unknown_developer_field_{}",ifield.field_defn_num);
                     field_units = None;
-                    field_scale = None;
-                    field_offset = None;
+                    conversion = Default::default();
+                    application_id = None;
                 }
 
-                let mut value  = field_to_value( &ifield.data );
+                if options.invalid_values == InvalidValues::Omit && !ifield.data.is_valid() {
+                    continue;
+                }
+                let mut value  = valid_field_to_value( &ifield.data );
 
-                value = handle_fit_scale_offset(value,  &field_scale, &field_offset );
+                value = handle_fit_conversion(value, &conversion);
 
-                if let Some(field_units_str) = &field_units {
-                    value = handle_fit_units(value, field_units_str);
-                }
                 if short_form {
                     fields.insert(field_name, value);
                 } else {
                     let mut field_map = Map::new();
                     field_map.insert("name".to_string(), Value::from(field_name));
-                    if let Some(field_units_str) = field_units {
+                    if let Some(field_units_str) = conversion.units(field_units.as_deref()) {
                         field_map.insert("units".to_string(), Value::from(field_units_str));
                     }
                     field_map.insert("value".to_string(), value);
+                    if let Some(x) = application_id {
+                        field_map.insert("application_id".to_string(), Value::from(x.to_string()));
+                    }
 
                     field_vec.push(Value::from(field_map));
                 }
@@ -314,56 +335,123 @@ fn to_json(rec: &FitRecord, pf: &ProfileData) -> (String, Value){
 }
 
 pub fn print_rec(rec: &FitRecord, pf: &ProfileData) {
-    let (name, value) = to_json(rec, pf);
+    print_rec_with_options(rec, pf, &Default::default());
+}
+
+pub fn print_rec_with_options(rec: &FitRecord, pf: &ProfileData, options: &DumpOptions) {
+    let (name, value) = to_json(rec, pf, options);
     println!("{}: {}", name, value);
 }
 
-pub fn read_record(context: &mut FitFileContext, reader: &mut dyn Read) -> Result< FitRecord, std::io::Error> {
-    let record_hdr = fit_read_u8(context, reader)?;
+/// Read the next record, errors give the offset and index of the record.
+pub fn read_record(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<FitRecord> {
+    let rec = read_record_if(context, reader, &|_| true)?;
+    Ok(rec.expect("only unwanted messages are skipped"))
+}
+
+/// Read the next record, but skip data messages that are not wanted without decoding them,
+/// returning None. Developer data messages are always read, as later messages need them.
+pub fn read_record_if(context: &mut FitFileContext, reader: &mut dyn Read, wanted: &dyn Fn(u16) -> bool)
+                      -> FitResult<Option<FitRecord>> {
+    at_record(context, |context| read_record_at(context, reader, wanted))
+}
+
+/// Read a record with f, giving errors and warnings the offset and index of the record.
+pub(crate) fn at_record<T, F>(context: &mut FitFileContext, f: F) -> FitResult<T>
+    where F: FnOnce(&mut FitFileContext) -> FitResult<T>
+{
+    let offset = context.file_offset();
+    let record_index = context.records_read;
+    let warnings = context.warnings.len();
+    let rec = f(context).map_err(|e| e.at(offset, record_index))?;
+    for w in &mut context.warnings[warnings..] {
+        w.offset.get_or_insert(offset);
+        w.record_index.get_or_insert(record_index);
+    }
+    context.records_read += 1;
+    Ok(rec)
+}
+
+/// Check the reserved bit of a record header byte.
+pub(crate) fn check_record_header(context: &mut FitFileContext, record_hdr: u8) -> FitResult<()> {
     let is_normal_header = (record_hdr & 0x80) == 0;
-    let reserve_bit = (record_hdr & 0x10) != 0;  // Bit 4 is reserved and should be zero.
+    // Bit 4 of a normal header is reserved and should be zero, in a compressed header it is part of the time offset.
+    let reserve_bit = is_normal_header && (record_hdr & 0x10) != 0;
 
     if reserve_bit {
         if context.checks.reserved_bits_zero {
-            return Err(std::io::Error::new(std::io::ErrorKind::Other, "Reserved bit is set."));
+            return Err(FitErrorKind::ReservedBitSet(record_hdr).into());
         } else {
             warn!("Reserved bit is set in header. Byte=0x{:x}",record_hdr);
+            context.warnings.push(FitErrorKind::ReservedBitSet(record_hdr).into());
         }
     }
+    Ok(())
+}
+
+/// The timestamp of a compressed timestamp header, from the offset in it and the previous timestamp.
+pub(crate) fn compressed_timestamp(prev_time_stamp: u32, record_hdr: u8) -> u32 {
+    let time_offset = (record_hdr & 0x1F) as u32;
+    if time_offset >= (prev_time_stamp & 0x1fu32) {
+        (prev_time_stamp & 0xFFFFFFE0) + time_offset
+    } else {
+        (prev_time_stamp & 0xFFFFFFE0) + time_offset+ 0x20
+    }
+}
+
+/// What a record header byte says follows.
+pub(crate) enum RecordHeader {
+    Definition{ local_message_type: u8, is_developer: bool },
+    Data{ local_message_type: u8, timestamp: Option<u32> },  // The timestamp of a compressed header.
+}
+
+pub(crate) fn read_record_header(context: &mut FitFileContext, reader: &mut dyn Read) -> FitResult<RecordHeader> {
+    let record_hdr = fit_read_u8(context, reader)?;
+    let is_normal_header = (record_hdr & 0x80) == 0;
+    check_record_header(context, record_hdr)?;
     debug!("Header: Byte=0x{:x} at Offset=0x{:x}",record_hdr, context.data_bytes_read - 1 + 14);
 
     if is_normal_header {
         let local_message_type = record_hdr & 0x0F;
         if (record_hdr & 0x40) != 0 {
-            //Definition message
             let is_developer = record_hdr & 0x20 != 0;
-            return Ok(FitRecord::DefinitionMessage(
-                fitdefnmesg::read_definition_message( context, reader, local_message_type, is_developer)?));
+            Ok(RecordHeader::Definition{ local_message_type, is_developer })
         } else {
-            // Data message
-            return Ok(FitRecord::DataRecord(
-                fitdatamesg::read_data_message( context, reader, local_message_type, None)?));
+            Ok(RecordHeader::Data{ local_message_type, timestamp: None })
         }
     } else {
         // Compressed timestamp header
         debug!("Compressed message");
         let local_message_type = (record_hdr >> 5) & 0x03;
-        let time_offset = (record_hdr & 0x1F) as u32;
+        let timestamp = Some(compressed_timestamp(context.timestamp, record_hdr));
+        Ok(RecordHeader::Data{ local_message_type, timestamp })
+    }
+}
 
-        let prev_time_stamp = context.timestamp;
-        let new_timestamp = if time_offset >= (prev_time_stamp & 0x1fu32) {
-            (prev_time_stamp & 0xFFFFFFE0) + time_offset
-        } else {
-            (prev_time_stamp & 0xFFFFFFE0) + time_offset+ 0x20
-        };
-        // Data message
-        return Ok(FitRecord::DataRecord(
-            fitdatamesg::read_data_message( context, reader, local_message_type, Some(new_timestamp))?) );
+fn read_record_at(context: &mut FitFileContext, reader: &mut dyn Read, wanted: &dyn Fn(u16) -> bool)
+                  -> FitResult<Option<FitRecord>> {
+    match read_record_header(context, reader)? {
+        RecordHeader::Definition{ local_message_type, is_developer } => Ok(Some(FitRecord::DefinitionMessage(
+            fitdefnmesg::read_definition_message( context, reader, local_message_type, is_developer)?))),
+        RecordHeader::Data{ local_message_type, timestamp } =>
+            read_data_record(context, reader, local_message_type, timestamp, wanted),
+    }
+}
+
+fn read_data_record(context: &mut FitFileContext, reader: &mut dyn Read, local_message_type: u8,
+                    timestamp: Option<u32>, wanted: &dyn Fn(u16) -> bool) -> FitResult<Option<FitRecord>> {
+    let skip = context.field_definitions.get(&local_message_type)
+        .is_some_and(|x| !fitdatamesg::is_developer_data(x.global_message_number) && !wanted(x.global_message_number));
+    if skip {
+        fitdatamesg::skip_data_message(context, reader, local_message_type, timestamp)?;
+        return Ok(None);
     }
+    Ok(Some(FitRecord::DataRecord(
+        fitdatamesg::read_data_message(context, reader, local_message_type, timestamp)?)))
 }
 
 pub fn write_record(context: &mut FitFileContext, writer: &mut dyn Write, rec: &FitRecord)
-                    -> Result< (), std::io::Error>
+                    -> FitResult<()>
 {
     match rec {
         FitRecord::HeaderRecord(header)
@@ -373,7 +461,67 @@ pub fn write_record(context: &mut FitFileContext, writer: &mut dyn Write, rec: &
         FitRecord::DataRecord(data_message) =>
             fitdatamesg::write_data_message(context, writer, data_message),
         FitRecord::EndOfFile(crc) => {
-            writer.write_u16::<LittleEndian>(*crc)
+            Ok(writer.write_u16::<LittleEndian>(*crc)?)
         }
     }
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fittypes::{FitDataField, FitDataMessage};
+    use crate::profile::build_profile;
+
+    fn record_with_invalid_values() -> FitRecord {
+        FitRecord::DataRecord(FitDataMessage {
+            global_message_number: 20,
+            fields: vec![
+                FitDataField { field_defn_num: 3, data: FitFieldData::FitUint8(smallvec![0xFF]) },  // heart_rate
+                FitDataField { field_defn_num: 2, data: FitFieldData::FitUint16(smallvec![3891]) },  // altitude
+                FitDataField { field_defn_num: 13, data: FitFieldData::FitSint8(smallvec![0x7F]) },  // temperature
+                FitDataField { field_defn_num: 17, data: FitFieldData::FitUint8(smallvec![32, 0xFF]) },  // speed_1s
+            ],
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn test_invalid_as_null() {
+        let pf = build_profile().unwrap();
+        let (_, value) = to_json(&record_with_invalid_values(), &pf, &Default::default());
+        let fields = &value["fields"];
+        assert_eq!(Value::Null, fields["heart_rate"]);
+        assert_eq!(Value::Null, fields["temperature"]);
+        assert!((fields["altitude"].as_f64().unwrap() - 278.2).abs() < 1e-9);
+        assert_eq!(Value::from(vec![Value::from(2.0), Value::Null]), fields["speed_1s"]);
+    }
+
+    #[test]
+    fn test_bitfield_names() {
+        let pf = build_profile().unwrap();
+        let rec = FitRecord::DataRecord(FitDataMessage {
+            global_message_number: 37,  // file_capabilities
+            fields: vec![
+                FitDataField { field_defn_num: 0, data: FitFieldData::FitEnum(smallvec![4]) },
+                FitDataField { field_defn_num: 1, data: FitFieldData::FitU8z(smallvec![0x16]) },
+            ],
+            ..Default::default()
+        });
+        let (_, value) = to_json(&rec, &pf, &Default::default());
+        assert_eq!(Value::from("activity"), value["fields"]["type"]);
+        assert_eq!(serde_json::json!(["read", "write", 16]), value["fields"]["flags"]);
+    }
+
+    #[test]
+    fn test_invalid_omitted() {
+        let pf = build_profile().unwrap();
+        let options = DumpOptions { invalid_values: InvalidValues::Omit };
+        let (_, value) = to_json(&record_with_invalid_values(), &pf, &options);
+        let fields = value["fields"].as_object().unwrap();
+        assert!(!fields.contains_key("heart_rate"));
+        assert!(!fields.contains_key("temperature"));
+        assert!(fields.contains_key("altitude"));
+        assert_eq!(Value::from(vec![Value::from(2.0), Value::Null]), fields["speed_1s"]);
+    }
+}
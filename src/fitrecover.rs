@@ -0,0 +1,156 @@
+// Reading damaged FIT files.
+//
+// After an error the position of the next record is unknown, so the data is scanned for
+// something that looks like a definition message and reading resumes from there. The bytes
+// skipped are reported. The data size in the header is not trusted: devices that crash while
+// recording leave it 0 or too large, in which case the data is read to the end.
+
+use std::io::{Cursor, Read};
+use std::ops::Range;
+
+use crate::fitcrc;
+use crate::fiterror::{FitError, FitResult};
+use crate::fitheader::read_global_header;
+use crate::fitrecord::read_record;
+use crate::fittypes::{FitDataType, FitFile, FitFileContext};
+use crate::profile::{self, ProfileData};
+
+/// Bytes that could not be read, and the error that caused it.
+#[derive(Debug)]
+pub struct FitLostBytes {
+    pub range: Range<u64>,  // Byte offsets in the file.
+    pub error: FitError,
+}
+
+#[derive(Debug, Default)]
+pub struct FitRecovery {
+    pub file: FitFile,
+    pub lost: Vec<FitLostBytes>,
+    pub data_size_valid: bool,  // False if the data size in the header was 0 or too large.
+    pub crc_valid: Option<bool>,  // None if there is no CRC at the end of the data.
+}
+
+impl FitRecovery {
+    /// True if the file was read without problems.
+    pub fn is_clean(&self) -> bool {
+        self.lost.is_empty() && self.data_size_valid && self.crc_valid == Some(true)
+    }
+}
+
+/// Read all of a FIT file, skipping over damaged parts.
+pub fn recover_read(source: &mut dyn Read) -> FitResult<FitRecovery> {
+    let mut data = Vec::new();
+    source.read_to_end(&mut data)?;
+    recover(&data)
+}
+
+/// Read the records of a FIT file held in memory, skipping over damaged parts.
+/// Fails only if the file header cannot be read.
+pub fn recover(data: &[u8]) -> FitResult<FitRecovery> {
+    let profile = profile::shared();
+    let mut context = FitFileContext {
+        profile: profile.clone(),
+        ..Default::default()
+    };
+    let mut recovery = FitRecovery::default();
+    recovery.file.header = read_global_header(&mut context, &mut Cursor::new(data))?;
+
+    let header_size = context.header_size as usize;
+    let expected_end = header_size + recovery.file.header.data_size as usize;
+    recovery.data_size_valid = recovery.file.header.data_size != 0 && expected_end + 2 <= data.len();
+    let data_end = if recovery.data_size_valid { expected_end } else { data.len() };
+
+    let mut pos = header_size;
+    while pos < data_end {
+        // Without a data size, the data may end with a CRC.
+        if !recovery.data_size_valid && pos + 2 == data_end && has_crc(data, pos) {
+            recovery.crc_valid = Some(true);
+            return Ok(recovery);
+        }
+
+        context.data_bytes_read = (pos - header_size) as u32;
+        let mut reader = Cursor::new(&data[pos..data_end]);
+        match read_record(&mut context, &mut reader) {
+            Ok(rec) => {
+                recovery.file.records.push(rec);
+                pos += reader.position() as usize;
+            },
+            Err(error) => {
+                let next = (pos + 1..data_end)
+                    .find(|x| is_plausible_definition(&data[*x..data_end], profile.as_deref()))
+                    .unwrap_or(data_end);
+                debug!("Lost bytes {}..{}: {}", pos, next, error);
+                recovery.lost.push(FitLostBytes{ range: pos as u64..next as u64, error });
+                pos = next;
+            },
+        }
+    }
+
+    if recovery.data_size_valid {
+        recovery.crc_valid = Some(has_crc(data, data_end));
+    }
+    Ok(recovery)
+}
+
+/// True if the two bytes at pos are the CRC of everything before them.
+fn has_crc(data: &[u8], pos: usize) -> bool {
+    let crc = u16::from_le_bytes([data[pos], data[pos + 1]]);
+    fitcrc::compute(&data[..pos]) == crc
+}
+
+/// True if the data starts with what could be a definition message: the reserved bits are zero,
+/// the message is known, and the fields have valid types and sizes.
+fn is_plausible_definition(data: &[u8], profile: Option<&ProfileData>) -> bool {
+    if data.len() < 6 {
+        return false;
+    }
+    let record_hdr = data[0];
+    if record_hdr & 0xD0 != 0x40 || data[1] != 0 || data[2] > 1 {
+        return false;
+    }
+    let global_message_number = if data[2] == 1 {
+        u16::from_be_bytes([data[3], data[4]])
+    } else {
+        u16::from_le_bytes([data[3], data[4]])
+    };
+    // Manufacturer specific messages are not in the profile.
+    let is_known = match profile {
+        Some(p) => p.get_message(global_message_number).is_some() || global_message_number >= 0xFF00,
+        None => true,
+    };
+    if !is_known {
+        return false;
+    }
+
+    let number_of_fields = data[5] as usize;
+    let mut pos = 6;
+    if data.len() < pos + number_of_fields * 3 {
+        return false;
+    }
+    for field in data[pos..pos + number_of_fields * 3].chunks(3) {
+        let (field_defn_num, size_in_bytes, base_type) = (field[0], field[1], field[2]);
+        if field_defn_num == 0xFF || size_in_bytes == 0 || base_type & 0x60 != 0 {
+            return false;
+        }
+        match FitDataType::from_type_id(base_type & 0x1F) {
+            Ok(t) if t.fits_size(size_in_bytes) => {},
+            _ => return false,
+        }
+    }
+    pos += number_of_fields * 3;
+
+    if record_hdr & 0x20 != 0 {
+        let number_of_dev_fields = match data.get(pos) {
+            Some(x) => *x as usize,
+            None => return false,
+        };
+        pos += 1;
+        if data.len() < pos + number_of_dev_fields * 3 {
+            return false;
+        }
+        if data[pos..pos + number_of_dev_fields * 3].chunks(3).any(|x| x[1] == 0) {
+            return false;
+        }
+    }
+    true
+}
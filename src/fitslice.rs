@@ -0,0 +1,417 @@
+// Reading FIT files held in memory, e.g. read whole or memory mapped, without copying them.
+//
+// Data messages borrow their bytes from the slice, and field values are only decoded when they
+// are asked for. Definitions and developer data descriptions are decoded as they are met, as
+// the messages that follow depend on them. Components are not expanded, as that depends on
+// the earlier messages; use FitReader for that.
+
+use std::io::Cursor;
+use std::sync::Arc;
+
+use crate::fitcrc;
+use crate::fitdatamesg::{read_dev_field_description, add_developer_data_id, DEVELOPER_DATA_ID, FIELD_DESCRIPTION};
+use crate::fiterror::{FitError, FitErrorKind, FitResult};
+use crate::fitfield::decode_fit_field;
+use crate::fitheader::read_global_header;
+use crate::fitrecord::{check_record_header, compressed_timestamp, read_record};
+use crate::fittypes::{Checks, Endianness, FitDataField, FitDataMessage, FitDataType, FitDefinitionMessage,
+                      FitDevDataDescription, FitDevDataField, FitFieldData, FitFileContext, FitFileHeader};
+
+const TIMESTAMP: u8 = 253;
+
+/// Reads the data messages of FIT files in a slice. Chained files follow each other.
+/// The CRC of each file is checked when its end is reached. Iteration stops at the end of the
+/// data or after the first error.
+///
+/// for mesg in FitSliceReader::new(&data) {
+///     let mesg = mesg?;
+///     if mesg.global_message_number() == Record::MESG_NUM { let hr = mesg.field(3)?.integer(0); ... }
+/// }
+pub struct FitSliceReader<'a> {
+    data: &'a [u8],
+    pos: usize,  // Offset of the next record.
+    data_end: Option<usize>,  // End of the data of the current file, None until its header is read.
+    header: Option<FitFileHeader>,
+    context: FitFileContext,
+    files_read: u32,  // Files read up to and including the CRC.
+    finished: bool,
+}
+
+impl<'a> FitSliceReader<'a> {
+    pub fn new(data: &'a [u8]) -> FitSliceReader<'a> {
+        FitSliceReader{ data,
+            pos: 0,
+            data_end: None,
+            header: None,
+            context: Default::default(),
+            files_read: 0,
+            finished: false }
+    }
+
+    /// The next data message, or None at the end of the data.
+    pub fn next_message(&mut self) -> FitResult<Option<FitMessageRef<'a>>> {
+        loop {
+            let data_end = match self.data_end {
+                Some(x) => x,
+                None if self.files_read > 0 && self.pos == self.data.len() => return Ok(None),
+                None => self.read_header()?,
+            };
+            if self.pos == data_end {
+                self.read_crc(data_end)?;
+                continue;
+            }
+
+            let header_end = self.context.file_start as usize + self.context.header_size as usize;
+            self.context.data_bytes_read = (self.pos - header_end) as u32;
+            let end = std::cmp::min(data_end, self.data.len());
+            let record_hdr = match self.data.get(self.pos) {
+                Some(x) => *x,
+                None => return Err(FitError::new(FitErrorKind::Truncated)
+                    .at(self.context.file_offset(), self.context.records_read)),
+            };
+
+            if record_hdr & 0xC0 == 0x40 {
+                let mut reader = Cursor::new(&self.data[self.pos..end]);
+                read_record(&mut self.context, &mut reader)?;
+                self.pos += reader.position() as usize;
+                continue;
+            }
+
+            let offset = self.context.file_offset();
+            let record_index = self.context.records_read;
+            let mesg = self.read_data_message(record_hdr, end, offset).map_err(|e| e.at(offset, record_index))?;
+            self.context.records_read += 1;
+            return Ok(Some(mesg));
+        }
+    }
+
+    fn read_header(&mut self) -> FitResult<usize> {
+        self.context.file_start = self.pos as u64;
+        let mut reader = Cursor::new(&self.data[self.pos..]);
+        let header = read_global_header(&mut self.context, &mut reader)?;
+        self.pos += header.header_size as usize;
+        let data_end = self.pos + header.data_size as usize;
+        self.data_end = Some(data_end);
+        self.header = Some(header);
+        Ok(data_end)
+    }
+
+    fn read_crc(&mut self, data_end: usize) -> FitResult<()> {
+        let file_start = self.context.file_start as usize;
+        let header_size = self.context.header_size as usize;
+        self.context.data_bytes_read = (data_end - file_start - header_size) as u32;
+        let error_at = |kind: FitErrorKind, context: &FitFileContext|
+            Err(FitError::new(kind).at(context.file_offset(), context.records_read));
+
+        let expected = match self.data.get(data_end..data_end + 2) {
+            Some(x) => u16::from_le_bytes([x[0], x[1]]),
+            None => return error_at(FitErrorKind::Truncated, &self.context),
+        };
+        let computed = fitcrc::compute(&self.data[file_start..data_end]);
+        if expected != computed {
+            return error_at(FitErrorKind::FileCrc{ expected, computed }, &self.context);
+        }
+        self.context.start_next_file();
+        self.pos = data_end + 2;
+        self.data_end = None;
+        self.files_read += 1;
+        Ok(())
+    }
+
+    fn read_data_message(&mut self, record_hdr: u8, end: usize, offset: u64) -> FitResult<FitMessageRef<'a>> {
+        check_record_header(&mut self.context, record_hdr)?;
+        let (local_message_type, timestamp) = if record_hdr & 0x80 == 0 {
+            (record_hdr & 0x0F, None)
+        } else {
+            ((record_hdr >> 5) & 0x03, Some(compressed_timestamp(self.context.timestamp, record_hdr)))
+        };
+        let definition = match self.context.field_definitions.get(&local_message_type) {
+            Some(x) => x.clone(),
+            None => return Err(FitErrorKind::UndefinedLocalMessage(local_message_type).into()),
+        };
+
+        let start = self.pos + 1;
+        let size = definition.data_size();
+        if start + size > end {
+            return Err(FitErrorKind::Truncated.into());
+        }
+        self.pos = start + size;
+
+        let dev_descriptions = definition.dev_field_defns.iter()
+            .map(|x| self.context.developer_ids.get(&x.dev_data_index)
+                .and_then(|d| d.developer_field_definitions.get(&x.field_defn_num))
+                .cloned())
+            .collect();
+        let mesg = FitMessageRef{ definition,
+            dev_descriptions,
+            bytes: &self.data[start..start + size],
+            timestamp,
+            offset };
+
+        // The reference for the timestamps of compressed messages that follow.
+        match timestamp {
+            Some(x) => self.context.timestamp = x,
+            None => {
+                if let Some(x) = mesg.field(TIMESTAMP)
+                    .filter(|x| x.data_type == FitDataType::FitUint32)
+                    .and_then(|x| x.integer(0)) {
+                    self.context.timestamp = x as u32;
+                }
+            },
+        }
+
+        // Developer fields that follow are described by these.
+        match mesg.global_message_number() {
+            DEVELOPER_DATA_ID => add_developer_data_id(&mut self.context, &mesg.to_message()?),
+            FIELD_DESCRIPTION => read_dev_field_description(&mut self.context, &mesg.to_message()?)?,
+            _ => {},
+        }
+        Ok(mesg)
+    }
+
+    /// The header of the current file.
+    pub fn header(&self) -> Option<&FitFileHeader>   {self.header.as_ref()}
+
+    /// The number of files read, including their CRC.
+    pub fn files_read(&self) -> u32   {self.files_read}
+
+    /// The decoding state, e.g. the current definitions and developer fields.
+    pub fn context(&self) -> &FitFileContext   {&self.context}
+
+    /// Which problems are errors. Those that are not are kept in the context's warnings.
+    pub fn set_checks(&mut self, checks: Checks)   {self.context.checks = checks}
+}
+
+impl<'a> Iterator for FitSliceReader<'a> {
+    type Item = FitResult<FitMessageRef<'a>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let mesg = self.next_message().transpose();
+        self.finished = !matches!(mesg, Some(Ok(_)));
+        mesg
+    }
+}
+
+/// A data message in the bytes of a file.
+#[derive(Clone, Debug)]
+pub struct FitMessageRef<'a> {
+    definition: Arc<FitDefinitionMessage>,
+    dev_descriptions: Vec<Option<Arc<FitDevDataDescription>>>,  // For each developer field.
+    bytes: &'a [u8],  // The fields, without the record header.
+    timestamp: Option<u32>,
+    offset: u64,
+}
+
+impl<'a> FitMessageRef<'a> {
+    pub fn global_message_number(&self) -> u16   {self.definition.global_message_number}
+
+    pub fn local_message_type(&self) -> u8   {self.definition.local_message_type}
+
+    /// The timestamp of a compressed timestamp header, None for other messages.
+    pub fn timestamp(&self) -> Option<u32>   {self.timestamp}
+
+    /// Byte offset of the record in the file.
+    pub fn offset(&self) -> u64   {self.offset}
+
+    pub fn definition(&self) -> &Arc<FitDefinitionMessage>   {&self.definition}
+
+    /// The bytes of the fields.
+    pub fn bytes(&self) -> &'a [u8]   {self.bytes}
+
+    /// The fields in the order of the definition.
+    pub fn fields(&self) -> impl Iterator<Item = FitFieldRef<'a>> + '_ {
+        let architecture = self.definition.architecture;
+        let bytes = self.bytes;
+        self.definition.field_defns.iter().scan(0, move |pos, defn| {
+            let size = defn.size_in_bytes as usize;
+            let field = FitFieldRef{ field_defn_num: defn.field_defn_num,
+                data_type: defn.data_type.unwrap(),
+                architecture,
+                bytes: &bytes[*pos..*pos + size] };
+            *pos += size;
+            Some(field)
+        })
+    }
+
+    pub fn field(&self, field_defn_num: u8) -> Option<FitFieldRef<'a>> {
+        self.fields().find(|x| x.field_defn_num == field_defn_num)
+    }
+
+    /// The developer fields, which follow the fields.
+    pub fn dev_fields(&self) -> impl Iterator<Item = FitDevFieldRef<'a>> + '_ {
+        let architecture = self.definition.architecture;
+        let bytes = self.bytes;
+        let start = self.definition.field_defns.iter().map(|x| x.size_in_bytes as usize).sum::<usize>();
+        self.definition.dev_field_defns.iter().zip(&self.dev_descriptions).scan(start, move |pos, (defn, desc)| {
+            let size = defn.size_in_bytes as usize;
+            let field = FitDevFieldRef{ field_defn_num: defn.field_defn_num,
+                dev_data_index: defn.dev_data_index,
+                description: desc.clone(),
+                architecture,
+                bytes: &bytes[*pos..*pos + size] };
+            *pos += size;
+            Some(field)
+        })
+    }
+
+    /// Decode all the fields. Components are not expanded.
+    pub fn to_message(&self) -> FitResult<FitDataMessage> {
+        let mut mesg = FitDataMessage{
+            global_message_number: self.global_message_number(),
+            local_message_type: self.local_message_type(),
+            timestamp: self.timestamp,
+            ..Default::default()
+        };
+        for field in self.fields() {
+            mesg.fields.push(FitDataField{ field_defn_num: field.field_defn_num, data: field.value()? });
+        }
+        for field in self.dev_fields() {
+            let data = field.value()?;
+            mesg.dev_fields.push(FitDevDataField{ field_defn_num: field.field_defn_num, data, description: field.description });
+        }
+        // As when reading, a compressed timestamp is also given as a timestamp field.
+        if let Some(x) = self.timestamp {
+            if self.field(TIMESTAMP).is_none() {
+                mesg.expanded_fields.push(FitDataField{ field_defn_num: TIMESTAMP, data: FitFieldData::FitUint32(smallvec![x]) });
+            }
+        }
+        Ok(mesg)
+    }
+}
+
+/// A field of a message, in the bytes of a file.
+#[derive(Clone, Copy, Debug)]
+pub struct FitFieldRef<'a> {
+    pub field_defn_num: u8,
+    pub data_type: FitDataType,
+    pub architecture: Endianness,
+    pub bytes: &'a [u8],
+}
+
+impl<'a> FitFieldRef<'a> {
+    pub fn value(&self) -> FitResult<FitFieldData> {
+        decode_fit_field(Some(self.architecture), self.data_type, self.bytes)
+    }
+
+    /// The value at index as an integer, without decoding the others. None for strings and
+    /// floating point data, or if there are not that many values.
+    pub fn integer(&self, index: usize) -> Option<u64> {
+        integer_at(self.architecture, self.data_type, self.bytes, index)
+    }
+}
+
+/// A developer field of a message, in the bytes of a file.
+#[derive(Clone, Debug)]
+pub struct FitDevFieldRef<'a> {
+    pub field_defn_num: u8,
+    pub dev_data_index: u8,
+    pub description: Option<Arc<FitDevDataDescription>>,  // None if the field was not described.
+    pub architecture: Endianness,
+    pub bytes: &'a [u8],
+}
+
+impl<'a> FitDevFieldRef<'a> {
+    /// The value, as bytes if the field was not described.
+    pub fn value(&self) -> FitResult<FitFieldData> {
+        match self.data_type() {
+            Some(x) => decode_fit_field(Some(self.architecture), x, self.bytes),
+            None => Ok(FitFieldData::FitByte(self.bytes.into())),
+        }
+    }
+
+    pub fn integer(&self, index: usize) -> Option<u64> {
+        integer_at(self.architecture, self.data_type()?, self.bytes, index)
+    }
+
+    fn data_type(&self) -> Option<FitDataType> {
+        self.description.as_ref()
+            .and_then(|x| x.base_type)
+            .filter(|x| x.fits_size(self.bytes.len() as u8))
+    }
+}
+
+/// Signed values are sign extended, as by FitFieldData::integer_values().
+fn integer_at(architecture: Endianness, data_type: FitDataType, bytes: &[u8], index: usize) -> Option<u64> {
+    if matches!(data_type, FitDataType::FitString | FitDataType::FitF32 | FitDataType::FitF64) {
+        return None;
+    }
+    let size = data_type.data_size() as usize;
+    let x = bytes.get(index * size..(index + 1) * size)?;
+    let mut buf = [0u8; 8];
+    let value = match architecture {
+        Endianness::Little => {
+            buf[..size].copy_from_slice(x);
+            u64::from_le_bytes(buf)
+        },
+        Endianness::Big => {
+            buf[8 - size..].copy_from_slice(x);
+            u64::from_be_bytes(buf)
+        },
+    };
+    let shift = 64 - 8 * size as u32;
+    Some(match data_type {
+        FitDataType::FitSint8 | FitDataType::FitSint16 | FitDataType::FitSint32 | FitDataType::FitSInt64 =>
+            (((value << shift) as i64) >> shift) as u64,
+        _ => value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::fitmessages::Record;
+    use crate::fittest::{get_developer_data_fit, make_activity_fit, read_data_messages};
+    use std::convert::TryFrom;
+
+    #[test]
+    fn test_same_as_reader() -> FitResult<()> {
+        let data = make_activity_fit();
+        let messages: Vec<FitMessageRef> = FitSliceReader::new(&data).collect::<FitResult<_>>()?;
+        let records = read_data_messages(&data);
+        assert_eq!(49, messages.len());
+        assert!(messages.iter().any(|x| x.timestamp().is_some()));
+        for (mesg, record) in messages.iter().zip(&records) {
+            let owned = mesg.to_message()?;
+            assert_eq!(format!("{:?}", record.fields), format!("{:?}", owned.fields));
+            assert_eq!(record.timestamp, owned.timestamp);
+            if record.global_message_number == Record::MESG_NUM {
+                assert_eq!(Record::try_from(record).unwrap().timestamp, Record::try_from(&owned).unwrap().timestamp);
+            }
+        }
+
+        // Values are decoded from the bytes as needed. The fourth record follows the first event.
+        let mesg = &messages[4];
+        assert_eq!(20, mesg.global_message_number());
+        assert_eq!(Some(103), mesg.field(3).and_then(|x| x.integer(0)));
+        assert_eq!(Some(-5i64 as u64), mesg.field(13).and_then(|x| x.integer(0)));
+        assert!(std::ptr::eq(&data[mesg.offset() as usize + 1], &mesg.bytes()[0]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_developer_data_and_errors() -> FitResult<()> {
+        let data = get_developer_data_fit();
+        let mesg = FitSliceReader::new(&data).filter_map(|x| x.ok()).find(|x| x.global_message_number() == 20).unwrap();
+        let dev_field = mesg.dev_fields().next().unwrap();
+        assert_eq!("doughnuts_earned", dev_field.description.as_ref().unwrap().field_name);
+        assert_eq!(Some(1), dev_field.integer(0));
+
+        // Chained files, then a bad CRC.
+        let mut chained = data.clone();
+        chained.extend_from_slice(&data);
+        let mut reader = FitSliceReader::new(&chained);
+        assert_eq!(12, reader.by_ref().filter(|x| x.is_ok()).count());
+        assert_eq!(2, reader.files_read());
+
+        let last = chained.len() - 1;
+        chained[last] ^= 0xFF;
+        let results: Vec<FitResult<FitMessageRef>> = FitSliceReader::new(&chained).collect();
+        let e = results.last().unwrap().as_ref().unwrap_err();
+        assert!(matches!(e.kind, FitErrorKind::FileCrc{ .. }));
+        assert_eq!(Some(last as u64 - 1), e.offset);
+        Ok(())
+    }
+}
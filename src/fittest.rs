@@ -0,0 +1,49 @@
+// Test data and helpers shared by the tests of several modules.
+
+use crate::fitfile::{FitReader, FitWriter};
+use crate::fitmessages::{Event, Record, Session};
+use crate::fittypes::{FitDataMessage, FitFileHeader, FitRecord};
+
+/// DeveloperData.fit from the FIT SDK.
+pub(crate) fn get_developer_data_fit() -> Vec<u8> {
+    base64::decode("DiBoBqIAAAAuRklUvtBAAAEAAAQBAoQAAQACAoQDBIwAAA8EIykAAAalQAABAM8CARANAw\
+    ECAAEBAgMFCA0VIjdZkOl5YtsAQAABAM4FAAECAQECAgECAxEHCAoHAAAAAWRvdWdobnV0c19lYXJuZWQAZG91Z2hud\
+    XRzAGAAAQAUBAMBAgQBAgUEhgYChAEAAQAAjFgAAMc4uYABAI9aAAMsgI5AAgCQXAAFqTiKEAPTng=").unwrap()
+}
+
+/// A made up activity: 40 records every 10 s from 1000 with compressed timestamps, an event
+/// 3 s after every fifth record from the third, and a session. The records gain a cadence
+/// field half way, so they have two definitions.
+pub(crate) fn make_activity_fit() -> Vec<u8> {
+    let mut writer = FitWriter::new(Vec::new(), FitFileHeader{ header_size: 14, ..Default::default() });
+    writer.encoder().set_compressed_timestamps(true);
+    for i in 0..40 {
+        let mut record = Record{ timestamp: Some(1000 + i * 10), heart_rate: Some(100 + i as u8),
+            altitude: Some(100.0), temperature: Some(-5), ..Default::default() };
+        if i >= 20 {
+            record.cadence = Some(80);
+        }
+        writer.write_message(FitDataMessage::from(&record)).unwrap();
+        if i % 5 == 2 {
+            let event = Event{ timestamp: Some(1000 + i * 10 + 3), data16: Some(i as u16), ..Default::default() };
+            writer.write_message(FitDataMessage::from(&event)).unwrap();
+        }
+    }
+    let session = Session{ timestamp: Some(1400), total_elapsed_time: Some(400.0), ..Default::default() };
+    writer.write_message(FitDataMessage::from(&session)).unwrap();
+    writer.finish().unwrap();
+    writer.into_inner()
+}
+
+/// The data messages read from the start, including chained files.
+pub(crate) fn read_data_messages(data: &[u8]) -> Vec<FitDataMessage> {
+    FitReader::new(data).filter_map(|x| match x.unwrap() {
+        FitRecord::DataRecord(mesg) => Some(mesg),
+        _ => None,
+    }).collect()
+}
+
+/// Messages are not Clone or PartialEq, so they are compared by their debug output.
+pub(crate) fn debug_strings<'a, I: IntoIterator<Item = &'a FitDataMessage>>(mesgs: I) -> Vec<String> {
+    mesgs.into_iter().map(|x| format!("{:?}", x)).collect()
+}
@@ -1,15 +1,18 @@
 
 use crate::fitcrc::{FitCrc};
+use crate::fiterror::{FitError, FitErrorKind, FitResult};
+use crate::profile::ProfileData;
 
 use chrono::{DateTime, TimeZone, Utc};
+use smallvec::SmallVec;
 
 use std::sync::Arc;
 use std::collections::HashMap;
-use std::convert::{TryFrom};
+use std::convert::{TryFrom, TryInto};
 
 pub const INVALID_U32: u32 = 0xFFFFFFFF;
 
-#[derive(Copy, Clone, Default)]
+#[derive(Clone, Default)]
 #[derive(Debug)]
 pub struct FitFileHeader {
     pub header_size: u8,
@@ -17,11 +20,12 @@ pub struct FitFileHeader {
     pub profile_version: u16,
     pub data_size: u32,
     pub type_signature: [u8; 4],
-    pub crc: u16,
+    pub crc: Option<u16>,  // As read. It is computed when written, unless it was 0, meaning none.
+    pub extra: Vec<u8>,  // Bytes after the CRC, if the header is larger than 14 bytes.
 }
 
 #[derive(Debug)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub enum Endianness {
     Little, Big,
 }
@@ -31,7 +35,7 @@ impl Default for Endianness {
 }
 
 #[derive(Debug)]
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, PartialEq, Eq)]
 pub enum FitDataType {
     FitEnum,
     FitSint8, FitUint8, FitSint16, FitUint16, FitSint32, FitUint32,
@@ -39,19 +43,21 @@ pub enum FitDataType {
     FitSInt64, FitUint64, FitUint64z,
 }
 
+/// The values of a field. Up to 16 bytes of values, e.g. any single value, are held inline, so
+/// that decoding them does not allocate. Build them with `smallvec![...]` or `.into()` a Vec.
 #[derive(Debug)]
 #[derive(Clone)]
 pub enum FitFieldData {
-    FitEnum(Vec<u8>),
-    FitSint8(Vec<i8>), FitUint8(Vec<u8>), FitSint16(Vec<i16>), FitUint16(Vec<u16>),
-    FitSint32(Vec<i32>), FitUint32(Vec<u32>),
-    FitString(String,u8), FitF32(Vec<f32>), FitF64(Vec<f64>), FitU8z(Vec<u8>),
-    FitU16z(Vec<u16>), FitU32z(Vec<u32>), FitByte(Vec<u8>),
-    FitSInt64(Vec<i64>), FitUint64(Vec<u64>), FitUint64z(Vec<u64>),
+    FitEnum(SmallVec<[u8; 16]>),
+    FitSint8(SmallVec<[i8; 16]>), FitUint8(SmallVec<[u8; 16]>), FitSint16(SmallVec<[i16; 8]>), FitUint16(SmallVec<[u16; 8]>),
+    FitSint32(SmallVec<[i32; 4]>), FitUint32(SmallVec<[u32; 4]>),
+    FitString(String,u8), FitF32(SmallVec<[f32; 4]>), FitF64(SmallVec<[f64; 2]>), FitU8z(SmallVec<[u8; 16]>),
+    FitU16z(SmallVec<[u16; 8]>), FitU32z(SmallVec<[u32; 4]>), FitByte(SmallVec<[u8; 16]>),
+    FitSInt64(SmallVec<[i64; 2]>), FitUint64(SmallVec<[u64; 2]>), FitUint64z(SmallVec<[u64; 2]>),
 }
 
 impl FitDataType {
-    pub fn from_type_id(value: u8) -> Result<FitDataType, std::io::Error> {
+    pub fn from_type_id(value: u8) -> FitResult<FitDataType> {
         match value {
             0 => Ok(FitDataType::FitEnum),
             1 => Ok(FitDataType::FitSint8),
@@ -70,7 +76,7 @@ impl FitDataType {
             14 => Ok(FitDataType::FitSInt64),
             15 => Ok(FitDataType::FitUint64),
             16 => Ok(FitDataType::FitUint64z),
-            _ => Err(std::io::Error::new(std::io::ErrorKind::Other, "Invalid FIT data type"))
+            _ => Err(FitErrorKind::InvalidBaseType(value).into())
         }
     }
 
@@ -118,6 +124,14 @@ impl FitDataType {
         }
     }
 
+    /// True if a field of this many bytes holds a whole number of values.
+    pub fn fits_size(&self, size_in_bytes: u8) -> bool {
+        match self.data_size() {
+            0 => true,
+            x => size_in_bytes.is_multiple_of(x),
+        }
+    }
+
     pub fn name(&self) -> &str {
         match self {
             FitDataType::FitEnum => "enum",
@@ -140,9 +154,9 @@ impl FitDataType {
         }
     }
 
-    pub fn from_name(name: &str) -> Result<FitDataType, std::io::Error> {
+    pub fn from_name(name: &str) -> FitResult<FitDataType> {
         match name {
-            "enum" => Ok(FitDataType::FitEnum),
+            "enum" | "bool" => Ok(FitDataType::FitEnum),
             "sint8" => Ok(FitDataType::FitSint8),
             "uint8" => Ok(FitDataType::FitUint8),
             "sint16" => Ok(FitDataType::FitSint16),
@@ -150,16 +164,61 @@ impl FitDataType {
             "sint32" => Ok(FitDataType::FitSint32),
             "uint32" => Ok(FitDataType::FitUint32),
             "string" => Ok(FitDataType::FitString),
-            "f32" => Ok(FitDataType::FitF32),
-            "f64" => Ok(FitDataType::FitF64),
-            "u8z" => Ok(FitDataType::FitU8z),
-            "u16z" => Ok(FitDataType::FitU16z),
-            "u32z" => Ok(FitDataType::FitU32z),
+            "f32" | "float32" => Ok(FitDataType::FitF32),
+            "f64" | "float64" => Ok(FitDataType::FitF64),
+            "u8z" | "uint8z" => Ok(FitDataType::FitU8z),
+            "u16z" | "uint16z" => Ok(FitDataType::FitU16z),
+            "u32z" | "uint32z" => Ok(FitDataType::FitU32z),
             "byte" => Ok(FitDataType::FitByte),
             "sint64" => Ok(FitDataType::FitSInt64),
             "uint64" => Ok(FitDataType::FitUint64),
             "uint64z" => Ok(FitDataType::FitUint64z),
-            _ => Err(std::io::Error::new(std::io::ErrorKind::Other, "Invalid FIT data name"))
+            _ => Err(FitErrorKind::Profile(format!("Invalid FIT data name {}", name)).into())
+        }
+    }
+
+    /// The raw values that fit the type and are not its invalid value, or None for strings and
+    /// floating point types.
+    pub fn valid_range(&self) -> Option<std::ops::Range<f64>> {
+        let two_pow = |n: i32| 2f64.powi(n);
+        match self {
+            FitDataType::FitEnum | FitDataType::FitUint8 | FitDataType::FitByte => Some(0.0..255.0),
+            FitDataType::FitSint8 => Some(-128.0..127.0),
+            FitDataType::FitUint16 => Some(0.0..65535.0),
+            FitDataType::FitSint16 => Some(-32768.0..32767.0),
+            FitDataType::FitUint32 => Some(0.0..4294967295.0),
+            FitDataType::FitSint32 => Some(-2147483648.0..2147483647.0),
+            FitDataType::FitU8z => Some(1.0..256.0),
+            FitDataType::FitU16z => Some(1.0..65536.0),
+            FitDataType::FitU32z => Some(1.0..two_pow(32)),
+            // The invalid values of 64 bit types are not exact as f64, the ranges end below them.
+            FitDataType::FitSInt64 => Some(-two_pow(63)..two_pow(63)),
+            FitDataType::FitUint64 => Some(0.0..two_pow(64)),
+            FitDataType::FitUint64z => Some(1.0..two_pow(64)),
+            FitDataType::FitString | FitDataType::FitF32 | FitDataType::FitF64 => None,
+        }
+    }
+
+    /// The bit pattern of the invalid value of the type, used to mark missing values.
+    pub fn invalid_value(&self) -> u64 {
+        match self {
+            FitDataType::FitEnum => 0xFF,
+            FitDataType::FitSint8 => 0x7F,
+            FitDataType::FitUint8 => 0xFF,
+            FitDataType::FitSint16 => 0x7FFF,
+            FitDataType::FitUint16 => 0xFFFF,
+            FitDataType::FitSint32 => 0x7FFFFFFF,
+            FitDataType::FitUint32 => 0xFFFFFFFF,
+            FitDataType::FitString => 0,
+            FitDataType::FitF32 => 0xFFFFFFFF,
+            FitDataType::FitF64 => 0xFFFFFFFF_FFFFFFFF,
+            FitDataType::FitU8z => 0,
+            FitDataType::FitU16z => 0,
+            FitDataType::FitU32z => 0,
+            FitDataType::FitByte => 0xFF,
+            FitDataType::FitSInt64 => 0x7FFFFFFF_FFFFFFFF,
+            FitDataType::FitUint64 => 0xFFFFFFFF_FFFFFFFF,
+            FitDataType::FitUint64z => 0,
         }
     }
 }
@@ -194,7 +253,7 @@ impl TryFrom<&FitFieldData> for String {
     }
 }
 
-fn attempt_to_cast_first<T,U>(x: &Vec<T>) -> Result<U, &'static str>
+fn attempt_to_cast_first<T,U>(x: &[T]) -> Result<U, &'static str>
 where U: From<T>, T:Copy
 {
     if let Some(v) = x.get(0) {
@@ -235,68 +294,248 @@ impl TryFrom<&FitFieldData> for f64 {
 }
 
 
-fn contains_invalid_f32(x: &Vec<f32>) -> bool
+/// True if the value at `index` exists and is not the invalid value of its base type.
+fn valid_at<T: PartialEq>(x: &[T], index: usize, invalid: T) -> bool
 {
-    for item in x {
-        let bitpattern = unsafe {
-            std::mem::transmute::<f32, u32>(*item)
-        };
-        if bitpattern != 0xFFFFFFFF_u32 {
-            return true;
-        }
-    }
-    return false;
+    matches!(x.get(index), Some(v) if *v != invalid)
 }
 
-fn contains_invalid_f64(x: &Vec<f64>) -> bool
+// Invalid floating point values are all ones, which is a NaN, so compare the bit patterns.
+fn valid_f32_at(x: &[f32], index: usize) -> bool
 {
-    for item in x {
-        let bitpattern = unsafe {
-            std::mem::transmute::<f64, u64>(*item)
-        };
-        if bitpattern != 0xFFFFFFFF_FFFFFFFF_u64 {
-            return true;
-        }
-    }
-    return false;
+    matches!(x.get(index), Some(v) if v.to_bits() != 0xFFFFFFFF_u32)
+}
+
+fn valid_f64_at(x: &[f64], index: usize) -> bool
+{
+    matches!(x.get(index), Some(v) if v.to_bits() != 0xFFFFFFFF_FFFFFFFF_u64)
 }
 
 impl FitFieldData
 {
+    /// The base type of the values.
+    pub fn data_type(&self) -> FitDataType
+    {
+        match self {
+            FitFieldData::FitEnum(_) => FitDataType::FitEnum,
+            FitFieldData::FitSint8(_) => FitDataType::FitSint8,
+            FitFieldData::FitUint8(_) => FitDataType::FitUint8,
+            FitFieldData::FitSint16(_) => FitDataType::FitSint16,
+            FitFieldData::FitUint16(_) => FitDataType::FitUint16,
+            FitFieldData::FitSint32(_) => FitDataType::FitSint32,
+            FitFieldData::FitUint32(_) => FitDataType::FitUint32,
+            FitFieldData::FitString(_, _) => FitDataType::FitString,
+            FitFieldData::FitF32(_) => FitDataType::FitF32,
+            FitFieldData::FitF64(_) => FitDataType::FitF64,
+            FitFieldData::FitU8z(_) => FitDataType::FitU8z,
+            FitFieldData::FitU16z(_) => FitDataType::FitU16z,
+            FitFieldData::FitU32z(_) => FitDataType::FitU32z,
+            FitFieldData::FitByte(_) => FitDataType::FitByte,
+            FitFieldData::FitSInt64(_) => FitDataType::FitSInt64,
+            FitFieldData::FitUint64(_) => FitDataType::FitUint64,
+            FitFieldData::FitUint64z(_) => FitDataType::FitUint64z,
+        }
+    }
+
+    /// The number of bytes when written, strings are padded to their width.
+    pub fn size_in_bytes(&self) -> usize
+    {
+        match self {
+            FitFieldData::FitString(_, width) => *width as usize,
+            _ => self.value_count() * self.data_type().data_size() as usize,
+        }
+    }
+
+    /// The number of values, a string counts as one value.
+    pub fn value_count(&self) -> usize
+    {
+        match self {
+            FitFieldData::FitEnum(x) |
+            FitFieldData::FitUint8(x) |
+            FitFieldData::FitU8z(x) |
+            FitFieldData::FitByte(x) => x.len(),
+            FitFieldData::FitSint8(x) => x.len(),
+            FitFieldData::FitSint16(x) => x.len(),
+            FitFieldData::FitUint16(x) |
+            FitFieldData::FitU16z(x) => x.len(),
+            FitFieldData::FitSint32(x) => x.len(),
+            FitFieldData::FitUint32(x) |
+            FitFieldData::FitU32z(x) => x.len(),
+            FitFieldData::FitString(_, _) => 1,
+            FitFieldData::FitF32(x) => x.len(),
+            FitFieldData::FitF64(x) => x.len(),
+            FitFieldData::FitSInt64(x) => x.len(),
+            FitFieldData::FitUint64(x) |
+            FitFieldData::FitUint64z(x) => x.len(),
+        }
+    }
+
+    /// True if the value at `index` is present, i.e. is not the invalid value of the base type.
+    pub fn is_valid_at(&self, index: usize) -> bool
+    {
+        match self {
+            FitFieldData::FitEnum(x) => valid_at(x, index, 0xFF),
+            FitFieldData::FitSint8(x) => valid_at(x, index, 0x7F),
+            FitFieldData::FitUint8(x) => valid_at(x, index, 0xFF),
+            FitFieldData::FitSint16(x) => valid_at(x, index, 0x7FFF),
+            FitFieldData::FitUint16(x) => valid_at(x, index, 0xFFFF),
+            FitFieldData::FitSint32(x) => valid_at(x, index, 0x7FFFFFFF),
+            FitFieldData::FitUint32(x) => valid_at(x, index, 0xFFFFFFFF),
+            FitFieldData::FitString(x, _) => index == 0 && !x.is_empty(),
+            FitFieldData::FitF32(x) => valid_f32_at(x, index),
+            FitFieldData::FitF64(x) => valid_f64_at(x, index),
+            FitFieldData::FitU8z(x) => valid_at(x, index, 0x0_u8),
+            FitFieldData::FitU16z(x) => valid_at(x, index, 0x0_u16),
+            FitFieldData::FitU32z(x) => valid_at(x, index, 0x0_u32),
+            FitFieldData::FitByte(x) => valid_at(x, index, 0xFF),
+            FitFieldData::FitSInt64(x) => valid_at(x, index, 0x7FFFFFFF_FFFFFFFF_i64),
+            FitFieldData::FitUint64(x) => valid_at(x, index, 0xFFFFFFFF_FFFFFFFF_u64),
+            FitFieldData::FitUint64z(x) => valid_at(x, index, 0x0_u64),
+        }
+    }
+
+    /// Which of the values are present.
+    pub fn valid_mask(&self) -> Vec<bool>
+    {
+        (0..self.value_count()).map(|i| self.is_valid_at(i)).collect()
+    }
+
+    /// A field is valid if any of its values is. As in the SDK, an array field is only
+    /// invalid when all of its elements are.
     pub fn is_valid(&self) -> bool
+    {
+        (0..self.value_count()).any(|i| self.is_valid_at(i))
+    }
+
+    /// The values as integers, or None for strings and floating point data.
+    pub fn integer_values(&self) -> Option<Vec<u64>>
     {
         match self {
-            FitFieldData::FitEnum(x) => (!x.is_empty()) && (!x.contains(&0xFF)),
-            FitFieldData::FitSint8(x) => (!x.is_empty()) && (!x.contains(&0x7F)),
-            FitFieldData::FitUint8(x) => (!x.is_empty()) && (!x.contains(&0xFF)),
-            FitFieldData::FitSint16(x) => (!x.is_empty()) && (!x.contains(&0x7FFF)),
-            FitFieldData::FitUint16(x) => (!x.is_empty()) && (!x.contains(&0xFFFF)),
-            FitFieldData::FitSint32(x) => (!x.is_empty()) && (!x.contains(&0x7FFFFFFF)),
-            FitFieldData::FitUint32(x) => (!x.is_empty()) && (!x.contains(&0xFFFFFFFF)),
-            FitFieldData::FitString(x, _) => (!x.is_empty()),
-            FitFieldData::FitF32(x) => (!x.is_empty()) && (!contains_invalid_f32(x)),
-            FitFieldData::FitF64(x) => (!x.is_empty()) && (!contains_invalid_f64(x)),
-            FitFieldData::FitU8z(x) => (!x.is_empty()) && (!x.contains(&0x0_u8)),
-            FitFieldData::FitU16z(x) => (!x.is_empty()) && (!x.contains(&0x0_u16)),
-            FitFieldData::FitU32z(x) => (!x.is_empty()) && (!x.contains(&0x0_u32)),
-            FitFieldData::FitByte(x) => (!x.is_empty()) && (!x.contains(&0xFF)),
-            FitFieldData::FitSInt64(x) => (!x.is_empty()) && (!x.contains(&0x7FFFFFFF_FFFFFFFF_i64)),
-            FitFieldData::FitUint64(x) => (!x.is_empty()) && (!x.contains(&0xFFFFFFFF_FFFFFFFF_u64)),
-            FitFieldData::FitUint64z(x) => (!x.is_empty()) && (!x.contains(&0x0_u64)),
+            FitFieldData::FitEnum(x) |
+            FitFieldData::FitUint8(x) |
+            FitFieldData::FitU8z(x) |
+            FitFieldData::FitByte(x) => Some(x.iter().map(|v| *v as u64).collect()),
+            FitFieldData::FitSint8(x) => Some(x.iter().map(|v| *v as u64).collect()),
+            FitFieldData::FitSint16(x) => Some(x.iter().map(|v| *v as u64).collect()),
+            FitFieldData::FitUint16(x) |
+            FitFieldData::FitU16z(x) => Some(x.iter().map(|v| *v as u64).collect()),
+            FitFieldData::FitSint32(x) => Some(x.iter().map(|v| *v as u64).collect()),
+            FitFieldData::FitUint32(x) |
+            FitFieldData::FitU32z(x) => Some(x.iter().map(|v| *v as u64).collect()),
+            FitFieldData::FitSInt64(x) => Some(x.iter().map(|v| *v as u64).collect()),
+            FitFieldData::FitUint64(x) |
+            FitFieldData::FitUint64z(x) => Some(x.to_vec()),
+            FitFieldData::FitString(_, _) |
+            FitFieldData::FitF32(_) |
+            FitFieldData::FitF64(_) => None,
         }
     }
-}
 
+    /// The first value as an integer, used to compare against enumerated values.
+    pub fn first_integer(&self) -> Option<u64>
+    {
+        self.integer_values()?.first().copied()
+    }
 
+    /// Build field data of the given type from (already rounded) numeric values.
+    /// Strings can not be built this way and are returned as bytes.
+    pub fn from_f64_values(data_type: FitDataType, values: &[f64]) -> FitFieldData
+    {
+        match data_type {
+            FitDataType::FitEnum => FitFieldData::FitEnum(values.iter().map(|v| *v as u8).collect()),
+            FitDataType::FitSint8 => FitFieldData::FitSint8(values.iter().map(|v| *v as i8).collect()),
+            FitDataType::FitUint8 => FitFieldData::FitUint8(values.iter().map(|v| *v as u8).collect()),
+            FitDataType::FitSint16 => FitFieldData::FitSint16(values.iter().map(|v| *v as i16).collect()),
+            FitDataType::FitUint16 => FitFieldData::FitUint16(values.iter().map(|v| *v as u16).collect()),
+            FitDataType::FitSint32 => FitFieldData::FitSint32(values.iter().map(|v| *v as i32).collect()),
+            FitDataType::FitUint32 => FitFieldData::FitUint32(values.iter().map(|v| *v as u32).collect()),
+            FitDataType::FitF32 => FitFieldData::FitF32(values.iter().map(|v| *v as f32).collect()),
+            FitDataType::FitF64 => FitFieldData::FitF64(SmallVec::from_slice(values)),
+            FitDataType::FitU8z => FitFieldData::FitU8z(values.iter().map(|v| *v as u8).collect()),
+            FitDataType::FitU16z => FitFieldData::FitU16z(values.iter().map(|v| *v as u16).collect()),
+            FitDataType::FitU32z => FitFieldData::FitU32z(values.iter().map(|v| *v as u32).collect()),
+            FitDataType::FitString |
+            FitDataType::FitByte => FitFieldData::FitByte(values.iter().map(|v| *v as u8).collect()),
+            FitDataType::FitSInt64 => FitFieldData::FitSInt64(values.iter().map(|v| *v as i64).collect()),
+            FitDataType::FitUint64 => FitFieldData::FitUint64(values.iter().map(|v| *v as u64).collect()),
+            FitDataType::FitUint64z => FitFieldData::FitUint64z(values.iter().map(|v| *v as u64).collect()),
+        }
+    }
 
-#[derive(Clone, Debug, Default)]
+    /// Build field data of the given type from integer values, the inverse of `integer_values`.
+    /// Floating point types are given as bit patterns.
+    pub fn from_integer_values(data_type: FitDataType, values: &[u64]) -> FitFieldData
+    {
+        match data_type {
+            FitDataType::FitEnum => FitFieldData::FitEnum(values.iter().map(|v| *v as u8).collect()),
+            FitDataType::FitSint8 => FitFieldData::FitSint8(values.iter().map(|v| *v as i8).collect()),
+            FitDataType::FitUint8 => FitFieldData::FitUint8(values.iter().map(|v| *v as u8).collect()),
+            FitDataType::FitSint16 => FitFieldData::FitSint16(values.iter().map(|v| *v as i16).collect()),
+            FitDataType::FitUint16 => FitFieldData::FitUint16(values.iter().map(|v| *v as u16).collect()),
+            FitDataType::FitSint32 => FitFieldData::FitSint32(values.iter().map(|v| *v as i32).collect()),
+            FitDataType::FitUint32 => FitFieldData::FitUint32(values.iter().map(|v| *v as u32).collect()),
+            FitDataType::FitF32 => FitFieldData::FitF32(values.iter().map(|v| f32::from_bits(*v as u32)).collect()),
+            FitDataType::FitF64 => FitFieldData::FitF64(values.iter().map(|v| f64::from_bits(*v)).collect()),
+            FitDataType::FitU8z => FitFieldData::FitU8z(values.iter().map(|v| *v as u8).collect()),
+            FitDataType::FitU16z => FitFieldData::FitU16z(values.iter().map(|v| *v as u16).collect()),
+            FitDataType::FitU32z => FitFieldData::FitU32z(values.iter().map(|v| *v as u32).collect()),
+            FitDataType::FitString |
+            FitDataType::FitByte => FitFieldData::FitByte(values.iter().map(|v| *v as u8).collect()),
+            FitDataType::FitSInt64 => FitFieldData::FitSInt64(values.iter().map(|v| *v as i64).collect()),
+            FitDataType::FitUint64 => FitFieldData::FitUint64(SmallVec::from_slice(values)),
+            FitDataType::FitUint64z => FitFieldData::FitUint64z(SmallVec::from_slice(values)),
+        }
+    }
+
+    /// A null terminated string. The width is at most 255 bytes, so a longer string is cut at a
+    /// character boundary to leave room for the terminator.
+    pub fn from_string(value: &str) -> FitFieldData
+    {
+        let mut len = std::cmp::min(value.len(), 254);
+        while !value.is_char_boundary(len) {
+            len -= 1;
+        }
+        if len < value.len() {
+            warn!("String of {} bytes is cut to {}", value.len(), len);
+        }
+        FitFieldData::FitString(value[..len].to_string(), len as u8 + 1)
+    }
+
+    /// The little-endian bytes of integer data, used to unpack bit-packed components.
+    pub fn to_le_bytes(&self) -> Option<SmallVec<[u8; 16]>>
+    {
+        match self {
+            FitFieldData::FitEnum(x) |
+            FitFieldData::FitUint8(x) |
+            FitFieldData::FitU8z(x) |
+            FitFieldData::FitByte(x) => Some(x.clone()),
+            FitFieldData::FitSint8(x) => Some(x.iter().map(|v| *v as u8).collect()),
+            FitFieldData::FitSint16(x) => Some(x.iter().flat_map(|v| v.to_le_bytes()).collect()),
+            FitFieldData::FitUint16(x) |
+            FitFieldData::FitU16z(x) => Some(x.iter().flat_map(|v| v.to_le_bytes()).collect()),
+            FitFieldData::FitSint32(x) => Some(x.iter().flat_map(|v| v.to_le_bytes()).collect()),
+            FitFieldData::FitUint32(x) |
+            FitFieldData::FitU32z(x) => Some(x.iter().flat_map(|v| v.to_le_bytes()).collect()),
+            FitFieldData::FitSInt64(x) => Some(x.iter().flat_map(|v| v.to_le_bytes()).collect()),
+            FitFieldData::FitUint64(x) |
+            FitFieldData::FitUint64z(x) => Some(x.iter().flat_map(|v| v.to_le_bytes()).collect()),
+            FitFieldData::FitString(_, _) |
+            FitFieldData::FitF32(_) |
+            FitFieldData::FitF64(_) => None,
+        }
+    }
+}
+
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct FitFieldDefinition{
     pub field_defn_num: u8,
     pub size_in_bytes: u8,
     pub data_type: Option<FitDataType>,
+    pub raw_base_type: Option<u8>,  // The base type byte as read, written as it is unless data_type is changed.
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct FitDeveloperFieldDefinition{
     pub field_defn_num: u8,
     pub size_in_bytes: u8,
@@ -305,33 +544,128 @@ pub struct FitDeveloperFieldDefinition{
 
 #[derive(Clone, Debug,Default)]
 pub struct FitDefinitionMessage {
+    pub reserved: u8,  // The byte after the record header, should be 0.
     pub architecture:Endianness,
     pub global_message_number: u16,
     pub local_message_type: u8,
     pub field_defns: Vec< Arc<FitFieldDefinition> >,
     pub dev_field_defns: Vec< Arc<FitDeveloperFieldDefinition> >,
+    pub is_developer: bool,  // Set if the record header flags developer fields, even if there are none.
+}
+
+impl FitDefinitionMessage {
+    /// The number of bytes of the fields of a data message, without the record header.
+    pub fn data_size(&self) -> usize {
+        self.field_defns.iter().map(|x| x.size_in_bytes as usize).sum::<usize>()
+            + self.dev_field_defns.iter().map(|x| x.size_in_bytes as usize).sum::<usize>()
+    }
 }
 
 #[derive(Debug)]
 pub struct Checks {
     pub reserved_bits_zero: bool,  // Error if reserved bits are non-zero.
+    pub valid_base_types: bool,  // Error if a base type is unknown.
+    pub field_sizes: bool,  // Error if a field size is not a multiple of its base type, else it is read as bytes.
 }
 
 impl Default for Checks {
-    fn default() -> Self { Self{reserved_bits_zero: true,} }
+    fn default() -> Self { Self{reserved_bits_zero: true, valid_base_types: true, field_sizes: false,} }
 }
 
-#[derive(Default)]
+/// A UUID, e.g. the application_id of a Connect IQ app. The bytes are in the order they are
+/// written, which is the order of the usual text form.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FitUuid(pub [u8; 16]);
+
+impl FitUuid {
+    /// The UUID in 16 bytes of field data, None if the data is invalid.
+    pub fn from_field_data(data: &FitFieldData) -> Option<FitUuid> {
+        if !data.is_valid() {
+            return None;
+        }
+        let bytes: Vec<u8> = data.integer_values()?.into_iter().map(|x| x as u8).collect();
+        Some(FitUuid(bytes.try_into().ok()?))
+    }
+}
+
+impl std::fmt::Display for FitUuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (i, b) in self.0.iter().enumerate() {
+            if i == 4 || i == 6 || i == 8 || i == 10 {
+                write!(f, "-")?;
+            }
+            write!(f, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Debug for FitUuid {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "FitUuid({})", self)
+    }
+}
+
+impl std::str::FromStr for FitUuid {
+    type Err = &'static str;
+
+    /// Parse the usual text form, e.g. "12345678-9abc-def0-1234-56789abcdef0".
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let text = s.as_bytes();
+        let is_dash = |i: usize| matches!(i, 8 | 13 | 18 | 23);
+        if text.len() != 36 || text.iter().enumerate().any(|(i, c)| (*c == b'-') != is_dash(i)) {
+            return Err("UUID must be 32 hex digits in groups of 8-4-4-4-12");
+        }
+        if !text.iter().enumerate().all(|(i, c)| is_dash(i) || c.is_ascii_hexdigit()) {
+            return Err("UUID has a character that is not hex");
+        }
+        let hex: Vec<u8> = text.iter().copied().filter(|c| *c != b'-').collect();
+        let mut bytes = [0u8; 16];
+        for (i, pair) in hex.chunks(2).enumerate() {
+            // Only hex digits are left, so the pair is valid UTF-8 and a valid number.
+            bytes[i] = u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap();
+        }
+        Ok(FitUuid(bytes))
+    }
+}
+
+/// A developer application, from a developer_data_id message, and the fields it has described.
+#[derive(Clone, Default)]
 #[derive(Debug)]
 pub struct FitFileDeveloperId {
-    pub developer_id: Option< u8 >,
-    pub application_id: Option< u8 >,
+    pub developer_id: Option< FitUuid >,
+    pub application_id: Option< FitUuid >,
     pub manufacturer_id: Option< u16 >,  // manufacturer
     pub developer_data_index: Option< u8 >,
     pub application_version: Option< u32 >,
     pub developer_field_definitions: HashMap<u8, Arc<FitDevDataDescription> >,
 }
 
+/// Running total of a field that is sent as a rolling count in a few bits.
+#[derive(Copy, Clone, Default, PartialEq)]
+#[derive(Debug)]
+pub struct FitAccumulator {
+    pub last_value: u64,
+    pub accumulated_value: u64,
+}
+
+impl FitAccumulator {
+    /// Add the increase since the last value, allowing for the value having rolled over.
+    pub fn accumulate(&mut self, value: u64, bits: u8) -> u64 {
+        let mask = if bits >= 64 { u64::MAX } else { (1u64 << bits) - 1 };
+        self.accumulated_value = self.accumulated_value
+            .wrapping_add(value.wrapping_sub(self.last_value) & mask);
+        self.last_value = value;
+        self.accumulated_value
+    }
+
+    /// Restart from a known total, e.g. when the full-width field is sent.
+    pub fn set(&mut self, value: u64) {
+        self.accumulated_value = value;
+        self.last_value = value;
+    }
+}
+
 #[derive(Default)]
 #[derive(Debug)]
 pub struct FitFileContext {
@@ -343,6 +677,35 @@ pub struct FitFileContext {
     pub developer_ids: HashMap<u8, FitFileDeveloperId >,
     pub timestamp: u32,
     pub checks: Checks,
+    pub profile: Option<Arc<ProfileData>>,  // If set, components are expanded when decoding.
+    pub accumulators: HashMap<(u16, u8), FitAccumulator>,  // By global message and field number.
+    pub header_size: u32,
+    pub records_read: u64,  // Including the header.
+    pub file_start: u64,  // Byte offset of the current file, when files are chained.
+    pub warnings: Vec<FitError>,  // Problems that were let through because a check is off.
+}
+
+impl FitFileContext {
+    /// Byte offset in the stream of the next byte to read.
+    pub fn file_offset(&self) -> u64 {
+        self.file_start + self.header_size as u64 + self.data_bytes_read as u64
+    }
+
+    /// Prepare to read the next of chained files, after the CRC of the current one.
+    /// Definitions and developer data do not carry over from one file to the next.
+    pub fn start_next_file(&mut self) {
+        self.file_start = self.file_offset() + 2;
+        self.data_bytes_read = 0;
+        self.data_bytes_written = 0;
+        self.crc.reset();
+        self.architecture = None;
+        self.field_definitions.clear();
+        self.developer_ids.clear();
+        self.timestamp = 0;
+        self.accumulators.clear();
+        self.header_size = 0;
+        self.records_read = 0;
+    }
 }
 
 
@@ -377,6 +740,14 @@ pub struct FitDevDataDescription {
     pub is_array: Option<bool>,
     pub base_type: Option<FitDataType>,
     pub dev_data_index: u8,
+    pub native_mesg_num: Option<u16>,  // The profile field that this field is equivalent to.
+    pub native_field_num: Option<u8>,
+    pub components: Option<String>,  // As given, these are not expanded.
+    pub bits: Option<String>,
+    pub accumulate: Option<String>,
+    pub fit_base_unit_id: Option<u16>,
+    pub application_id: Option<FitUuid>,  // The application that the field belongs to.
+    pub application_version: Option<u32>,
 }
 
 #[derive(Debug,Default)]
@@ -386,6 +757,7 @@ pub struct FitDataMessage {
     pub timestamp: Option<u32>,    // Only set for compressed messages.
     pub fields: Vec<FitDataField>,
     pub dev_fields: Vec<FitDevDataField>,
+    pub expanded_fields: Vec<FitDataField>,  // Decoded from components, these are not written.
 }
 
 #[derive(Debug)]
@@ -1,100 +1,101 @@
 use std::io::Write;
 use byteorder::{LittleEndian, BigEndian, WriteBytesExt};
 
+use crate::fiterror::{FitErrorKind, FitResult};
 use crate::fittypes::{Endianness, FitFileContext};
 
-pub fn fit_write_u8(context: &mut FitFileContext, writer: &mut dyn Write, byte: u8) -> Result<(), std::io::Error> {
+pub fn fit_write_u8(context: &mut FitFileContext, writer: &mut dyn Write, byte: u8) -> FitResult<()> {
     writer.write_u8(byte)?;
     context.data_bytes_written = context.data_bytes_written + 1;
     return Ok(());
 }
 
-pub fn fit_write_i8(context: &mut FitFileContext, writer: &mut dyn Write, byte: i8) -> Result<(), std::io::Error> {
+pub fn fit_write_i8(context: &mut FitFileContext, writer: &mut dyn Write, byte: i8) -> FitResult<()> {
     writer.write_i8(byte)?;
     context.data_bytes_written = context.data_bytes_written + 1;
     return Ok(());
 }
-pub fn fit_write_u16(context: &mut FitFileContext, writer: &mut dyn Write, v: u16) -> Result<(), std::io::Error> {
+pub fn fit_write_u16(context: &mut FitFileContext, writer: &mut dyn Write, v: u16) -> FitResult<()> {
     match context.architecture {
         Some(Endianness::Little) => writer.write_u16::<LittleEndian>(v)?,
         Some(Endianness::Big) => writer.write_u16::<BigEndian>(v)?,
-        None =>  return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None =>  return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_written = context.data_bytes_written + 2;
     return Ok(());
 }
 
-pub fn fit_write_i16(context: &mut FitFileContext, writer: &mut dyn Write, v: i16) -> Result<(), std::io::Error> {
+pub fn fit_write_i16(context: &mut FitFileContext, writer: &mut dyn Write, v: i16) -> FitResult<()> {
     match context.architecture {
         Some(Endianness::Little) => writer.write_i16::<LittleEndian>(v)?,
         Some(Endianness::Big) => writer.write_i16::<BigEndian>(v)?,
-        None =>  return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None =>  return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_written = context.data_bytes_written + 2;
     return Ok(());
 }
 
-pub fn fit_write_u32(context: &mut FitFileContext, writer: &mut dyn Write, v: u32) -> Result<(), std::io::Error> {
+pub fn fit_write_u32(context: &mut FitFileContext, writer: &mut dyn Write, v: u32) -> FitResult<()> {
     match context.architecture {
         Some(Endianness::Little) => writer.write_u32::<LittleEndian>(v)?,
         Some(Endianness::Big) => writer.write_u32::<BigEndian>(v)?,
-        None =>  return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None =>  return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_written = context.data_bytes_written + 4;
     return Ok(());
 }
 
-pub fn fit_write_i32(context: &mut FitFileContext, writer: &mut dyn Write, v: i32) -> Result<(), std::io::Error> {
+pub fn fit_write_i32(context: &mut FitFileContext, writer: &mut dyn Write, v: i32) -> FitResult<()> {
     match context.architecture {
         Some(Endianness::Little) => writer.write_i32::<LittleEndian>(v)?,
         Some(Endianness::Big) => writer.write_i32::<BigEndian>(v)?,
-        None =>  return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None =>  return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_written = context.data_bytes_written + 4;
     return Ok(());
 }
 
-pub fn fit_write_u64(context: &mut FitFileContext, writer: &mut dyn Write, v: u64) -> Result<(), std::io::Error> {
+pub fn fit_write_u64(context: &mut FitFileContext, writer: &mut dyn Write, v: u64) -> FitResult<()> {
     match context.architecture {
         Some(Endianness::Little) => writer.write_u64::<LittleEndian>(v)?,
         Some(Endianness::Big) => writer.write_u64::<BigEndian>(v)?,
-        None =>  return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None =>  return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_written = context.data_bytes_written + 8;
     return Ok(());
 }
 
-pub fn fit_write_i64(context: &mut FitFileContext, writer: &mut dyn Write, v: i64) -> Result<(), std::io::Error> {
+pub fn fit_write_i64(context: &mut FitFileContext, writer: &mut dyn Write, v: i64) -> FitResult<()> {
     match context.architecture {
         Some(Endianness::Little) => writer.write_i64::<LittleEndian>(v)?,
         Some(Endianness::Big) => writer.write_i64::<BigEndian>(v)?,
-        None =>  return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None =>  return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_written = context.data_bytes_written + 8;
     return Ok(());
 }
 
-pub fn fit_write_f32(context: &mut FitFileContext, writer: &mut dyn Write, v: f32) -> Result<(), std::io::Error> {
+pub fn fit_write_f32(context: &mut FitFileContext, writer: &mut dyn Write, v: f32) -> FitResult<()> {
     match context.architecture {
         Some(Endianness::Little) => writer.write_f32::<LittleEndian>(v)?,
         Some(Endianness::Big) => writer.write_f32::<BigEndian>(v)?,
-        None =>  return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None =>  return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_written = context.data_bytes_written + 4;
     return Ok(());
 }
 
-pub fn fit_write_f64(context: &mut FitFileContext, writer: &mut dyn Write, v: f64) -> Result<(), std::io::Error> {
+pub fn fit_write_f64(context: &mut FitFileContext, writer: &mut dyn Write, v: f64) -> FitResult<()> {
     match context.architecture {
         Some(Endianness::Little) => writer.write_f64::<LittleEndian>(v)?,
         Some(Endianness::Big) => writer.write_f64::<BigEndian>(v)?,
-        None =>  return Err( std::io::Error::new(std::io::ErrorKind::Other, "Endianness not set"))
+        None =>  return Err(FitErrorKind::EndiannessNotSet.into())
     };
     context.data_bytes_written = context.data_bytes_written + 8;
     return Ok(());
 }
 
-pub fn fit_write_string(context: &mut FitFileContext, writer: &mut dyn Write, v: &str, width: &u8) -> Result<(), std::io::Error> {
+pub fn fit_write_string(context: &mut FitFileContext, writer: &mut dyn Write, v: &str, width: &u8) -> FitResult<()> {
     let vbytes = v.as_bytes();
     let sz = *width as usize;
     let mut string_bytes = vbytes.len();
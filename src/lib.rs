@@ -4,8 +4,11 @@
 extern crate serde_derive;
 #[macro_use]
 extern crate log;
+#[macro_use]
+extern crate smallvec;
 
 pub mod fitcrc;
+pub mod fiterror;
 pub mod fitread;
 pub mod fitwrite;
 pub mod fittypes;
@@ -13,9 +16,20 @@ pub mod fitheader;
 pub mod fitdefnmesg;
 pub mod fitdatamesg;
 pub mod fitfile;
+pub mod fitrecover;
+pub mod fitencode;
+pub mod fitdeveloper;
+pub mod fitslice;
+pub mod fitlisten;
+pub mod fitindex;
 pub mod fitcheck;
 pub mod fitrecord;
 pub mod fitfield;
+pub mod fitconvert;
+pub mod fitenums;
+pub mod fitmessages;
+#[cfg(test)]
+mod fittest;
 
 pub mod profile;
 
@@ -6,6 +6,7 @@ use std::env;
 
 extern crate fit_reader;
 use crate::fit_reader::fitfile;
+use crate::fit_reader::fitrecord::{DumpOptions, InvalidValues};
 
 extern crate env_logger;
 
@@ -14,14 +15,24 @@ fn main() {
 
     let args: Vec<String> = env::args().collect();
 
-    if args.len() <= 1 {
-        println!("Usage: fit_file input_file.fit");
+    let mut options: DumpOptions = Default::default();
+    let mut pathnames = Vec::new();
+    for arg in &args[1..] {
+        if arg == "--omit-invalid" {
+            options.invalid_values = InvalidValues::Omit;
+        } else {
+            pathnames.push(arg);
+        }
+    }
+
+    if pathnames.is_empty() {
+        println!("Usage: fit_file [--omit-invalid] input_file.fit");
         std::process::exit(1);
     }
 
-    for pathname in &args[1..] {
+    for pathname in pathnames {
         info!("Processing {}", pathname);
-        let res = fitfile::read_file(&pathname);
+        let res = fitfile::read_file_with_options(pathname, &options);
 
         match res {
             Ok(_) => {},
@@ -1,16 +1,54 @@
 
 use std::collections::HashMap;
+use std::sync::{Arc, OnceLock};
+
+use crate::fittypes::{FitDataType, FitDataMessage};
+
+/// A bit-packed part of a field, which expands into another field of the same message.
+/// Components are read from the source field's bits in order, least significant first.
+#[derive(Deserialize)]
+#[derive(Clone, Debug, Default)]
+pub struct ProfileComponent {
+    pub field_name: String,   // Name of the destination field.
+    pub bits: u8,
+    pub scale: Option<f64>,
+    pub offset: Option<f64>,
+    pub units: Option<String>,
+    #[serde(default)]
+    pub accumulate: bool,
+    #[serde(skip)]
+    pub field_defn_num: Option<u8>,  // Of the destination field, found when the profile is built.
+}
+
+/// A subfield applies when the named field of the same message has the given value.
+#[derive(Deserialize)]
+#[derive(Clone, Debug, Default)]
+pub struct ProfileRefField {
+    pub ref_field_name: String,
+    pub ref_field_value: String,  // Value name in the referenced field's type, e.g. "garmin".
+}
 
 #[derive(Deserialize)]
 #[derive(Clone, Debug, Default)]
 pub struct ProfileField {
-    pub field_defn_num:u8,
+    #[serde(default)]
+    pub field_defn_num:u8,  // Not given for subfields, copied from the main field.
     pub field_name: String,
     pub scale: Option<f64>,
     pub offset: Option<f64>,
     pub units: Option<String>,
     pub is_array: Option<bool>,
     pub field_type: String,
+    #[serde(default)]
+    pub components: Vec<ProfileComponent>,
+    #[serde(default)]
+    pub subfields: Vec<ProfileField>,
+    #[serde(default)]
+    pub ref_fields: Vec<ProfileRefField>,  // Only set for subfields, any one may match.
+    #[serde(skip)]
+    pub is_accumulated: bool,  // The destination of an accumulating component.
+    #[serde(skip)]
+    pub base_type: Option<FitDataType>,  // Of field_type, found when the profile is built.
 }
 
 #[derive(Deserialize)]
@@ -18,6 +56,8 @@ pub struct ProfileField {
 pub struct ProfileType {
     pub base_type: String,
     pub type_name: String,
+    #[serde(default)]
+    pub bitfield: bool,  // The values are flags which may be combined.
     pub values: HashMap<String,u32>,
 }
 
@@ -27,8 +67,11 @@ pub struct ProfileMessage {
     pub mesg_num: u16,
     pub message_name: String,
     fields: Vec<ProfileField>,
+    #[serde(skip)]
+    field_index: Vec<Option<u8>>,  // The position in fields of each field number.
 }
 
+#[derive(Debug)]
 pub struct ProfileData {
     message_map: HashMap<u16, ProfileMessage>,
     type_map: HashMap<String, ProfileType>,
@@ -49,6 +92,43 @@ fn merge_message(message: &mut ProfileMessage, supplement: &ProfileMessage) {
     }
 }
 
+fn mark_accumulated_fields(message: &mut ProfileMessage) {
+    let mut accumulated: Vec<String> = Vec::new();
+    for field in &message.fields {
+        for component in field.components.iter()
+            .chain(field.subfields.iter().flat_map(|x| x.components.iter())) {
+            if component.accumulate {
+                accumulated.push(component.field_name.clone());
+            }
+        }
+    }
+    for field in &mut message.fields {
+        field.is_accumulated = accumulated.contains(&field.field_name);
+    }
+}
+
+fn index_fields(message: &mut ProfileMessage) {
+    message.field_index = vec![None; 256];
+    for (i, field) in message.fields.iter().enumerate() {
+        message.field_index[field.field_defn_num as usize] = Some(i as u8);
+    }
+}
+
+/// Find the destination field of each component, so that expanding them does not look up
+/// fields by name.
+fn link_components(message: &mut ProfileMessage) {
+    // The first field of a name is the one found by find_field_by_name.
+    let numbers: HashMap<String, u8> = message.fields.iter().rev()
+        .map(|x| (x.field_name.clone(), x.field_defn_num))
+        .collect();
+    for field in &mut message.fields {
+        for component in field.components.iter_mut()
+            .chain(field.subfields.iter_mut().flat_map(|x| x.components.iter_mut())) {
+            component.field_defn_num = numbers.get(&component.field_name).copied();
+        }
+    }
+}
+
 fn append_profile(message_map: &mut HashMap<u16, ProfileMessage>, json_messages: &[u8])  -> Result<(), String> {
     // Parse the string of data into serde_json::Value.
     let vec_from_json: Vec<ProfileMessage> = match serde_json::from_slice(json_messages){
@@ -57,6 +137,14 @@ fn append_profile(message_map: &mut HashMap<u16, ProfileMessage>, json_messages:
     }
         ;
     for message in &vec_from_json {
+        let mut message = message.clone();
+        for field in &mut message.fields {
+            let field_defn_num = field.field_defn_num;
+            for subfield in &mut field.subfields {
+                subfield.field_defn_num = field_defn_num;
+            }
+        }
+        let message = &message;
         if let Some(msg) = message_map.get_mut(&message.mesg_num) {
             merge_message(msg, message);
         } else {
@@ -75,6 +163,12 @@ pub fn build_profile() -> Result<ProfileData, String> {
     append_profile(&mut message_map, json_messages).unwrap();
     append_profile(&mut message_map, custom_messages).unwrap() ;
 
+    for message in message_map.values_mut() {
+        index_fields(message);
+        mark_accumulated_fields(message);
+        link_components(message);
+    }
+
     let json_types = include_bytes!("types.json");
     let type_vec: Vec<ProfileType> = match serde_json::from_slice(json_types){
         Ok(x) => {x},
@@ -86,12 +180,76 @@ pub fn build_profile() -> Result<ProfileData, String> {
         type_map.insert(item.type_name.clone(), item.clone());
     }
 
-    Ok( ProfileData { message_map, type_map } )
+    // Base types are looked up once here, rather than by type name for each value decoded.
+    let mut profile = ProfileData { message_map: HashMap::new(), type_map };
+    for field in message_map.values_mut().flat_map(|x| x.fields.iter_mut()) {
+        field.base_type = profile.base_type(&field.field_type);
+        for subfield in &mut field.subfields {
+            subfield.base_type = profile.base_type(&subfield.field_type);
+        }
+    }
+    profile.message_map = message_map;
+    Ok(profile)
+}
+
+/// The profile, built the first time it is asked for and then shared, so that reading many
+/// small files does not parse the JSON for each one. None if it could not be built.
+pub fn shared() -> Option<Arc<ProfileData>> {
+    static PROFILE: OnceLock<Option<Arc<ProfileData>>> = OnceLock::new();
+    PROFILE.get_or_init(|| build_profile().ok().map(Arc::new)).clone()
 }
 
 impl ProfileMessage {
     pub fn find_field(&self, field_defn_num: u8) -> Option<&ProfileField> {
-        self.fields.iter().find( | &x| x.field_defn_num == field_defn_num)
+        let position = (*self.field_index.get(field_defn_num as usize)?)?;
+        Some(&self.fields[position as usize])
+    }
+
+    pub fn find_field_by_name(&self, field_name: &str) -> Option<&ProfileField> {
+        self.fields.iter().find( | &x| x.field_name == field_name)
+    }
+
+    /// The first accumulating component which expands into the named field.
+    pub fn accumulating_component(&self, field_name: &str) -> Option<&ProfileComponent> {
+        self.fields.iter()
+            .flat_map(|x| x.components.iter()
+                .chain(x.subfields.iter().flat_map(|y| y.components.iter())))
+            .find(|x| x.accumulate && x.field_name == field_name)
+    }
+
+    /// Find the definition of a field, choosing the subfield selected by the message's other
+    /// fields if there is one. e.g. device_info.product is garmin_product if manufacturer=garmin.
+    pub fn resolve_field(&self, profile: &ProfileData, field_defn_num: u8, mesg: &FitDataMessage)
+        -> Option<&ProfileField>
+    {
+        let field = self.find_field(field_defn_num)?;
+        for subfield in &field.subfields {
+            for ref_field in &subfield.ref_fields {
+                if self.ref_field_matches(profile, ref_field, mesg) {
+                    return Some(subfield);
+                }
+            }
+        }
+        Some(field)
+    }
+
+    fn ref_field_matches(&self, profile: &ProfileData, ref_field: &ProfileRefField, mesg: &FitDataMessage) -> bool
+    {
+        let ref_desc = match self.find_field_by_name(&ref_field.ref_field_name) {
+            Some(x) => x,
+            None => return false,
+        };
+        let ref_value = match profile.value_of(&ref_desc.field_type, &ref_field.ref_field_value) {
+            Some(x) => x,
+            None => {
+                warn!("Unknown value {} for subfield reference {}", ref_field.ref_field_value, ref_field.ref_field_name);
+                return false;
+            },
+        };
+        mesg.fields.iter().chain(mesg.expanded_fields.iter())
+            .find(|f| f.field_defn_num == ref_desc.field_defn_num)
+            .and_then(|f| f.data.first_integer())
+            == Some(ref_value as u64)
     }
 }
 
@@ -111,6 +269,55 @@ impl ProfileData {
         }
         return None;
     }
+
+    /// Find the numeric value of the named value of a type, e.g. ("manufacturer", "garmin") -> 1.
+    pub fn value_of(&self, type_name: &str, value_name: &str) -> Option<u32>
+    {
+        self.type_map.get(type_name)?.values.get(value_name).copied()
+    }
+
+    pub fn is_bitfield(&self, type_name: &str) -> bool
+    {
+        self.type_map.get(type_name).map(|x| x.bitfield).unwrap_or(false)
+    }
+
+    /// The names of the flags set in a bitfield value, ordered by bit, and any bits which have
+    /// no name. None if the type is not a bitfield.
+    pub fn flag_names(&self, type_name: &str, value: u32) -> Option<(Vec<String>, u32)>
+    {
+        let a_type = self.type_map.get(type_name).filter(|x| x.bitfield)?;
+        let mut flags: Vec<(u32, &String)> = a_type.values.iter()
+            .filter(|(_, v)| **v != 0 && (value & **v) == **v)
+            .map(|(k, v)| (*v, k))
+            .collect();
+        flags.sort();
+        let mut remainder = value;
+        for (v, _) in &flags {
+            remainder &= !v;
+        }
+        let mut names: Vec<String> = flags.into_iter().map(|(_, k)| k.clone()).collect();
+        if value == 0 {
+            // e.g. auto_activity_detect has a name for no flags.
+            names.extend(a_type.values.iter().filter(|(_, v)| **v == 0).map(|(k, _)| k.clone()));
+        }
+        Some((names, remainder))
+    }
+
+    /// The bitfield value with the named flags set, None if a name is not a flag of the type.
+    pub fn flags_value(&self, type_name: &str, names: &[&str]) -> Option<u32>
+    {
+        let a_type = self.type_map.get(type_name).filter(|x| x.bitfield)?;
+        names.iter().try_fold(0u32, |acc, name| Some(acc | a_type.values.get(*name)?))
+    }
+
+    /// The base type used to store a field of the given profile type, e.g. "manufacturer" -> uint16.
+    pub fn base_type(&self, type_name: &str) -> Option<FitDataType> {
+        let base_type_name = match self.type_map.get(type_name) {
+            Some(a_type) => a_type.base_type.as_str(),
+            None => type_name,
+        };
+        FitDataType::from_name(base_type_name).ok()
+    }
 }
 
 #[cfg(test)]
@@ -123,6 +330,12 @@ mod tests {
         assert!(build_profile().is_ok(), "json could not be parsed")
     }
 
+    #[test]
+    fn test_shared() {
+        // Built once, then the same profile is given to every reader.
+        assert!(Arc::ptr_eq(&shared().unwrap(), &shared().unwrap()));
+    }
+
     #[test]
     fn test_message_lookup() {
         let p = build_profile().unwrap();
@@ -132,4 +345,77 @@ mod tests {
         assert_eq!(a_message.message_name, "file_id");
         assert_eq!(p.value_name("file", 14), Some("blood_pressure".to_string()));
     }
+
+    #[test]
+    fn test_components() {
+        let p = build_profile().unwrap();
+
+        let record = p.get_message(20).unwrap();
+        let field = record.find_field(8).unwrap();
+        assert_eq!(field.field_name, "compressed_speed_distance");
+        assert_eq!(field.components.len(), 2);
+        assert_eq!(field.components[0].field_name, "speed");
+        assert_eq!(field.components[0].bits, 12);
+        assert_eq!(field.components[1].field_name, "distance");
+        assert_eq!(field.components[1].scale, Some(16.0));
+        assert_eq!(field.components[1].field_defn_num, Some(5));
+        assert!(field.components[1].accumulate);
+        assert!(record.find_field_by_name("distance").unwrap().is_accumulated);
+        assert!(!record.find_field_by_name("speed").unwrap().is_accumulated);
+        assert_eq!(record.accumulating_component("distance").unwrap().scale, Some(16.0));
+
+        assert!(matches!(p.base_type("manufacturer"), Some(FitDataType::FitUint16)));
+        assert!(matches!(p.base_type("uint32z"), Some(FitDataType::FitU32z)));
+    }
+
+    #[test]
+    fn test_bitfields() {
+        let p = build_profile().unwrap();
+
+        assert!(p.is_bitfield("file_flags"));
+        assert!(!p.is_bitfield("file"));
+        assert_eq!(p.flag_names("file_flags", 6), Some((vec!["read".to_string(), "write".to_string()], 0)));
+        assert_eq!(p.flag_names("file_flags", 0x12), Some((vec!["read".to_string()], 0x10)));
+        assert_eq!(p.flag_names("auto_activity_detect", 0), Some((vec!["none".to_string()], 0)));
+        assert_eq!(p.flag_names("file", 4), None);
+
+        assert_eq!(p.flags_value("file_flags", &["read", "erase"]), Some(10));
+        assert_eq!(p.flags_value("file_flags", &["read", "delete"]), None);
+        assert_eq!(p.flags_value("file_flags", &[]), Some(0));
+    }
+
+    #[test]
+    fn test_subfields() {
+        use crate::fittypes::{FitDataField, FitFieldData};
+        let p = build_profile().unwrap();
+
+        let device_info = p.get_message(23).unwrap();
+        let mut mesg = FitDataMessage{
+            global_message_number: 23,
+            fields: vec![
+                FitDataField{ field_defn_num: 2, data: FitFieldData::FitUint16(smallvec![1]) },  // garmin
+                FitDataField{ field_defn_num: 4, data: FitFieldData::FitUint16(smallvec![1735]) },
+            ],
+            ..Default::default()
+        };
+        let field = device_info.resolve_field(&p, 4, &mesg).unwrap();
+        assert_eq!(field.field_name, "garmin_product");
+        assert_eq!(field.field_defn_num, 4);
+
+        mesg.fields[0].data = FitFieldData::FitUint16(smallvec![32]);  // wahoo_fitness
+        assert_eq!(device_info.resolve_field(&p, 4, &mesg).unwrap().field_name, "product");
+
+        let workout_step = p.get_message(27).unwrap();
+        let mesg = FitDataMessage{
+            global_message_number: 27,
+            fields: vec![
+                FitDataField{ field_defn_num: 1, data: FitFieldData::FitEnum(smallvec![0]) },  // time
+                FitDataField{ field_defn_num: 2, data: FitFieldData::FitUint32(smallvec![60000]) },
+            ],
+            ..Default::default()
+        };
+        let field = workout_step.resolve_field(&p, 2, &mesg).unwrap();
+        assert_eq!(field.field_name, "duration_time");
+        assert_eq!(field.scale, Some(1000.0));
+    }
 }
\ No newline at end of file